@@ -49,6 +49,26 @@ def propagate_between_gaps_mp(tle_records, density_per_segment):
     return all_states
     
 
+_model = None
+
+def propagate_batched(tle_records, tle_rows, tsinces):
+    """
+    Called from Rust with many satellites' TLEs at once, so the model runs one large batch per call \n
+    tle_records: list of TLE dictionaries, each one propagated over its own gap \n
+    tle_rows: numpy array with the index into tle_records of every row \n
+    tsinces: numpy array of minutes since that TLE's epoch for every row \n
+    Returns a len(tsinces)x6 numpy array of normalised TEME states
+    """
+    global _model
+    if _model is None: #built once per process instead of once per satellite
+        _model = customMLDSGP4.mldsgp4(hidden_size=35)
+
+    tles = [CustomTLE(record) for record in tle_records]
+    tle_expanded = [tles[i] for i in tle_rows]
+    with torch.no_grad():
+        states = _model(tle_expanded, torch.tensor(tsinces, dtype=torch.get_default_dtype()))
+    return states.detach().double().numpy()
+
 def propagate(record:CustomTLE, time:int, density:int, model:customMLDSGP4):
     """
     Record: Expects a CustomTLE object \n
@@ -1,3 +1,4 @@
+# kept for older outputs, `rust_leo_sim dataset --files <dir> --move --seed <n>` splits by satellite reproducibly
 import os
 import sys
 import random
@@ -0,0 +1,243 @@
+use std::{collections::{BTreeMap, HashMap}, fs::{self, File}, io::{BufWriter, Write}, path::Path};
+use rayon::prelude::*;
+use satkit::{consts::EARTH_RADIUS, orbitprop::{PropSettings, SatState}, TLE};
+use anyhow::Result;
+use zstd::Encoder;
+use crate::frames::{rtn_error, RtnError};
+use crate::maneuver::ManeuverHandling;
+use crate::satellite::OrbitalInstance;
+use crate::numerical_integration::{GapLimits, convert_map_to_gcrf, integrate_to, integration_settings, maneuver_gaps, plan_segments, sample_result, sgp4_gcrf, GcrfRecords, Sampling};
+
+const ALTITUDE_BAND_KM: f64 = 100.0;
+
+//running statistics over RTN errors, kept as sums so they can be merged across satellites and bands
+#[derive(Clone, Default)]
+pub(crate) struct ErrorStats {
+    pub(crate) count: usize,
+    pub(crate) sum_sq: [f64; 6], //R, T, N position then R, T, N velocity
+    pub(crate) sum_position: f64,
+    pub(crate) max_position: f64,
+}
+
+impl ErrorStats {
+    pub(crate) fn add(&mut self, error: &RtnError) {
+        self.count += 1;
+        for i in 0..3 {
+            self.sum_sq[i] += error.position[i].powi(2);
+            self.sum_sq[i + 3] += error.velocity[i].powi(2);
+        }
+        let position_norm = error.position.norm();
+        self.sum_position += position_norm;
+        self.max_position = self.max_position.max(position_norm);
+    }
+
+    pub(crate) fn merge(&mut self, other: &ErrorStats) {
+        self.count += other.count;
+        for i in 0..6 {
+            self.sum_sq[i] += other.sum_sq[i];
+        }
+        self.sum_position += other.sum_position;
+        self.max_position = self.max_position.max(other.max_position);
+    }
+
+    pub(crate) fn rms(&self, component: usize) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        (self.sum_sq[component] / self.count as f64).sqrt()
+    }
+
+    pub(crate) fn rms_position(&self) -> f64 {
+        (self.rms(0).powi(2) + self.rms(1).powi(2) + self.rms(2).powi(2)).sqrt()
+    }
+
+    pub(crate) fn mean_position(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum_position / self.count as f64
+    }
+}
+
+struct SatelliteSummary {
+    id: String,
+    altitude_km: f64,
+    bstar: f64,
+    stats: ErrorStats,
+}
+
+//the same gap and maneuver choices as the integration run being analyzed
+pub(crate) struct AnalysisOptions {
+    pub(crate) sampling: Sampling,
+    pub(crate) compression_level: i32,
+    pub(crate) maneuvers: ManeuverHandling,
+    pub(crate) maneuver_sigma: f64,
+    pub(crate) gaps: GapLimits,
+}
+
+//propagates every TLE with SGP4 over the same segments and time grid stream() samples and compares it against the integrated states
+pub(crate) fn analyze(map: HashMap<String, Vec<TLE>>, options: &AnalysisOptions, output_dir: &str) -> Result<()> {
+    fs::create_dir_all(output_dir)?;
+
+    println!("Converting to SatStates");
+    let time = std::time::Instant::now();
+    let map: HashMap<String, GcrfRecords> = convert_map_to_gcrf(map)?;
+    println!("Converted in {}", time.elapsed().as_secs_f64());
+
+    let settings = integration_settings();
+
+    println!("Starting SGP4 error analysis");
+    let time = std::time::Instant::now();
+    let summaries: Vec<SatelliteSummary> = map.into_par_iter()
+        .map(|(id, records)| analyze_satellite(&id, records, &settings, options, output_dir))
+        .collect::<Result<Vec<SatelliteSummary>>>()?;
+    println!("Analyzed {} satellites in {}", summaries.len(), time.elapsed().as_secs_f64());
+
+    write_summaries(&summaries, output_dir)?;
+    write_bands(&summaries, output_dir)?;
+    Ok(())
+}
+
+fn analyze_satellite(id: &String, records: GcrfRecords, settings: &PropSettings, options: &AnalysisOptions, output_dir: &str) -> Result<SatelliteSummary> {
+    let (tles, states) = records;
+
+    let filename = Path::new(output_dir).join(format!("analysis_{}.csv.zst", id));
+    let writer: BufWriter<File> = BufWriter::new(File::create(filename)?);
+    let mut encoder: Encoder<'static, BufWriter<File>> = Encoder::new(writer, options.compression_level)?;
+    writeln!(encoder, "time,seconds_since_epoch,dr_r,dr_t,dr_n,dv_r,dv_t,dv_n")?;
+
+    let epochs: Vec<satkit::Instant> = states.iter().map(|state| state.time).collect();
+    let maneuver_gaps = maneuver_gaps(&tles, options.maneuvers, options.maneuver_sigma);
+    let (segments, _) = plan_segments(&epochs, &maneuver_gaps, options.maneuvers, &options.gaps);
+
+    let mut stats = ErrorStats::default();
+    for segment in segments {
+        let result = integrate_to(&states[segment.tle_index], &segment.stop, settings, None)?;
+        let steps: Vec<SatState> = sample_result(&result, &options.sampling, tles[segment.tle_index].mean_motion)?;
+        let times: Vec<satkit::Instant> = steps.iter().map(|step| step.time).collect();
+
+        let mut tle: TLE = tles[segment.tle_index].clone(); //the TLE the segment was integrated from, backward halves of split gaps included
+        let sgp4_states: Vec<SatState> = sgp4_gcrf(&mut tle, &times);
+
+        for (integrated, sgp4_state) in steps.iter().zip(sgp4_states.iter()) {
+            let error = rtn_error(integrated, sgp4_state);
+            stats.add(&error);
+            let since_epoch = (integrated.time - tle.epoch).as_seconds();
+            writeln!(encoder, "{},{},{},{},{},{},{},{}",
+                integrated.time.as_unixtime(), since_epoch,
+                error.position[0], error.position[1], error.position[2],
+                error.velocity[0], error.velocity[1], error.velocity[2])?;
+        }
+    }
+    encoder.finish()?.flush()?;
+
+    let bstar = median(tles.iter().map(|tle| tle.bstar).collect());
+    let altitude_km = median(tles.iter().map(mean_altitude_km).collect());
+    Ok(SatelliteSummary { id: id.clone(), altitude_km, bstar, stats })
+}
+
+//altitude of the mean semi-major axis above the equatorial radius
+pub(crate) fn mean_altitude_km(tle: &TLE) -> f64 {
+    (OrbitalInstance::from_tle(tle).semi_major_axis() - EARTH_RADIUS) / 1000.0
+}
+
+pub(crate) fn median(mut values: Vec<f64>) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    values[values.len() / 2]
+}
+
+//decade of B* the satellite falls into, e.g. -4 for 1e-4 <= |B*| < 1e-3
+fn bstar_band(bstar: f64) -> i32 {
+    if bstar == 0.0 {
+        return i32::MIN;
+    }
+    bstar.abs().log10().floor() as i32
+}
+
+fn write_summaries(summaries: &[SatelliteSummary], output_dir: &str) -> Result<()> {
+    let file = File::create(Path::new(output_dir).join("analysis_satellites.csv"))?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "id,altitude_km,bstar,samples,rms_r,rms_t,rms_n,rms_vr,rms_vt,rms_vn,rms_position,mean_position,max_position")?;
+    for summary in summaries {
+        let stats = &summary.stats;
+        writeln!(writer, "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            summary.id, summary.altitude_km, summary.bstar, stats.count,
+            stats.rms(0), stats.rms(1), stats.rms(2), stats.rms(3), stats.rms(4), stats.rms(5),
+            stats.rms_position(), stats.mean_position(), stats.max_position)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_bands(summaries: &[SatelliteSummary], output_dir: &str) -> Result<()> {
+    //keyed by (altitude band start in km, B* decade), BTreeMap keeps the output ordered
+    let mut bands: BTreeMap<(i64, i32), (usize, ErrorStats)> = BTreeMap::new();
+    for summary in summaries {
+        let altitude_band = ((summary.altitude_km / ALTITUDE_BAND_KM).floor() * ALTITUDE_BAND_KM) as i64;
+        let (satellites, stats) = bands.entry((altitude_band, bstar_band(summary.bstar))).or_default();
+        *satellites += 1;
+        stats.merge(&summary.stats);
+    }
+
+    let file = File::create(Path::new(output_dir).join("analysis_bands.csv"))?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "altitude_km_from,altitude_km_to,bstar_decade,satellites,samples,rms_r,rms_t,rms_n,rms_vr,rms_vt,rms_vn,rms_position,mean_position,max_position")?;
+    println!("{:>12} {:>8} {:>6} {:>12} {:>12}", "altitude_km", "bstar", "sats", "rms_pos_m", "max_pos_m");
+    for ((altitude_band, bstar_decade), (satellites, stats)) in bands.iter() {
+        let decade = if *bstar_decade == i32::MIN { "0".to_string() } else { format!("1e{}", bstar_decade) };
+        writeln!(writer, "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            altitude_band, altitude_band + ALTITUDE_BAND_KM as i64, decade, satellites, stats.count,
+            stats.rms(0), stats.rms(1), stats.rms(2), stats.rms(3), stats.rms(4), stats.rms(5),
+            stats.rms_position(), stats.mean_position(), stats.max_position)?;
+        println!("{:>12} {:>8} {:>6} {:>12.1} {:>12.1}", altitude_band, decade, satellites, stats.rms_position(), stats.max_position);
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use satkit::{types::Vector3, Instant};
+
+    fn error(position: [f64; 3], velocity: [f64; 3]) -> RtnError {
+        RtnError { position: Vector3::from(position), velocity: Vector3::from(velocity) }
+    }
+
+    #[test]
+    fn error_stats_accumulate_and_merge() {
+        let mut first = ErrorStats::default();
+        first.add(&error([3.0, 4.0, 0.0], [0.1, 0.0, 0.0]));
+        first.add(&error([0.0, 0.0, 12.0], [0.3, 0.0, 0.0]));
+        let mut second = ErrorStats::default();
+        second.add(&error([1.0, 0.0, 0.0], [0.0, 0.2, 0.0]));
+        first.merge(&second);
+
+        assert_eq!(first.count, 3);
+        assert!((first.rms(0) - (10.0_f64 / 3.0).sqrt()).abs() < 1e-12);
+        assert!((first.rms(3) - (0.1_f64 / 3.0).sqrt()).abs() < 1e-12);
+        assert!((first.rms_position() - ((9.0 + 16.0 + 144.0 + 1.0) / 3.0_f64).sqrt()).abs() < 1e-12);
+        assert!((first.mean_position() - 6.0).abs() < 1e-12); //(5 + 12 + 1) / 3
+        assert_eq!(first.max_position, 12.0);
+        assert_eq!(ErrorStats::default().rms_position(), 0.0);
+    }
+
+    //the reference moves along +y at +x, so radial is x, in-track y and cross-track z
+    #[test]
+    fn rtn_error_uses_the_reference_axes() {
+        let time = Instant::from_unixtime(1.7e9);
+        let reference = SatState::from_pv(&time, &Vector3::new(7.0e6, 0.0, 0.0), &Vector3::new(0.0, 7.5e3, 0.0));
+        let other = SatState::from_pv(&time, &Vector3::new(7.0e6 + 10.0, -20.0, 30.0), &Vector3::new(1.0, 7.5e3 + 2.0, -3.0));
+        let error = rtn_error(&reference, &other);
+        assert!((error.position - Vector3::new(10.0, -20.0, 30.0)).norm() < 1e-6);
+        assert!((error.velocity - Vector3::new(1.0, 2.0, -3.0)).norm() < 1e-9);
+
+        //the same offsets seen from a reference on the other side of the orbit flip radial and in-track
+        let reference = SatState::from_pv(&time, &Vector3::new(-7.0e6, 0.0, 0.0), &Vector3::new(0.0, -7.5e3, 0.0));
+        let other = SatState::from_pv(&time, &Vector3::new(-7.0e6 + 10.0, -20.0, 30.0), &Vector3::new(0.0, -7.5e3, 0.0));
+        assert!((rtn_error(&reference, &other).position - Vector3::new(-10.0, 20.0, 30.0)).norm() < 1e-6);
+    }
+}
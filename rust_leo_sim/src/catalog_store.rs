@@ -0,0 +1,406 @@
+use std::{collections::{BTreeMap, HashMap}, fs::{self, read_to_string, File, OpenOptions}, io::{BufWriter, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+use rayon::prelude::*;
+use satkit::Instant;
+use serde_json::{json, Value};
+use anyhow::{anyhow, bail, Result};
+use crate::satellite::{OrbitalInstance, SatelliteRecord};
+
+const FIELDS: usize = 15;
+const RECORD_SIZE: u64 = FIELDS as u64 * 8; //little-endian f64s, the epoch first so it can be binary searched
+
+//one <catalog number>.bin per satellite with its element sets sorted by epoch, and index.json saying what each holds
+pub(crate) struct CatalogStore {
+    dir: PathBuf,
+    index: BTreeMap<String, IndexEntry>,
+}
+
+#[derive(Clone)]
+struct IndexEntry {
+    international_designator: String,
+    count: u64,
+    first_epoch: f64, //unix seconds
+    last_epoch: f64,
+}
+
+#[derive(Default)]
+pub(crate) struct AppendSummary {
+    pub(crate) satellites: usize,
+    pub(crate) added: u64,
+    pub(crate) duplicates: u64,
+}
+
+impl CatalogStore {
+    pub(crate) fn open(dir: &str) -> Result<CatalogStore> {
+        fs::create_dir_all(dir)?;
+        let dir = PathBuf::from(dir);
+        let mut index: BTreeMap<String, IndexEntry> = BTreeMap::new();
+        let index_path = dir.join("index.json");
+        if index_path.exists() {
+            let json: Value = serde_json::from_str(&read_to_string(&index_path)?)?;
+            for (id, entry) in json.as_object().ok_or_else(|| anyhow!("{} is not an object", index_path.display()))? {
+                let fields = entry["fields"].as_u64().unwrap_or(12); //stores from before the element set number was kept
+                if fields != FIELDS as u64 {
+                    bail!("{} holds records of {fields} fields, this version writes {FIELDS}. Append the TLE files to a new store", index_path.display());
+                }
+                index.insert(id.clone(), IndexEntry {
+                    international_designator: entry["international_designator"].as_str().unwrap_or_default().to_string(),
+                    count: entry["count"].as_u64().ok_or_else(|| anyhow!("No count for {id} in the index"))?,
+                    first_epoch: entry["first_epoch"].as_f64().unwrap_or_default(),
+                    last_epoch: entry["last_epoch"].as_f64().unwrap_or_default(),
+                });
+            }
+        }
+        Ok(CatalogStore { dir, index })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub(crate) fn element_sets(&self) -> u64 {
+        self.index.values().map(|entry| entry.count).sum()
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.bin", id))
+    }
+
+    //adds element sets not already stored (same epoch and element set number), appending in place when they are all newer than what is there
+    pub(crate) fn append(&mut self, satellites: &HashMap<String, SatelliteRecord>) -> Result<AppendSummary> {
+        let updates: Vec<(String, IndexEntry, u64, u64)> = satellites.par_iter()
+            .map(|(id, record)| -> Result<(String, IndexEntry, u64, u64)> {
+                let (entry, added, duplicates) = append_satellite(&self.path(id), self.index.get(id), record)?;
+                Ok((id.clone(), entry, added, duplicates))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut summary = AppendSummary::default();
+        for (id, entry, added, duplicates) in updates {
+            summary.satellites += 1;
+            summary.added += added;
+            summary.duplicates += duplicates;
+            self.index.insert(id, entry);
+        }
+        self.save_index()?;
+        Ok(summary)
+    }
+
+    //satellites (all when None) with their element sets in [from, to], only reading the matching part of each file
+    pub(crate) fn query(&self, ids: Option<&[String]>, from: Option<Instant>, to: Option<Instant>) -> Result<HashMap<String, SatelliteRecord>> {
+        let from = from.map(|from| from.as_unixtime()).unwrap_or(f64::NEG_INFINITY);
+        let to = to.map(|to| to.as_unixtime()).unwrap_or(f64::INFINITY);
+        let entries: Vec<(&String, &IndexEntry)> = match ids {
+            Some(ids) => ids.iter().filter_map(|id| self.index.get_key_value(id)).collect(),
+            None => self.index.iter().collect(),
+        };
+        entries.into_par_iter()
+            .filter(|(_, entry)| entry.count > 0 && entry.last_epoch >= from && entry.first_epoch <= to)
+            .map(|(id, entry)| -> Result<Option<(String, SatelliteRecord)>> {
+                let mut file = File::open(self.path(id))?;
+                let start = lower_bound(&mut file, entry.count, from)?;
+                let end = upper_bound(&mut file, entry.count, to)?;
+                let orbital_records = read_records(&mut file, start, end)?;
+                if orbital_records.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some((id.clone(), SatelliteRecord {
+                    catalog_number: id.parse().unwrap_or_default(),
+                    international_designator: entry.international_designator.clone(),
+                    orbital_records,
+                    satcat: None,
+                })))
+            })
+            .filter_map(|result| result.transpose())
+            .collect()
+    }
+
+    fn save_index(&self) -> Result<()> {
+        let json: serde_json::Map<String, Value> = self.index.iter()
+            .map(|(id, entry)| (id.clone(), json!({
+                "international_designator": entry.international_designator,
+                "count": entry.count,
+                "fields": FIELDS,
+                "first_epoch": entry.first_epoch,
+                "last_epoch": entry.last_epoch,
+            })))
+            .collect();
+        let temporary = self.dir.join("index.json.tmp");
+        fs::write(&temporary, serde_json::to_string(&json)?)?;
+        fs::rename(&temporary, self.dir.join("index.json"))?; //so a crash never leaves half an index
+        Ok(())
+    }
+}
+
+fn append_satellite(path: &Path, entry: Option<&IndexEntry>, record: &SatelliteRecord) -> Result<(IndexEntry, u64, u64)> {
+    let mut incoming: Vec<(f64, &OrbitalInstance)> = record.orbital_records.iter()
+        .map(|instance| (instance.epoch().as_unixtime(), instance))
+        .collect();
+    incoming.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.element_set_number.cmp(&b.1.element_set_number)));
+    let before = incoming.len() as u64;
+    incoming.dedup_by(|a, b| a.0 == b.0 && a.1.element_set_number == b.1.element_set_number);
+    let mut duplicates = before - incoming.len() as u64;
+
+    let mut entry = entry.cloned().unwrap_or(IndexEntry {
+        international_designator: record.international_designator.clone(),
+        count: 0,
+        first_epoch: f64::INFINITY,
+        last_epoch: f64::NEG_INFINITY,
+    });
+    let file_size = if path.exists() { fs::metadata(path)?.len() } else { 0 };
+    if file_size != entry.count * RECORD_SIZE {
+        //an earlier run stopped between writing the file and saving the index, the file is what is actually stored
+        println!("{} holds {} bytes but the index says {} element sets, rebuilding its entry from the file", path.display(), file_size, entry.count);
+        entry = entry_from_file(path, entry.international_designator, file_size / RECORD_SIZE)?;
+    }
+
+    let (records, appended): (Vec<OrbitalInstance>, bool) = if incoming.first().is_none_or(|(epoch, _)| *epoch > entry.last_epoch) {
+        (incoming.iter().map(|(_, instance)| (*instance).clone()).collect(), true)
+    } else {
+        //older or repeated epochs, so the file is merged and written again
+        let mut merged: Vec<OrbitalInstance> = if entry.count > 0 { read_records(&mut File::open(path)?, 0, entry.count)? } else { Vec::new() };
+        let existing = merged.len();
+        merged.extend(incoming.iter().map(|(_, instance)| (*instance).clone()));
+        merged.sort_by(|a, b| a.epoch().as_unixtime().total_cmp(&b.epoch().as_unixtime()).then(a.element_set_number.cmp(&b.element_set_number)));
+        merged.dedup_by(|a, b| a.epoch().as_unixtime() == b.epoch().as_unixtime() && a.element_set_number == b.element_set_number);
+        duplicates += (existing + incoming.len() - merged.len()) as u64;
+        (merged, false)
+    };
+
+    let file = if appended {
+        OpenOptions::new().create(true).append(true).open(path)?
+    } else {
+        File::create(path)?
+    };
+    let mut writer = BufWriter::new(file);
+    for instance in records.iter() {
+        for value in encode(instance) {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+    writer.flush()?;
+
+    let written = records.len() as u64;
+    let count = if appended { entry.count + written } else { written };
+    let epochs = records.iter().map(|instance| instance.epoch().as_unixtime());
+    let (first, last) = epochs.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), epoch| (lo.min(epoch), hi.max(epoch)));
+    Ok((IndexEntry {
+        international_designator: entry.international_designator,
+        count,
+        first_epoch: if appended { entry.first_epoch.min(first) } else { first },
+        last_epoch: if appended { entry.last_epoch.max(last) } else { last },
+    }, count - entry.count, duplicates))
+}
+
+//index entry for the first count whole records of a file, dropping a record cut off part way through
+fn entry_from_file(path: &Path, international_designator: String, count: u64) -> Result<IndexEntry> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    file.set_len(count * RECORD_SIZE)?;
+    let records = read_records(&mut file, 0, count)?;
+    let epochs: Vec<f64> = records.iter().map(|instance| instance.epoch().as_unixtime()).collect();
+    if epochs.windows(2).any(|pair| pair[1] < pair[0]) {
+        bail!("{} is not sorted by epoch and can't be recovered", path.display());
+    }
+    Ok(IndexEntry {
+        international_designator,
+        count,
+        first_epoch: epochs.first().copied().unwrap_or(f64::INFINITY),
+        last_epoch: epochs.last().copied().unwrap_or(f64::NEG_INFINITY),
+    })
+}
+
+fn encode(instance: &OrbitalInstance) -> [f64; FIELDS] {
+    [
+        instance.epoch().as_unixtime(),
+        instance.epoch_year as f64,
+        instance.epoch_day,
+        instance.first_time_derivative,
+        instance.second_time_derivative,
+        instance.drag,
+        instance.inclination,
+        instance.raan,
+        instance.eccentricity,
+        instance.perigee,
+        instance.mean_anomaly,
+        instance.mean_motion,
+        instance.element_set_number as f64,
+        instance.revolution_number as f64,
+        instance.ephemeris_type as f64,
+    ]
+}
+
+fn decode(values: &[f64]) -> OrbitalInstance {
+    OrbitalInstance {
+        epoch_year: values[1] as i32,
+        epoch_day: values[2],
+        first_time_derivative: values[3],
+        second_time_derivative: values[4],
+        drag: values[5],
+        inclination: values[6],
+        raan: values[7],
+        eccentricity: values[8],
+        perigee: values[9],
+        mean_anomaly: values[10],
+        mean_motion: values[11],
+        element_set_number: values[12] as i32,
+        revolution_number: values[13] as i32,
+        ephemeris_type: values[14] as u8,
+    }
+}
+
+fn epoch_at(file: &mut File, k: u64) -> Result<f64> {
+    let mut bytes = [0u8; 8];
+    file.seek(SeekFrom::Start(k * RECORD_SIZE))?;
+    file.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+//first record with an epoch at or after the time
+fn lower_bound(file: &mut File, count: u64, time: f64) -> Result<u64> {
+    let (mut lo, mut hi) = (0, count);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if epoch_at(file, mid)? < time { lo = mid + 1 } else { hi = mid }
+    }
+    Ok(lo)
+}
+
+//first record with an epoch after the time
+fn upper_bound(file: &mut File, count: u64, time: f64) -> Result<u64> {
+    let (mut lo, mut hi) = (0, count);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if epoch_at(file, mid)? <= time { lo = mid + 1 } else { hi = mid }
+    }
+    Ok(lo)
+}
+
+fn read_records(file: &mut File, start: u64, end: u64) -> Result<Vec<OrbitalInstance>> {
+    if end <= start {
+        return Ok(Vec::new());
+    }
+    let mut bytes = vec![0u8; ((end - start) * RECORD_SIZE) as usize];
+    file.seek(SeekFrom::Start(start * RECORD_SIZE))?;
+    file.read_exact(&mut bytes)?;
+    let values: Vec<f64> = bytes.chunks_exact(8).map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap())).collect();
+    Ok(values.chunks_exact(FIELDS).map(decode).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(days: &[f64]) -> SatelliteRecord {
+        SatelliteRecord {
+            catalog_number: 25544,
+            international_designator: "98067A".to_string(),
+            orbital_records: days.iter()
+                .map(|day| OrbitalInstance {
+                    epoch_year: 2024,
+                    epoch_day: *day,
+                    first_time_derivative: 1.6717e-4,
+                    second_time_derivative: 0.0,
+                    drag: 1.027e-4,
+                    inclination: 51.64,
+                    raan: 208.9163,
+                    eccentricity: 6.317e-4,
+                    perigee: 69.9862,
+                    mean_anomaly: 25.2906,
+                    mean_motion: 15.49815322,
+                    element_set_number: 999,
+                    revolution_number: 43000 + (*day * 15.5) as i32,
+                    ephemeris_type: 0,
+                })
+                .collect(),
+            satcat: None,
+        }
+    }
+
+    fn days(satellites: &HashMap<String, SatelliteRecord>) -> Vec<f64> {
+        satellites["25544"].orbital_records.iter().map(|instance| instance.epoch_day).collect()
+    }
+
+    #[test]
+    fn appends_merges_and_queries_by_epoch() {
+        let dir = std::env::temp_dir().join(format!("catalog_store_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_string_lossy().to_string();
+
+        let mut store = CatalogStore::open(&dir).unwrap();
+        let summary = store.append(&HashMap::from([("25544".to_string(), record(&[3.5, 1.5, 2.5]))])).unwrap();
+        assert_eq!((summary.added, summary.duplicates), (3, 0));
+        let summary = store.append(&HashMap::from([("25544".to_string(), record(&[4.5, 5.5]))])).unwrap(); //newer, appended in place
+        assert_eq!(summary.added, 2);
+        let summary = store.append(&HashMap::from([("25544".to_string(), record(&[0.5, 2.5]))])).unwrap(); //older and repeated, merged
+        assert_eq!((summary.added, summary.duplicates), (1, 1));
+
+        let store = CatalogStore::open(&dir).unwrap(); //reopened from the index
+        assert_eq!((store.len(), store.element_sets()), (1, 6));
+        assert_eq!(days(&store.query(None, None, None).unwrap()), vec![0.5, 1.5, 2.5, 3.5, 4.5, 5.5]);
+
+        let day = |d: f64| Instant::from_date(2024, 1, 1) + satkit::Duration::from_days(d - 1.0);
+        let window = store.query(Some(&["25544".to_string()]), Some(day(1.5)), Some(day(4.0))).unwrap();
+        assert_eq!(days(&window), vec![1.5, 2.5, 3.5]);
+        assert!(store.query(None, Some(day(10.0)), None).unwrap().is_empty());
+        assert!(store.query(Some(&["1".to_string()]), None, None).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recovers_from_a_file_written_without_its_index() {
+        let dir = std::env::temp_dir().join(format!("catalog_store_crash_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_string_lossy().to_string();
+
+        let mut store = CatalogStore::open(&dir).unwrap();
+        store.append(&HashMap::from([("25544".to_string(), record(&[1.5, 2.5]))])).unwrap();
+        //a run that died after appending to the file but before saving the index, part way through another record
+        let path = store.path("25544");
+        append_satellite(&path, store.index.get("25544"), &record(&[3.5])).unwrap();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0u8; 20]).unwrap();
+
+        let mut store = CatalogStore::open(&dir).unwrap();
+        assert_eq!(store.element_sets(), 2);
+        let summary = store.append(&HashMap::from([("25544".to_string(), record(&[3.5, 4.5]))])).unwrap();
+        assert_eq!((summary.added, summary.duplicates), (1, 1));
+        let store = CatalogStore::open(&dir).unwrap();
+        assert_eq!(days(&store.query(None, None, None).unwrap()), vec![1.5, 2.5, 3.5, 4.5]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn query_writes_back_the_lines_that_were_appended() {
+        let dir = std::env::temp_dir().join(format!("catalog_store_lines_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_string_lossy().to_string();
+
+        //the second is a reissue at the same epoch under the next element set number, so both are kept
+        let lines = [
+            ("1 25544U 98067A   08264.51782528 -.00002182  00000-0 -11606-4 0  2927", "2 25544  51.6416 247.4627 0006703 130.5360 325.0288 15.72125391563537"),
+            ("1 25544U 98067A   08264.51782528 -.00002182  00000-0 -11606-4 0  2938", "2 25544  51.6416 247.4627 0006703 130.5360 325.0288 15.72125391563548"),
+        ];
+        let tles: Vec<satkit::TLE> = lines.iter().map(|(line1, line2)| satkit::TLE::load_2line(line1, line2).unwrap()).collect();
+        let written = |tles: &[satkit::TLE]| -> String {
+            let mut output: Vec<u8> = Vec::new();
+            for tle in tles {
+                crate::numerical_integration::write_tle_data(&mut output, tle).unwrap();
+            }
+            String::from_utf8(output).unwrap()
+        };
+        let record = SatelliteRecord {
+            catalog_number: 25544,
+            international_designator: tles[0].intl_desig.clone(),
+            orbital_records: tles.iter().map(OrbitalInstance::from_tle).collect(),
+            satcat: None,
+        };
+
+        let mut store = CatalogStore::open(&dir).unwrap();
+        let summary = store.append(&HashMap::from([("25544".to_string(), record)])).unwrap();
+        assert_eq!((summary.added, summary.duplicates), (2, 0));
+        let queried = &store.query(None, None, None).unwrap()["25544"];
+        let queried: Vec<satkit::TLE> = queried.orbital_records.iter().map(|instance| instance.to_tle(25544, &queried.international_designator)).collect();
+        assert_eq!(written(&queried), written(&tles));
+        let expected: Vec<&str> = lines.iter().flat_map(|(line1, line2)| [*line1, *line2]).collect();
+        assert_eq!(written(&queried).lines().map(|line| line.trim_end()).collect::<Vec<&str>>(), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
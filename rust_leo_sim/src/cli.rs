@@ -0,0 +1,79 @@
+use std::{collections::HashMap, str::FromStr};
+use anyhow::{anyhow, Result};
+use satkit::Instant;
+
+//holds the "--key value" pairs and bare "--switch" flags that follow the mode key
+pub(crate) struct Flags {
+    values: HashMap<String, Vec<String>>,
+}
+
+impl Flags {
+    pub(crate) fn parse(args: &[String]) -> Flags {
+        let mut values: HashMap<String, Vec<String>> = HashMap::new();
+
+        let mut i = 0;
+        while i < args.len() {
+            if let Some(key) = args[i].strip_prefix("--") {
+                let entry = values.entry(key.to_string()).or_default();
+                if i + 1 < args.len() && !args[i + 1].starts_with("--") { //a flag followed by a value, otherwise it's a switch
+                    entry.push(args[i + 1].clone());
+                    i += 1;
+                }
+            }
+            i += 1;
+        }
+
+        Flags { values }
+    }
+
+    //last value given for a flag, so later flags override earlier ones
+    pub(crate) fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).and_then(|v| v.last()).map(|v| v.as_str())
+    }
+
+    pub(crate) fn get_or<T: FromStr>(&self, key: &str, default: T) -> Result<T> {
+        match self.get(key) {
+            Some(value) => value.parse::<T>().map_err(|_| anyhow!("Invalid value for --{key}: {value}")),
+            None => Ok(default),
+        }
+    }
+
+    pub(crate) fn get_parsed<T: FromStr>(&self, key: &str) -> Result<Option<T>> {
+        match self.get(key) {
+            Some(value) => value.parse::<T>().map(Some).map_err(|_| anyhow!("Invalid value for --{key}: {value}")),
+            None => Ok(None),
+        }
+    }
+
+    //comma separated values, e.g. "--sigma-rtn 100,500,50"
+    pub(crate) fn get_list<T: FromStr>(&self, key: &str) -> Result<Option<Vec<T>>> {
+        match self.get(key) {
+            Some(value) => value.split(',')
+                .map(|v| v.trim().parse::<T>().map_err(|_| anyhow!("Invalid value for --{key}: {value}")))
+                .collect::<Result<Vec<T>>>()
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    //accepts RFC 3339 / ISO 8601 strings or unix seconds
+    pub(crate) fn get_instant(&self, key: &str) -> Result<Option<Instant>> {
+        match self.get(key) {
+            Some(value) => Ok(Some(parse_instant(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn has(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+}
+
+pub(crate) fn parse_instant(value: &str) -> Result<Instant> {
+    if let Ok(unix_seconds) = value.parse::<f64>() {
+        return Ok(Instant::from_unixtime(unix_seconds));
+    }
+    Instant::from_rfc3339(value)
+        .or_else(|_| Instant::from_string(value))
+        .map_err(|e| anyhow!("Could not parse time {value}: {e}"))
+}
@@ -0,0 +1,505 @@
+use std::{collections::HashMap, str::FromStr};
+use nalgebra::{Matrix2, Matrix2x3, Vector2};
+use satkit::{orbitprop::{SatState, StateCov}, types::{Matrix3, Matrix6, Vector3}};
+use anyhow::{bail, Result};
+use crate::frames::rtn_rotation;
+use crate::conjunction::Conjunction;
+use crate::consistency::read_covariances;
+
+const FOSTER_PANELS: usize = 64; //initial panels before adaptive refinement, so a narrow peak isn't stepped over
+const FOSTER_TOLERANCE: f64 = 1.0e-14;
+const FOSTER_MAX_DEPTH: u32 = 40;
+const ERFC_SERIES_LIMIT: f64 = 2.5; //above this erfc comes from the continued fraction
+const ERFC_FRACTION_TERMS: usize = 100;
+const CHOLESKY_PIVOT_TOLERANCE: f64 = 1.0e-10; //relative to the diagonal entry, below it the direction counts as certain
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum PcMethod {
+    Foster, //numerical integration of the 2D gaussian over the hard body circle
+    Chan, //series expansion, exact for isotropic covariance and an approximation otherwise
+}
+
+impl FromStr for PcMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "foster" => Ok(PcMethod::Foster),
+            "chan" => Ok(PcMethod::Chan),
+            _ => bail!("Unknown Pc method {s}, expected foster or chan"),
+        }
+    }
+}
+
+//where state covariances come from when the propagated states don't carry one
+pub(crate) struct CovarianceSource {
+    pub(crate) rtn: HashMap<String, Matrix6>, //per object RTN covariance, e.g. the covariance.csv the consistency check writes
+    pub(crate) default_sigma_rtn: Option<Vector3>, //m, 1-sigma R, T, N position uncertainty for objects without one
+}
+
+impl CovarianceSource {
+    pub(crate) fn new(path: Option<&str>, default_sigma_rtn: Option<Vector3>) -> Result<CovarianceSource> {
+        let rtn = match path {
+            Some(path) => read_covariances(path)?,
+            None => HashMap::new(),
+        };
+        Ok(CovarianceSource { rtn, default_sigma_rtn })
+    }
+
+    //GCRF position/velocity covariance of an object at the given state
+    pub(crate) fn covariance_for(&self, id: &str, state: &SatState) -> Option<Matrix6> {
+        let rtn = match (self.rtn.get(id), self.default_sigma_rtn) {
+            (Some(covariance), _) => *covariance,
+            (None, Some(sigma)) => {
+                let mut covariance = Matrix6::zeros();
+                for i in 0..3 {
+                    covariance[(i, i)] = sigma[i].powi(2);
+                }
+                covariance
+            }
+            (None, None) => return None,
+        };
+        Some(rtn_to_gcrf(&rtn, state))
+    }
+}
+
+//rotates an RTN covariance into GCRF at the given state, ignoring the rotation rate of the RTN frame
+pub(crate) fn rtn_to_gcrf(covariance: &Matrix6, state: &SatState) -> Matrix6 {
+    let transform = rtn_transform(state).transpose();
+    transform * covariance * transform.transpose()
+}
+
+//the other way around, into the radial/in-track/cross-track axes of the state
+pub(crate) fn gcrf_to_rtn(covariance: &Matrix6, state: &SatState) -> Matrix6 {
+    let transform = rtn_transform(state);
+    transform * covariance * transform.transpose()
+}
+
+//GCRF to RTN for position and velocity alike
+fn rtn_transform(state: &SatState) -> Matrix6 {
+    let rotation: Matrix3 = rtn_rotation(&state.pos_gcrf(), &state.vel_gcrf());
+    let mut transform = Matrix6::zeros();
+    transform.fixed_view_mut::<3, 3>(0, 0).copy_from(&rotation);
+    transform.fixed_view_mut::<3, 3>(3, 3).copy_from(&rotation);
+    transform
+}
+
+//lower triangular L with L * L^T = covariance. A covariance seeded from position sigmas alone is only
+//semi-definite, so a column whose pivot vanishes (a direction without uncertainty) is left at zero instead of failing
+pub(crate) fn cholesky_factor(covariance: &Matrix6) -> Matrix6 {
+    let mut factor = Matrix6::zeros();
+    for j in 0..6 {
+        let pivot = covariance[(j, j)] - (0..j).map(|k| factor[(j, k)].powi(2)).sum::<f64>();
+        if pivot <= covariance[(j, j)] * CHOLESKY_PIVOT_TOLERANCE {
+            continue;
+        }
+        factor[(j, j)] = pivot.sqrt();
+        for i in j + 1..6 {
+            factor[(i, j)] = (covariance[(i, j)] - (0..j).map(|k| factor[(i, k)] * factor[(j, k)]).sum::<f64>()) / factor[(j, j)];
+        }
+    }
+    factor
+}
+
+//how a propagated covariance is written next to each state
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum CovarianceForm {
+    Full, //upper triangle of the 6x6, m^2, m^2/s and m^2/s^2
+    Cholesky, //lower triangle of its Cholesky factor, m and m/s
+}
+
+impl FromStr for CovarianceForm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(CovarianceForm::Full),
+            "cholesky" => Ok(CovarianceForm::Cholesky),
+            _ => bail!("Unknown covariance form {s}, expected full or cholesky"),
+        }
+    }
+}
+
+//the 21 covariance columns a written step gets
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct CovarianceColumns {
+    pub(crate) form: CovarianceForm,
+    pub(crate) rtn: bool, //in the radial/in-track/cross-track axes of the step's own state instead of GCRF
+}
+
+impl CovarianceColumns {
+    //e.g. cov_x_vx or chol_t_r, row then column of the matrix
+    pub(crate) fn columns(self) -> Vec<String> {
+        let axes = if self.rtn { ["r", "t", "n", "vr", "vt", "vn"] } else { ["x", "y", "z", "vx", "vy", "vz"] };
+        let prefix = match self.form {
+            CovarianceForm::Full => "cov",
+            CovarianceForm::Cholesky => "chol",
+        };
+        self.entries().map(|(i, j)| format!("{prefix}_{}_{}", axes[i], axes[j])).collect()
+    }
+
+    //the columns of a state, NaN when it has no covariance (an object the source knows nothing about)
+    pub(crate) fn values(self, state: &SatState) -> Vec<f64> {
+        let covariance = match &state.cov {
+            StateCov::PVCov(covariance) if self.rtn => gcrf_to_rtn(covariance, state),
+            StateCov::PVCov(covariance) => *covariance,
+            StateCov::None => return vec![f64::NAN; 21],
+        };
+        let matrix = match self.form {
+            CovarianceForm::Full => covariance,
+            CovarianceForm::Cholesky => cholesky_factor(&covariance),
+        };
+        self.entries().map(|(i, j)| matrix[(i, j)]).collect()
+    }
+
+    //row major over the triangle that holds the values, upper for the symmetric matrix and lower for the factor
+    fn entries(self) -> impl Iterator<Item = (usize, usize)> {
+        let form = self.form;
+        (0..6).flat_map(move |i| (0..6).map(move |j| (i, j)))
+            .filter(move |(i, j)| match form {
+                CovarianceForm::Full => i <= j,
+                CovarianceForm::Cholesky => i >= j,
+            })
+    }
+}
+
+//attaches covariances to the states at TCA and fills in the probability of collision where both objects have one
+pub(crate) fn assess(conjunctions: &mut [Conjunction], source: &CovarianceSource, hard_body_radius: f64, method: PcMethod) {
+    for conjunction in conjunctions.iter_mut() {
+        if let Some(covariance) = source.covariance_for(&conjunction.primary, &conjunction.primary_state) {
+            conjunction.primary_state.set_cov(StateCov::PVCov(covariance));
+        }
+        if let Some(covariance) = source.covariance_for(&conjunction.secondary, &conjunction.secondary_state) {
+            conjunction.secondary_state.set_cov(StateCov::PVCov(covariance));
+        }
+        conjunction.probability = collision_probability(&conjunction.primary_state, &conjunction.secondary_state, hard_body_radius, method);
+    }
+}
+
+//probability that the objects come within the hard body radius of each other, None when either state has no covariance
+pub(crate) fn collision_probability(primary: &SatState, secondary: &SatState, hard_body_radius: f64, method: PcMethod) -> Option<f64> {
+    let combined = position_covariance(primary)? + position_covariance(secondary)?;
+    let encounter = Encounter::new(primary, secondary, &combined)?;
+    Some(match method {
+        PcMethod::Foster => encounter.foster(hard_body_radius),
+        PcMethod::Chan => encounter.chan(hard_body_radius),
+    })
+}
+
+fn position_covariance(state: &SatState) -> Option<Matrix3> {
+    match &state.cov {
+        StateCov::PVCov(covariance) => Some(covariance.fixed_view::<3, 3>(0, 0).into_owned()),
+        StateCov::None => None,
+    }
+}
+
+//relative position and combined covariance projected onto the plane perpendicular to the relative velocity,
+//rotated to the principal axes of the covariance
+pub(crate) struct Encounter {
+    pub(crate) miss: Vector2<f64>, //m
+    pub(crate) sigma: Vector2<f64>, //m, 1-sigma along the principal axes
+}
+
+impl Encounter {
+    pub(crate) fn new(primary: &SatState, secondary: &SatState, combined: &Matrix3) -> Option<Encounter> {
+        let relative_position = secondary.pos_gcrf() - primary.pos_gcrf();
+        let relative_velocity = secondary.vel_gcrf() - primary.vel_gcrf();
+        let along = relative_velocity.try_normalize(f64::EPSILON)?;
+
+        //first axis points at the secondary, or anywhere perpendicular when the miss is exactly zero
+        let radial = relative_position - relative_position.dot(&along) * along;
+        let x = match radial.try_normalize(f64::EPSILON) {
+            Some(x) => x,
+            None => along.cross(&Vector3::x()).try_normalize(f64::EPSILON).unwrap_or_else(|| along.cross(&Vector3::y()).normalize()),
+        };
+        let y = along.cross(&x);
+
+        let projection = Matrix2x3::from_rows(&[x.transpose(), y.transpose()]);
+        let miss = projection * relative_position;
+        let covariance: Matrix2<f64> = projection * combined * projection.transpose();
+        Encounter::from_plane(miss, covariance)
+    }
+
+    pub(crate) fn from_plane(miss: Vector2<f64>, covariance: Matrix2<f64>) -> Option<Encounter> {
+        let eigen = covariance.symmetric_eigen();
+        if eigen.eigenvalues.iter().any(|value| *value <= 0.0) {
+            return None;
+        }
+        Some(Encounter {
+            miss: eigen.eigenvectors.transpose() * miss,
+            sigma: eigen.eigenvalues.map(f64::sqrt),
+        })
+    }
+
+    //Foster: integrates the gaussian over the hard body circle, the inner integral done in closed form with erf
+    //x = R sin(theta) keeps the integrand smooth at the edges of the circle
+    pub(crate) fn foster(&self, hard_body_radius: f64) -> f64 {
+        if hard_body_radius <= 0.0 {
+            return 0.0;
+        }
+        let (sx, sy) = (self.sigma[0], self.sigma[1]);
+        let (xm, ym) = (self.miss[0], self.miss[1]);
+        let integrand = |theta: f64| -> f64 {
+            let x = hard_body_radius * theta.sin();
+            let h = hard_body_radius * theta.cos();
+            let strip = erf_difference((h - ym) / (2.0_f64.sqrt() * sy), (-h - ym) / (2.0_f64.sqrt() * sy));
+            h * (-(x - xm).powi(2) / (2.0 * sx.powi(2))).exp() * strip
+        };
+
+        let (lo, hi) = (-std::f64::consts::FRAC_PI_2, std::f64::consts::FRAC_PI_2);
+        let width = (hi - lo) / FOSTER_PANELS as f64;
+        let integral: f64 = (0..FOSTER_PANELS)
+            .map(|i| {
+                let (a, b) = (lo + i as f64 * width, lo + (i + 1) as f64 * width);
+                let (fa, fm, fb) = (integrand(a), integrand((a + b) / 2.0), integrand(b));
+                adaptive_simpson(&integrand, a, b, fa, fm, fb, FOSTER_TOLERANCE, FOSTER_MAX_DEPTH)
+            })
+            .sum();
+        (integral / ((8.0 * std::f64::consts::PI).sqrt() * sx)).clamp(0.0, 1.0)
+    }
+
+    //Chan: noncentral chi-square series with the covariance replaced by an isotropic one of equal area
+    pub(crate) fn chan(&self, hard_body_radius: f64) -> f64 {
+        if hard_body_radius <= 0.0 {
+            return 0.0;
+        }
+        let u = hard_body_radius.powi(2) / (self.sigma[0] * self.sigma[1]);
+        let v = (self.miss[0] / self.sigma[0]).powi(2) + (self.miss[1] / self.sigma[1]).powi(2);
+
+        let terms = series_length(v / 2.0);
+        let outer = poisson_weights(v / 2.0, terms);
+        let inner = poisson_weights(u / 2.0, terms.max(series_length(u / 2.0)));
+
+        //tail[m] = P(Poisson(u/2) > m), summed from the small end so it stays accurate when it is tiny
+        let mut tail = vec![0.0; inner.len()];
+        for m in (0..inner.len() - 1).rev() {
+            tail[m] = tail[m + 1] + inner[m + 1];
+        }
+        outer.iter().zip(tail.iter()).map(|(weight, t)| weight * t).sum::<f64>().clamp(0.0, 1.0)
+    }
+}
+
+//number of terms that covers a poisson distribution with mean lambda
+fn series_length(lambda: f64) -> usize {
+    (lambda + 12.0 * lambda.sqrt() + 30.0).ceil() as usize
+}
+
+//poisson probabilities for k = 0..n, computed in log space so large means don't under or overflow
+fn poisson_weights(lambda: f64, n: usize) -> Vec<f64> {
+    if lambda == 0.0 {
+        let mut weights = vec![0.0; n];
+        weights[0] = 1.0;
+        return weights;
+    }
+    let mut log_factorial = 0.0;
+    (0..n)
+        .map(|k| {
+            if k > 0 {
+                log_factorial += (k as f64).ln();
+            }
+            (-lambda + k as f64 * lambda.ln() - log_factorial).exp()
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn adaptive_simpson(f: &dyn Fn(f64) -> f64, a: f64, b: f64, fa: f64, fm: f64, fb: f64, tolerance: f64, depth: u32) -> f64 {
+    let m = (a + b) / 2.0;
+    let (lm, rm) = ((a + m) / 2.0, (m + b) / 2.0);
+    let (flm, frm) = (f(lm), f(rm));
+    let whole = (b - a) / 6.0 * (fa + 4.0 * fm + fb);
+    let left = (m - a) / 6.0 * (fa + 4.0 * flm + fm);
+    let right = (b - m) / 6.0 * (fm + 4.0 * frm + fb);
+    if depth == 0 || (left + right - whole).abs() <= 15.0 * tolerance {
+        return left + right + (left + right - whole) / 15.0;
+    }
+    adaptive_simpson(f, a, m, fa, flm, fm, tolerance / 2.0, depth - 1) + adaptive_simpson(f, m, b, fm, frm, fb, tolerance / 2.0, depth - 1)
+}
+
+//erf(a) - erf(b) for a >= b, using erfc on the side where both are close to +-1
+fn erf_difference(a: f64, b: f64) -> f64 {
+    if b >= 0.0 {
+        erfc(b) - erfc(a)
+    } else if a <= 0.0 {
+        erfc(-a) - erfc(-b)
+    } else {
+        erf(a) - erf(b)
+    }
+}
+
+pub(crate) fn erf(x: f64) -> f64 {
+    if x < 0.0 {
+        return -erf(-x);
+    }
+    if x < ERFC_SERIES_LIMIT {
+        erf_series(x)
+    } else {
+        1.0 - erfc(x)
+    }
+}
+
+pub(crate) fn erfc(x: f64) -> f64 {
+    if x < 0.0 {
+        return 2.0 - erfc(-x);
+    }
+    if x < ERFC_SERIES_LIMIT {
+        return 1.0 - erf_series(x);
+    }
+    //erfc(x) = exp(-x^2)/sqrt(pi) / (x + (1/2)/(x + 1/(x + (3/2)/(x + ...)))), evaluated from the tail
+    let mut fraction = x;
+    for k in (1..=ERFC_FRACTION_TERMS).rev() {
+        fraction = x + (k as f64 / 2.0) / fraction;
+    }
+    (-x * x).exp() / (std::f64::consts::PI.sqrt() * fraction)
+}
+
+//erf(x) = 2/sqrt(pi) exp(-x^2) sum 2^n x^(2n+1) / (1*3*...*(2n+1)), all terms positive so nothing cancels
+fn erf_series(x: f64) -> f64 {
+    let mut term = x;
+    let mut sum = x;
+    let mut n = 0.0;
+    while term > sum * 1.0e-17 {
+        n += 1.0;
+        term *= 2.0 * x * x / (2.0 * n + 1.0);
+        sum += term;
+    }
+    2.0 / std::f64::consts::PI.sqrt() * (-x * x).exp() * sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use satkit::Instant;
+
+    fn relative_error(a: f64, b: f64) -> f64 {
+        (a - b).abs() / b.abs()
+    }
+
+    fn isotropic(miss: f64, sigma: f64) -> Encounter {
+        Encounter::from_plane(Vector2::new(miss, 0.0), Matrix2::identity() * sigma.powi(2)).unwrap()
+    }
+
+    #[test]
+    fn erf_matches_reference_values() {
+        let cases = [(0.5, 0.5204998778130465), (1.0, 0.8427007929497149), (2.0, 0.9953222650189527), (3.0, 0.9999779095030014)];
+        for (x, expected) in cases {
+            assert!(relative_error(erf(x), expected) < 1.0e-14, "erf({x}) = {}", erf(x));
+            assert!(relative_error(erf(-x), -expected) < 1.0e-14);
+        }
+        assert!(relative_error(erfc(3.0), 2.209049699858544e-5) < 1.0e-12);
+        assert!(relative_error(erfc(5.0), 1.537459794428035e-12) < 1.0e-12);
+    }
+
+    //zero miss with isotropic covariance has the closed form 1 - exp(-R^2 / 2 sigma^2)
+    #[test]
+    fn isotropic_zero_miss_matches_closed_form() {
+        for (radius, sigma) in [(10.0_f64, 50.0_f64), (20.0, 1000.0), (100.0, 30.0)] {
+            let expected = 1.0 - (-(radius * radius) / (2.0 * sigma * sigma)).exp();
+            let encounter = isotropic(0.0, sigma);
+            assert!(relative_error(encounter.foster(radius), expected) < 1.0e-9, "foster {radius} {sigma}");
+            assert!(relative_error(encounter.chan(radius), expected) < 1.0e-12, "chan {radius} {sigma}");
+        }
+    }
+
+    //Chan's series is exact for isotropic covariance, so the two methods have to agree with a miss too
+    #[test]
+    fn foster_matches_chan_for_isotropic_offset() {
+        for (miss, radius, sigma) in [(100.0_f64, 20.0, 50.0), (1000.0, 15.0, 200.0), (30.0, 50.0, 10.0), (5000.0, 10.0, 800.0)] {
+            let encounter = isotropic(miss, sigma);
+            let (foster, chan) = (encounter.foster(radius), encounter.chan(radius));
+            assert!(relative_error(foster, chan) < 1.0e-8, "miss {miss}: foster {foster} chan {chan}");
+        }
+    }
+
+    //for a hard body much smaller than the covariance, Pc is the circle area times the density at the miss
+    #[test]
+    fn small_hard_body_matches_point_density() {
+        let covariance = Matrix2::new(300.0_f64.powi(2), 4000.0, 4000.0, 80.0_f64.powi(2));
+        let miss = Vector2::new(150.0, -60.0);
+        let encounter = Encounter::from_plane(miss, covariance).unwrap();
+        let radius = 1.0;
+        let density = (-0.5 * (miss.transpose() * covariance.try_inverse().unwrap() * miss)[0]).exp()
+            / (2.0 * std::f64::consts::PI * covariance.determinant().sqrt());
+        let expected = std::f64::consts::PI * radius * radius * density;
+        assert!(relative_error(encounter.foster(radius), expected) < 1.0e-4);
+        assert!(relative_error(encounter.chan(radius), expected) < 1.0e-4);
+    }
+
+    //two objects crossing at right angles, with the covariance set on the states the way assess() does
+    #[test]
+    fn states_project_onto_encounter_plane() {
+        let time = Instant::from_unixtime(1.7e9);
+        let mut primary = SatState::from_pv(&time, &Vector3::new(7.0e6, 0.0, 0.0), &Vector3::new(0.0, 7500.0, 0.0));
+        let mut secondary = SatState::from_pv(&time, &Vector3::new(7.0e6, 0.0, 200.0), &Vector3::new(0.0, 0.0, 7500.0));
+        let mut covariance = Matrix6::zeros();
+        for (i, sigma) in [100.0, 300.0, 50.0, 0.1, 0.1, 0.1].iter().enumerate() {
+            covariance[(i, i)] = sigma * sigma;
+        }
+        primary.set_cov(StateCov::PVCov(covariance));
+        secondary.set_cov(StateCov::PVCov(covariance));
+
+        let combined = position_covariance(&primary).unwrap() + position_covariance(&secondary).unwrap();
+        let encounter = Encounter::new(&primary, &secondary, &combined).unwrap();
+        //relative velocity lies in the y-z plane at 45 degrees, so the plane keeps x and the y-z diagonal
+        //and only the part of the offset along that diagonal counts as miss
+        assert!((encounter.miss.norm() - 100.0 * 2.0_f64.sqrt()).abs() < 1.0e-9);
+        let mut sigmas = [encounter.sigma[0], encounter.sigma[1]];
+        sigmas.sort_by(|a, b| a.total_cmp(b));
+        let diagonal = (300.0_f64.powi(2) + 50.0_f64.powi(2)).sqrt(); //both objects contribute half of each axis along the diagonal
+        assert!(relative_error(sigmas[0], 100.0 * 2.0_f64.sqrt()) < 1.0e-12);
+        assert!(relative_error(sigmas[1], diagonal) < 1.0e-12);
+
+        let foster = collision_probability(&primary, &secondary, 20.0, PcMethod::Foster).unwrap();
+        let chan = collision_probability(&primary, &secondary, 20.0, PcMethod::Chan).unwrap();
+        assert!(foster > 0.0 && relative_error(chan, foster) < 0.05);
+        assert!(collision_probability(&SatState::from_pv(&time, &primary.pos_gcrf(), &primary.vel_gcrf()), &secondary, 20.0, PcMethod::Foster).is_none());
+    }
+
+    //Alfano (2009) conjunction test case with the Foster Pc NASA CARA's Pc2D unit tests check against,
+    //GCRF km, km/s and km^2 with a 20 m hard body radius, to the 1e-3 relative accuracy those tests use
+    #[test]
+    fn matches_published_alfano_case() {
+        let time = Instant::from_unixtime(1.7e9);
+        let primary = SatState::from_pv(&time, &Vector3::new(378.39559, 4305.721887, 5752.767554), &Vector3::new(2.360800244, 5.580331936, -4.322349039));
+        let secondary = SatState::from_pv(&time, &Vector3::new(374.5180598, 4307.560983, 5751.130418), &Vector3::new(-5.388125081, -3.946827739, 3.322820358));
+        let primary_covariance = Matrix3::new(
+            44.5757544811362, 81.6751751052616, -67.8687662707124,
+            81.6751751052616, 158.453402956163, -128.616921644857,
+            -67.8687662707124, -128.616921644857, 105.490542562701);
+        let secondary_covariance = Matrix3::new(
+            2.31067077720423, 1.69905293875632, -1.4170164577661,
+            1.69905293875632, 1.24957388457206, -1.04174164279599,
+            -1.4170164577661, -1.04174164279599, 0.869260558223714);
+        let expected = 2.70601573490125e-5;
+
+        let encounter = Encounter::new(&primary, &secondary, &(primary_covariance + secondary_covariance)).unwrap();
+        assert!(relative_error(encounter.foster(0.020), expected) < 1.0e-3, "Foster {}", encounter.foster(0.020));
+        //Chan's series is exact only for isotropic covariance, this one has a 4:1 aspect ratio
+        assert!(relative_error(encounter.chan(0.020), expected) < 1.0e-3, "Chan {}", encounter.chan(0.020));
+    }
+
+    //a covariance seeded from RTN position sigmas only, after a shear like the one propagation introduces
+    #[test]
+    fn cholesky_factor_handles_semi_definite_covariances() {
+        let time = Instant::from_unixtime(1.7e9);
+        let state = SatState::from_pv(&time, &Vector3::new(5.0e6, 4.0e6, 1.0e6), &Vector3::new(-4.0e3, 5.0e3, 2.0e3));
+        let mut rtn = Matrix6::zeros();
+        for (i, sigma) in [100.0, 1000.0, 50.0].iter().enumerate() {
+            rtn[(i, i)] = sigma * sigma;
+        }
+        let mut shear = Matrix6::identity();
+        shear[(1, 0)] = 0.5;
+        shear[(4, 0)] = -2.0e-3;
+        let covariance = rtn_to_gcrf(&(shear * rtn * shear.transpose()), &state);
+
+        let factor = cholesky_factor(&covariance);
+        assert!(factor.upper_triangle().iter().zip(Matrix6::from_diagonal(&factor.diagonal()).iter()).all(|(a, b)| a == b));
+        assert!((factor * factor.transpose() - covariance).abs().max() < 1.0e-9 * covariance.abs().max());
+        assert!((gcrf_to_rtn(&covariance, &state) - shear * rtn * shear.transpose()).abs().max() < 1.0e-6);
+
+        let columns = CovarianceColumns { form: CovarianceForm::Cholesky, rtn: false };
+        assert_eq!(columns.columns().len(), 21);
+        assert_eq!(columns.columns()[1], "chol_y_x");
+        assert!(columns.values(&state).iter().all(|value| value.is_nan()));
+    }
+}
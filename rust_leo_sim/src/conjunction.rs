@@ -0,0 +1,305 @@
+use std::{collections::HashMap, fs::{self, File}, io::{BufWriter, Write}, path::Path};
+use rayon::prelude::*;
+use satkit::{orbitprop::SatState, sgp4::{sgp4, SGP4Error}, types::Vector3, Duration, Instant, TLE};
+use anyhow::{bail, Result};
+use crate::elements::mean_elements;
+use crate::numerical_integration::sgp4_gcrf;
+use crate::satellite::{OrbitalInstance, SatelliteRecord};
+
+const MAX_RELATIVE_SPEED: f64 = 16_000.0; //m/s, upper bound for two objects in LEO (head-on)
+const RADIAL_PAD: f64 = 25_000.0; //m, osculating radius can stray this far outside the mean perigee/apogee shell
+const TCA_TOLERANCE: f64 = 1.0e-3; //s
+
+pub(crate) struct ScreeningOptions {
+    pub(crate) start: Instant,
+    pub(crate) stop: Instant,
+    pub(crate) threshold: f64, //m, largest miss distance to report
+    pub(crate) step: f64, //s, coarse sampling step for the spatial grid
+}
+
+pub(crate) struct Conjunction {
+    pub(crate) primary: String,
+    pub(crate) secondary: String,
+    pub(crate) tca: Instant,
+    pub(crate) miss_distance: f64, //m
+    pub(crate) relative_speed: f64, //m/s
+    pub(crate) primary_state: SatState, //GCRF at TCA
+    pub(crate) secondary_state: SatState,
+    pub(crate) probability: Option<f64>, //filled in by collision::assess when covariances are available
+}
+
+//a catalog object reduced to what screening needs
+struct ScreeningObject {
+    id: String,
+    tle: TLE,
+    perigee: f64, //m, radius
+    apogee: f64, //m, radius
+}
+
+pub(crate) fn screen(catalog: &HashMap<String, SatelliteRecord>, options: &ScreeningOptions) -> Result<Vec<Conjunction>> {
+    if options.stop <= options.start {
+        bail!("Screening window must end after it starts");
+    }
+
+    let mut objects: Vec<ScreeningObject> = catalog.iter()
+        .filter_map(|(id, record)| {
+            let tle = record.tle_at(&options.start)?;
+            let elements = mean_elements(&OrbitalInstance::from_tle(&tle));
+            Some(ScreeningObject {
+                id: id.clone(),
+                perigee: elements.perigee_radius(),
+                apogee: elements.apogee_radius(),
+                tle,
+            })
+        })
+        .collect();
+    objects.sort_by(|a, b| a.id.cmp(&b.id));
+    println!("Screening {} objects", objects.len());
+
+    //objects can move this far relative to each other between samples, so anything closer is a candidate
+    let cell_size = options.threshold + MAX_RELATIVE_SPEED * options.step / 2.0;
+
+    let steps = ((options.stop - options.start).as_seconds() / options.step).ceil() as usize;
+    let mut candidates: HashMap<(usize, usize), Vec<usize>> = HashMap::new(); //pair of object indices to the steps they were close at
+    for k in 0..=steps {
+        let time = options.start + Duration::from_seconds((k as f64 * options.step).min((options.stop - options.start).as_seconds()));
+        let positions: Vec<Option<Vector3>> = objects.par_iter_mut()
+            .map(|object| position_teme(&mut object.tle, &time))
+            .collect();
+        for pair in grid_candidates(&positions, &objects, cell_size, options.threshold) {
+            candidates.entry(pair).or_default().push(k);
+        }
+    }
+    println!("Refining {} candidate pairs", candidates.len());
+
+    let mut conjunctions: Vec<Conjunction> = candidates.into_par_iter()
+        .flat_map_iter(|((i, j), steps_close)| {
+            let mut tle_i = objects[i].tle.clone();
+            let mut tle_j = objects[j].tle.clone();
+            split_into_encounters(steps_close).into_iter()
+                .filter_map(|(first, last)| {
+                    let from = options.start + Duration::from_seconds((first as f64 - 1.0) * options.step);
+                    let to = options.start + Duration::from_seconds((last as f64 + 1.0) * options.step);
+                    let from = if from < options.start { options.start } else { from };
+                    let to = if to > options.stop { options.stop } else { to };
+                    refine(&objects[i].id, &objects[j].id, &mut tle_i, &mut tle_j, from, to)
+                })
+                .filter(|conjunction| conjunction.miss_distance <= options.threshold)
+                .collect::<Vec<Conjunction>>()
+        })
+        .collect();
+    conjunctions.sort_by(|a, b| a.tca.partial_cmp(&b.tca).unwrap());
+    Ok(conjunctions)
+}
+
+//TEME position in meters, None when SGP4 fails (e.g. the object has decayed)
+pub(crate) fn position_teme(tle: &mut TLE, time: &Instant) -> Option<Vector3> {
+    let (r, _v, errs) = sgp4(tle, &[*time]);
+    match errs.first() {
+        Some(SGP4Error::SGP4Success) => Some(Vector3::new(r[(0, 0)], r[(1, 0)], r[(2, 0)])),
+        _ => None,
+    }
+}
+
+//the radial shells two objects sweep out have to come within the threshold for them to ever meet
+fn could_approach(a: &ScreeningObject, b: &ScreeningObject, threshold: f64) -> bool {
+    let gap = a.perigee.max(b.perigee) - a.apogee.min(b.apogee);
+    gap <= threshold + RADIAL_PAD
+}
+
+//pairs in the same or neighbouring grid cells that are within one cell size of each other
+fn grid_candidates(positions: &[Option<Vector3>], objects: &[ScreeningObject], cell_size: f64, threshold: f64) -> Vec<(usize, usize)> {
+    let cell_of = |p: &Vector3| -> (i64, i64, i64) {
+        ((p[0] / cell_size).floor() as i64, (p[1] / cell_size).floor() as i64, (p[2] / cell_size).floor() as i64)
+    };
+
+    let mut grid: HashMap<(i64, i64, i64), Vec<usize>> = HashMap::new();
+    for (i, position) in positions.iter().enumerate() {
+        if let Some(p) = position {
+            grid.entry(cell_of(p)).or_default().push(i);
+        }
+    }
+
+    let mut pairs: Vec<(usize, usize)> = Vec::new();
+    for (i, position) in positions.iter().enumerate() {
+        let p = match position {
+            Some(p) => p,
+            None => continue,
+        };
+        let (cx, cy, cz) = cell_of(p);
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    if let Some(members) = grid.get(&(cx + dx, cy + dy, cz + dz)) {
+                        for &j in members.iter().filter(|&&j| j > i) {
+                            let q = positions[j].as_ref().unwrap();
+                            if (p - q).norm() <= cell_size && could_approach(&objects[i], &objects[j], threshold) {
+                                pairs.push((i, j));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    pairs
+}
+
+//groups runs of consecutive step indices, each run being one close approach
+fn split_into_encounters(mut steps: Vec<usize>) -> Vec<(usize, usize)> {
+    steps.sort_unstable();
+    let mut encounters: Vec<(usize, usize)> = Vec::new();
+    for step in steps {
+        match encounters.last_mut() {
+            Some((_, last)) if step == *last + 1 => *last = step,
+            _ => encounters.push((step, step)),
+        }
+    }
+    encounters
+}
+
+//closest approach within [from, to] refined to GCRF states at TCA
+fn refine(primary: &str, secondary: &str, tle_a: &mut TLE, tle_b: &mut TLE, from: Instant, to: Instant) -> Option<Conjunction> {
+    let tca = closest_approach(tle_a, tle_b, from, to)?;
+    let primary_state = sgp4_gcrf(tle_a, &[tca]).remove(0);
+    let secondary_state = sgp4_gcrf(tle_b, &[tca]).remove(0);
+    Some(Conjunction {
+        primary: primary.to_string(),
+        secondary: secondary.to_string(),
+        tca,
+        miss_distance: (primary_state.pos_gcrf() - secondary_state.pos_gcrf()).norm(),
+        relative_speed: (primary_state.vel_gcrf() - secondary_state.vel_gcrf()).norm(),
+        primary_state,
+        secondary_state,
+        probability: None,
+    })
+}
+
+//golden section search for the time of closest approach within [from, to], None when SGP4 fails for either object
+fn closest_approach(tle_a: &mut TLE, tle_b: &mut TLE, from: Instant, to: Instant) -> Option<Instant> {
+    let mut distance = |t: f64| -> f64 {
+        let time = from + Duration::from_seconds(t);
+        match (position_teme(tle_a, &time), position_teme(tle_b, &time)) {
+            (Some(a), Some(b)) => (a - b).norm(),
+            _ => f64::INFINITY,
+        }
+    };
+
+    let ratio = (5.0_f64.sqrt() - 1.0) / 2.0;
+    let (mut lo, mut hi) = (0.0, (to - from).as_seconds());
+    let mut x1 = hi - ratio * (hi - lo);
+    let mut x2 = lo + ratio * (hi - lo);
+    let (mut f1, mut f2) = (distance(x1), distance(x2));
+    while hi - lo > TCA_TOLERANCE {
+        if f1 < f2 {
+            hi = x2;
+            x2 = x1;
+            f2 = f1;
+            x1 = hi - ratio * (hi - lo);
+            f1 = distance(x1);
+        } else {
+            lo = x1;
+            x1 = x2;
+            f1 = f2;
+            x2 = lo + ratio * (hi - lo);
+            f2 = distance(x2);
+        }
+    }
+    if !f1.is_finite() || !f2.is_finite() {
+        return None;
+    }
+    Some(from + Duration::from_seconds((lo + hi) / 2.0))
+}
+
+pub(crate) fn write_conjunctions(conjunctions: &[Conjunction], output_dir: &str) -> Result<()> {
+    fs::create_dir_all(output_dir)?;
+    let file = File::create(Path::new(output_dir).join("conjunctions.csv"))?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "primary,secondary,tca,miss_distance_m,relative_speed_mps,pc,x1,y1,z1,vx1,vy1,vz1,x2,y2,z2,vx2,vy2,vz2")?;
+    for conjunction in conjunctions {
+        let probability = conjunction.probability.map(|pc| pc.to_string()).unwrap_or_default();
+        writeln!(writer, "{},{},{},{},{},{},{},{}",
+            conjunction.primary, conjunction.secondary, conjunction.tca.as_unixtime(),
+            conjunction.miss_distance, conjunction.relative_speed, probability,
+            format_state(&conjunction.primary_state), format_state(&conjunction.secondary_state))?;
+    }
+    writer.flush()?;
+    println!("Found {} conjunctions", conjunctions.len());
+    Ok(())
+}
+
+//GCRF position and velocity as six comma separated values
+fn format_state(state: &SatState) -> String {
+    state.pv.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE1: &str = "1 25544U 98067A   08264.51782528 -.00002182  00000-0 -11606-4 0  2927";
+    const LINE2: &str = "2 25544  51.6416 247.4627 0006703 130.5360 325.0288 15.72125391563537";
+
+    fn object(id: &str, perigee_km: f64, apogee_km: f64) -> ScreeningObject {
+        ScreeningObject { id: id.to_string(), tle: TLE::new(), perigee: perigee_km * 1000.0, apogee: apogee_km * 1000.0 }
+    }
+
+    #[test]
+    fn shells_have_to_overlap_within_the_threshold() {
+        let low = object("low", 6_778.0, 6_800.0);
+        assert!(could_approach(&low, &object("crossing", 6_790.0, 7_200.0), 5_000.0));
+        assert!(could_approach(&low, &object("just above", 6_820.0, 6_830.0), 5_000.0)); //20 km gap, inside threshold and pad
+        assert!(!could_approach(&low, &object("far above", 6_900.0, 6_950.0), 5_000.0));
+        assert!(!could_approach(&object("far above", 6_900.0, 6_950.0), &low, 5_000.0));
+    }
+
+    #[test]
+    fn grid_finds_close_pairs_across_cell_boundaries() {
+        let objects: Vec<ScreeningObject> = ["a", "b", "c", "d", "e"].iter().map(|id| object(id, 6_700.0, 6_900.0)).collect();
+        let cell_size = 10_000.0;
+        let positions = vec![
+            Some(Vector3::new(6_799_999.0, 0.0, 0.0)),
+            Some(Vector3::new(6_800_001.0, 5.0, 0.0)), //a neighbouring cell, 2 m from a
+            Some(Vector3::new(-6_800_000.0, 0.0, 0.0)), //the other side of the earth
+            None, //SGP4 failed
+            Some(Vector3::new(6_800_000.0, 9_000.0, 0.0)), //within a cell size of a and b
+        ];
+        let mut pairs = grid_candidates(&positions, &objects, cell_size, 5_000.0);
+        pairs.sort();
+        assert_eq!(pairs, vec![(0, 1), (0, 4), (1, 4)]);
+
+        let mut objects = objects;
+        objects[1] = object("b", 7_500.0, 7_600.0); //its shell never reaches a's
+        let mut pairs = grid_candidates(&positions, &objects, cell_size, 5_000.0);
+        pairs.sort();
+        assert_eq!(pairs, vec![(0, 4)]);
+    }
+
+    #[test]
+    fn consecutive_steps_are_one_encounter() {
+        assert_eq!(split_into_encounters(vec![9, 2, 1, 5, 3, 8]), vec![(1, 3), (5, 5), (8, 9)]);
+        assert!(split_into_encounters(Vec::new()).is_empty());
+    }
+
+    //two copies of the ISS on planes half a degree apart in RAAN meet where the planes cross
+    #[test]
+    fn golden_section_matches_a_brute_force_minimum() {
+        let tle_a = TLE::load_2line(LINE1, LINE2).unwrap();
+        let mut tle_b = tle_a.clone();
+        tle_b.raan += 0.5;
+        let from = tle_a.epoch - Duration::from_seconds(600.0); //the ISS is just past the northernmost point at epoch
+        let to = from + Duration::from_seconds(1_200.0);
+
+        let distance = |time: &Instant| (position_teme(&mut tle_a.clone(), time).unwrap() - position_teme(&mut tle_b.clone(), time).unwrap()).norm();
+        let (brute_offset, brute_distance) = (0..=120_000)
+            .map(|k| k as f64 * 0.01)
+            .map(|t| (t, distance(&(from + Duration::from_seconds(t)))))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        assert!(brute_offset > 1.0 && brute_offset < 1_199.0, "minimum has to be inside the window, at {brute_offset}");
+
+        let tca = closest_approach(&mut tle_a.clone(), &mut tle_b.clone(), from, to).unwrap();
+        assert!(((tca - from).as_seconds() - brute_offset).abs() < 0.05, "{} against {}", (tca - from).as_seconds(), brute_offset);
+        assert!(distance(&tca) <= brute_distance + 1.0e-3);
+    }
+}
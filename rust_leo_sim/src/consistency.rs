@@ -0,0 +1,339 @@
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::{BufRead, BufReader, BufWriter, Write}, path::Path};
+use rayon::prelude::*;
+use satkit::{orbitprop::{PropSettings, SatState}, types::Matrix6, Instant, TLE};
+use anyhow::{anyhow, Result};
+use crate::analysis::median;
+use crate::frames::{rtn_error, RtnError};
+use crate::maneuver::ManeuverHandling;
+use crate::numerical_integration::{GapLimits, integrate_to, integration_settings, make_sat_state, maneuver_gaps, plan_segments, sgp4_gcrf};
+
+pub(crate) const MAD_TO_SIGMA: f64 = 1.4826; //scales a median absolute deviation to a gaussian standard deviation
+const MIN_PAIRS_FOR_FLAGGING: usize = 5;
+const MIN_OUTLIER_EXCESS: f64 = 100.0; //m above the median jump, so a MAD of zero (identical jumps) doesn't flag everything above it
+
+pub(crate) struct ConsistencyOptions {
+    pub(crate) integrate: bool, //also propagate each pair with the numerical integrator
+    pub(crate) max_gap_days: f64, //pairs further apart than this are not compared
+    pub(crate) flag_sigma: f64, //robust sigmas a jump has to exceed to count as an outlier
+    pub(crate) maneuvers: ManeuverHandling, //anything but ignore leaves pairs across a maneuver out, their jump is the burn
+    pub(crate) maneuver_sigma: f64,
+    pub(crate) gaps: GapLimits, //pairs are only integrated where the integration run would integrate all the way across
+}
+
+//jump between TLE i propagated to the epoch of TLE i+1 and TLE i+1 itself, in the RTN frame of TLE i+1
+pub(crate) struct ConsistencyPair {
+    pub(crate) index: usize,
+    pub(crate) epoch_from: Instant,
+    pub(crate) epoch_to: Instant,
+    pub(crate) sgp4: RtnError,
+    pub(crate) integrated: Option<RtnError>,
+    pub(crate) outlier: bool,
+}
+
+pub(crate) struct SatelliteConsistency {
+    pub(crate) id: String,
+    pub(crate) pairs: Vec<ConsistencyPair>,
+    pub(crate) flagged_epochs: Vec<Instant>, //element sets inconsistent with both of their neighbours
+    pub(crate) covariance: Option<Matrix6>, //sample covariance of the RTN jumps of non-outlier pairs
+}
+
+impl SatelliteConsistency {
+    fn inliers(&self) -> impl Iterator<Item = &ConsistencyPair> {
+        self.pairs.iter().filter(|pair| !pair.outlier)
+    }
+
+    //RMS of each RTN position and velocity component over the non-outlier pairs
+    pub(crate) fn accuracy(&self) -> [f64; 6] {
+        let mut sum_sq = [0.0; 6];
+        let mut count = 0;
+        for pair in self.inliers() {
+            let v = jump_vector(&pair.sgp4);
+            for i in 0..6 {
+                sum_sq[i] += v[i].powi(2);
+            }
+            count += 1;
+        }
+        if count > 0 {
+            sum_sq.iter_mut().for_each(|s| *s = (*s / count as f64).sqrt());
+        }
+        sum_sq
+    }
+
+    fn median_gap_hours(&self) -> f64 {
+        median(self.pairs.iter().map(|pair| (pair.epoch_to - pair.epoch_from).as_hours()).collect())
+    }
+}
+
+pub(crate) fn check_consistency(mut map: HashMap<String, Vec<TLE>>, options: &ConsistencyOptions, output_dir: &str) -> Result<()> {
+    fs::create_dir_all(output_dir)?;
+    for tles in map.values_mut() {
+        tles.sort_by(|a, b| a.epoch.partial_cmp(&b.epoch).unwrap());
+    }
+
+    let settings = integration_settings();
+
+    println!("Starting TLE consistency check");
+    let time = std::time::Instant::now();
+    let results: Vec<SatelliteConsistency> = map.into_par_iter()
+        .map(|(id, tles)| check_satellite(id, tles, &settings, options))
+        .collect::<Result<Vec<SatelliteConsistency>>>()?;
+    println!("Checked {} satellites in {}", results.len(), time.elapsed().as_secs_f64());
+
+    write_pairs(&results, output_dir)?;
+    write_summary(&results, output_dir)?;
+    write_covariances(&results, output_dir)?;
+    Ok(())
+}
+
+fn check_satellite(id: String, mut tles: Vec<TLE>, settings: &PropSettings, options: &ConsistencyOptions) -> Result<SatelliteConsistency> {
+    let mut pairs: Vec<ConsistencyPair> = Vec::new();
+
+    let epochs: Vec<Instant> = tles.iter().map(|tle| tle.epoch).collect();
+    let maneuver_gaps: HashSet<usize> = maneuver_gaps(&tles, options.maneuvers, options.maneuver_sigma);
+    let (segments, _) = plan_segments(&epochs, &maneuver_gaps, options.maneuvers, &options.gaps);
+    let integrated_gaps: HashSet<usize> = segments.iter()
+        .filter(|segment| segment.end_epoch_index == segment.tle_index + 1 && segment.stop == epochs[segment.end_epoch_index])
+        .map(|segment| segment.tle_index)
+        .collect();
+
+    for i in 0..tles.len().saturating_sub(1) {
+        let epoch_from = tles[i].epoch;
+        let epoch_to = tles[i + 1].epoch;
+        let gap_days = (epoch_to - epoch_from).as_days();
+        if gap_days <= 0.0 || gap_days > options.max_gap_days { //duplicates and stale pairs say nothing about accuracy
+            continue;
+        }
+        if maneuver_gaps.contains(&i) {
+            continue;
+        }
+
+        let target: SatState = sgp4_gcrf(&mut tles[i + 1], &[epoch_to]).remove(0);
+        let start: SatState = sgp4_gcrf(&mut tles[i], &[epoch_from]).remove(0);
+        let propagated: SatState = sgp4_gcrf(&mut tles[i], &[epoch_to]).remove(0);
+
+        let integrated = if options.integrate && integrated_gaps.contains(&i) {
+            let result = integrate_to(&start, &epoch_to, settings, None)?;
+            Some(rtn_error(&target, &make_sat_state(result.time_end, result.state_end)))
+        } else {
+            None
+        };
+
+        pairs.push(ConsistencyPair {
+            index: i,
+            epoch_from,
+            epoch_to,
+            sgp4: rtn_error(&target, &propagated),
+            integrated,
+            outlier: false,
+        });
+    }
+
+    let flagged_epochs = flag_outliers(&mut pairs, &tles, options.flag_sigma);
+    let mut consistency = SatelliteConsistency { id, pairs, flagged_epochs, covariance: None };
+    consistency.covariance = sample_covariance(consistency.inliers().map(|pair| jump_vector(&pair.sgp4)).collect());
+    Ok(consistency)
+}
+
+//marks pairs whose position jump is far outside the object's typical jump, using median/MAD so the outliers don't hide themselves
+//an element set is flagged when the pairs on both sides of it are outliers (or it is the first or last one and its only pair is)
+fn flag_outliers(pairs: &mut [ConsistencyPair], tles: &[TLE], flag_sigma: f64) -> Vec<Instant> {
+    if pairs.len() < MIN_PAIRS_FOR_FLAGGING {
+        return Vec::new();
+    }
+
+    let magnitudes: Vec<f64> = pairs.iter().map(|pair| pair.sgp4.position.norm()).collect();
+    let center = median(magnitudes.clone());
+    let mad = median(magnitudes.iter().map(|m| (m - center).abs()).collect());
+    let threshold = (flag_sigma * MAD_TO_SIGMA * mad).max(MIN_OUTLIER_EXCESS);
+
+    for (pair, magnitude) in pairs.iter_mut().zip(magnitudes.iter()) {
+        pair.outlier = (magnitude - center) > threshold;
+    }
+
+    let outlier_into: HashMap<usize, bool> = pairs.iter().map(|pair| (pair.index + 1, pair.outlier)).collect();
+    let outlier_from: HashMap<usize, bool> = pairs.iter().map(|pair| (pair.index, pair.outlier)).collect();
+    (0..tles.len())
+        .filter(|j| {
+            let before = outlier_into.get(j).copied().unwrap_or(*j == 0);
+            let after = outlier_from.get(j).copied().unwrap_or(*j == tles.len() - 1);
+            before && after
+        })
+        .map(|j| tles[j].epoch)
+        .collect()
+}
+
+fn jump_vector(error: &RtnError) -> [f64; 6] {
+    [error.position[0], error.position[1], error.position[2], error.velocity[0], error.velocity[1], error.velocity[2]]
+}
+
+fn sample_covariance(samples: Vec<[f64; 6]>) -> Option<Matrix6> {
+    if samples.len() < 2 {
+        return None;
+    }
+    let n = samples.len() as f64;
+    let mut mean = [0.0; 6];
+    for sample in samples.iter() {
+        for i in 0..6 {
+            mean[i] += sample[i] / n;
+        }
+    }
+    let mut covariance = Matrix6::zeros();
+    for sample in samples.iter() {
+        for i in 0..6 {
+            for j in 0..6 {
+                covariance[(i, j)] += (sample[i] - mean[i]) * (sample[j] - mean[j]) / (n - 1.0);
+            }
+        }
+    }
+    Some(covariance)
+}
+
+fn write_pairs(results: &[SatelliteConsistency], output_dir: &str) -> Result<()> {
+    let file = File::create(Path::new(output_dir).join("consistency_pairs.csv"))?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "id,index,epoch_from,epoch_to,gap_hours,dr_r,dr_t,dr_n,dv_r,dv_t,dv_n,int_dr_r,int_dr_t,int_dr_n,int_dv_r,int_dv_t,int_dv_n,outlier")?;
+    for result in results {
+        for pair in result.pairs.iter() {
+            let s = jump_vector(&pair.sgp4);
+            let integrated: String = match &pair.integrated {
+                Some(error) => jump_vector(error).iter().map(|v| v.to_string()).collect::<Vec<String>>().join(","),
+                None => ",,,,,".to_string(),
+            };
+            writeln!(writer, "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                result.id, pair.index, pair.epoch_from.as_unixtime(), pair.epoch_to.as_unixtime(),
+                (pair.epoch_to - pair.epoch_from).as_hours(),
+                s[0], s[1], s[2], s[3], s[4], s[5], integrated, pair.outlier)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_summary(results: &[SatelliteConsistency], output_dir: &str) -> Result<()> {
+    let file = File::create(Path::new(output_dir).join("consistency_satellites.csv"))?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "id,pairs,outliers,flagged_element_sets,median_gap_hours,rms_r,rms_t,rms_n,rms_vr,rms_vt,rms_vn")?;
+
+    let flagged_file = File::create(Path::new(output_dir).join("consistency_flagged.csv"))?;
+    let mut flagged_writer = BufWriter::new(flagged_file);
+    writeln!(flagged_writer, "id,epoch")?;
+
+    for result in results {
+        let accuracy = result.accuracy();
+        let outliers = result.pairs.iter().filter(|pair| pair.outlier).count();
+        writeln!(writer, "{},{},{},{},{},{},{},{},{},{},{}",
+            result.id, result.pairs.len(), outliers, result.flagged_epochs.len(), result.median_gap_hours(),
+            accuracy[0], accuracy[1], accuracy[2], accuracy[3], accuracy[4], accuracy[5])?;
+        for epoch in result.flagged_epochs.iter() {
+            writeln!(flagged_writer, "{},{}", result.id, epoch.as_unixtime())?;
+        }
+    }
+    writer.flush()?;
+    flagged_writer.flush()?;
+    Ok(())
+}
+
+//one row per satellite: the id followed by the 36 entries of its RTN covariance in row-major order (m, m/s)
+fn write_covariances(results: &[SatelliteConsistency], output_dir: &str) -> Result<()> {
+    let file = File::create(Path::new(output_dir).join("covariance.csv"))?;
+    let mut writer = BufWriter::new(file);
+    for result in results {
+        if let Some(covariance) = &result.covariance {
+            let entries: Vec<String> = covariance.transpose().iter().map(|v| v.to_string()).collect(); //nalgebra iterates column-major
+            writeln!(writer, "{},{}", result.id, entries.join(","))?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+//reads the file write_covariances produces back into RTN covariances keyed by id
+pub(crate) fn read_covariances(path: &str) -> Result<HashMap<String, Matrix6>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut covariances: HashMap<String, Matrix6> = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let mut fields = line.trim().split(',');
+        let id = match fields.next() {
+            Some(id) if !id.is_empty() => id.to_string(),
+            _ => continue,
+        };
+        let entries: Vec<f64> = fields
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| anyhow!("Bad covariance for {id} in {path}: {e}"))?;
+        if entries.len() != 36 {
+            return Err(anyhow!("Expected 36 covariance entries for {id} in {path}, found {}", entries.len()));
+        }
+        covariances.insert(id, Matrix6::from_row_slice(&entries));
+    }
+    Ok(covariances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use satkit::{types::Vector3, Duration};
+
+    //TLEs a day apart and the pairs between them with the given position jumps along track, in m
+    fn with_jumps(jumps: &[f64]) -> (Vec<ConsistencyPair>, Vec<TLE>) {
+        let start = Instant::from_unixtime(1.7e9);
+        let tles: Vec<TLE> = (0..=jumps.len()).map(|i| {
+            let mut tle = TLE::new();
+            tle.epoch = start + Duration::from_days(i as f64);
+            tle
+        }).collect();
+        let pairs = jumps.iter().enumerate().map(|(i, jump)| ConsistencyPair {
+            index: i,
+            epoch_from: tles[i].epoch,
+            epoch_to: tles[i + 1].epoch,
+            sgp4: RtnError { position: Vector3::new(0.0, *jump, 0.0), velocity: Vector3::zeros() },
+            integrated: None,
+            outlier: false,
+        }).collect();
+        (pairs, tles)
+    }
+
+    //a bad element set makes the pairs on both sides of it jump
+    #[test]
+    fn flags_the_element_set_between_two_outlying_jumps() {
+        let (mut pairs, tles) = with_jumps(&[900.0, 1100.0, 1000.0, 9000.0, 8500.0, 950.0, 1050.0]);
+        let flagged = flag_outliers(&mut pairs, &tles, 3.0);
+        let outliers: Vec<usize> = pairs.iter().filter(|pair| pair.outlier).map(|pair| pair.index).collect();
+        assert_eq!(outliers, vec![3, 4]);
+        assert_eq!(flagged, vec![tles[4].epoch]);
+    }
+
+    //the first and last element sets only have one pair to show it
+    #[test]
+    fn flags_an_end_element_set_from_its_only_jump() {
+        let (mut pairs, tles) = with_jumps(&[9000.0, 1000.0, 1100.0, 950.0, 1050.0, 900.0, 1000.0]);
+        assert_eq!(flag_outliers(&mut pairs, &tles, 3.0), vec![tles[0].epoch]);
+
+        let (mut pairs, tles) = with_jumps(&[1000.0, 1100.0, 950.0, 1050.0, 900.0, 1000.0, 9000.0]);
+        assert_eq!(flag_outliers(&mut pairs, &tles, 3.0), vec![tles[7].epoch]);
+    }
+
+    #[test]
+    fn identical_jumps_flag_nothing() {
+        let (mut pairs, tles) = with_jumps(&[1000.0, 1000.0, 1000.0, 1000.0, 1000.0, 1000.0, 1000.5]);
+        assert!(flag_outliers(&mut pairs, &tles, 3.0).is_empty());
+        assert!(pairs.iter().all(|pair| !pair.outlier));
+
+        let (mut few, tles) = with_jumps(&[1000.0, 50000.0, 1000.0]); //too few pairs to say what is typical
+        assert!(flag_outliers(&mut few, &tles, 3.0).is_empty());
+    }
+
+    #[test]
+    fn sample_covariance_is_unbiased() {
+        let samples = vec![[1.0, 2.0, 0.0, 0.0, 0.0, 0.0], [3.0, 2.0, 0.0, 0.0, 0.0, 1.0], [5.0, 8.0, 0.0, 0.0, 0.0, 2.0]];
+        let covariance = sample_covariance(samples).unwrap();
+        assert!((covariance[(0, 0)] - 4.0).abs() < 1e-12); //mean 3, (4 + 0 + 4) / 2
+        assert!((covariance[(1, 1)] - 12.0).abs() < 1e-12); //mean 4, (4 + 4 + 16) / 2
+        assert!((covariance[(0, 1)] - 6.0).abs() < 1e-12); //(-2 * -2 + 0 + 2 * 4) / 2
+        assert!((covariance[(0, 5)] - 2.0).abs() < 1e-12);
+        assert_eq!(covariance, covariance.transpose());
+        assert_eq!(covariance[(2, 2)], 0.0);
+        assert!(sample_covariance(vec![[1.0; 6]]).is_none());
+    }
+}
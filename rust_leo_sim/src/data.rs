@@ -0,0 +1,252 @@
+use std::{collections::HashMap, fs::read_to_string, path::{Path, PathBuf}};
+use satkit::Instant;
+use anyhow::{anyhow, bail, Result};
+use crate::space_weather::{parse_date, SpaceWeather};
+
+//what a mode does with satkit, which decides the data files it needs
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum DataUse {
+    Frames, //TEME, GCRF and ITRF conversions, every mode that opens the data directory needs these
+    Integration, //numerical integration with gravity and the sun and moon
+    Drag, //the density model, without --space-weather
+}
+
+//what satkit reads from its data directory for this crate, each file it can't find it tries to download
+const REQUIRED_FILES: [(&str, DataUse, &str); 7] = [
+    ("EOP-All.csv", DataUse::Frames, "earth orientation parameters (UT1-UTC, polar motion) for TEME, GCRF and ITRF conversions"),
+    ("tab5.2a.txt", DataUse::Frames, "IERS 2010 precession-nutation series for X, GCRF <-> ITRF"),
+    ("tab5.2b.txt", DataUse::Frames, "IERS 2010 precession-nutation series for Y, GCRF <-> ITRF"),
+    ("tab5.2d.txt", DataUse::Frames, "IERS 2010 series for s, GCRF <-> ITRF"),
+    ("JGM3.gfc", DataUse::Integration, "gravity field for numerical integration"),
+    ("linux_p1550p2650.440", DataUse::Integration, "JPL DE440 ephemeris, sun and moon positions for numerical integration"),
+    ("SW-All.csv", DataUse::Drag, "F10.7 and Ap for the drag density model"),
+];
+const EOP_FILE: &str = "EOP-All.csv";
+const SPACE_WEATHER_FILE: &str = "SW-All.csv";
+
+//satkit's leap seconds are compiled in, the last one at the start of 2017 took TAI-UTC to 37 s
+const BUILT_IN_TAI_UTC: f64 = 37.0;
+
+//the days an EOP-All.csv covers, satkit interpolates between rows so epochs need a row on both sides
+pub(crate) struct EopCoverage {
+    first: Instant,
+    last_observed: Instant, //rows after this are IERS predictions, which change with every file update
+    last: Instant,
+    tai_utc: f64, //on the last row, s
+}
+
+impl EopCoverage {
+    pub(crate) fn load(path: &Path) -> Result<EopCoverage> {
+        let contents = read_to_string(path).map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))?;
+        let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+        let header: HashMap<String, usize> = lines.next().ok_or_else(|| anyhow!("{} is empty", path.display()))?
+            .split(',').enumerate().map(|(i, column)| (column.trim().to_ascii_uppercase(), i)).collect();
+        let column = |name: &str| header.get(name).copied().ok_or_else(|| anyhow!("{} has no {name} column, expected a CelesTrak EOP-All.csv", path.display()));
+        let (date, tai_utc, data_type) = (column("DATE")?, column("DAT")?, column("DATA_TYPE")?);
+
+        let mut rows: Vec<(Instant, f64, bool)> = Vec::new(); //date, TAI-UTC, observed
+        for line in lines {
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            let field = |i: usize| fields.get(i).copied().unwrap_or_default();
+            rows.push((
+                parse_date(field(date)).map_err(|e| anyhow!("{}: {e}", path.display()))?,
+                field(tai_utc).parse().map_err(|_| anyhow!("{}: invalid DAT {}", path.display(), field(tai_utc)))?,
+                field(data_type) == "O",
+            ));
+        }
+        if rows.len() < 2 {
+            bail!("{} holds fewer than two days", path.display());
+        }
+        let (first, last) = (rows[0].0, rows[rows.len() - 1].0);
+        Ok(EopCoverage {
+            first,
+            last_observed: rows.iter().rev().find(|(_, _, observed)| *observed).map(|(date, _, _)| *date).unwrap_or(first),
+            last,
+            tai_utc: rows[rows.len() - 1].1,
+        })
+    }
+
+    //None when the leap seconds agree, otherwise what is wrong
+    fn leap_second_mismatch(&self) -> Option<String> {
+        (self.tai_utc != BUILT_IN_TAI_UTC).then(|| format!(
+            "TAI-UTC is {} s in {} but satkit's built in leap seconds stop at {} s (2017-01-01), UTC times after the newer leap second are off",
+            self.tai_utc, EOP_FILE, BUILT_IN_TAI_UTC))
+    }
+}
+
+//satkit's data directory, checked so that nothing is downloaded and every run reads the same files
+pub(crate) struct DataDir {
+    path: PathBuf,
+    eop: EopCoverage,
+    strict: bool, //predicted EOP are an error rather than a warning
+}
+
+impl DataDir {
+    //points satkit at the directory when given (otherwise satkit's own search through SATKIT_DATA, ~/.satkit-data, ...),
+    //fails when a file satkit would download for one of the uses is missing
+    pub(crate) fn open(dir: Option<&str>, strict: bool, uses: &[DataUse]) -> Result<DataDir> {
+        let path = configure(dir)?;
+        let missing = missing_files(&path, uses);
+        if !missing.is_empty() {
+            bail!("{} is missing {}, satkit would try to download them. Copy them in from a machine with network access, `data verify` lists what each is for",
+                path.display(), missing.join(", "));
+        }
+        let eop = EopCoverage::load(&path.join(EOP_FILE))?;
+        println!("satkit data from {}, EOP {} to {} (observed until {})", path.display(), eop.first, eop.last, eop.last_observed);
+        if let Some(mismatch) = eop.leap_second_mismatch() {
+            println!("Warning: {mismatch}");
+        }
+        Ok(DataDir { path, eop, strict })
+    }
+
+    //epochs outside the EOP are an error, satkit panics on the ITRF rotation there and quietly uses UT1 = UTC for TEME
+    pub(crate) fn check_epochs(&self, first: &Instant, last: &Instant) -> Result<()> {
+        let eop = &self.eop;
+        if *first < eop.first || *last >= eop.last {
+            bail!("Epochs {} to {} fall outside the EOP in {} ({} to {})", first, last, self.path.join(EOP_FILE).display(), eop.first, eop.last);
+        }
+        if *last > eop.last_observed {
+            let message = format!("Epochs after {} use predicted EOP, which change with every {} update", eop.last_observed, EOP_FILE);
+            if self.strict {
+                bail!("{message} (--eop-strict)");
+            }
+            println!("Warning: {message}");
+        }
+        Ok(())
+    }
+}
+
+//required files for the uses (the frame ones always) that aren't in the directory
+fn missing_files(path: &Path, uses: &[DataUse]) -> Vec<&'static str> {
+    REQUIRED_FILES.iter()
+        .filter(|(_, data_use, _)| *data_use == DataUse::Frames || uses.contains(data_use))
+        .map(|(name, _, _)| *name)
+        .filter(|name| !path.join(name).is_file())
+        .collect()
+}
+
+fn configure(dir: Option<&str>) -> Result<PathBuf> {
+    if let Some(dir) = dir {
+        satkit::utils::set_datadir(Path::new(dir)).map_err(|e| anyhow!("Can't use {dir} as the satkit data directory: {e}"))?;
+    }
+    satkit::utils::datadir().map_err(|e| anyhow!("No satkit data directory: {e}"))
+}
+
+//the `data verify` report: every file, the EOP and space weather ranges, the leap seconds and optionally a range of epochs
+pub(crate) fn verify(dir: Option<&str>, epochs: Option<(Instant, Instant)>) -> Result<()> {
+    let path = configure(dir)?;
+    println!("Data directory {}", path.display());
+    let mut problems: Vec<String> = Vec::new();
+    for (name, _, purpose) in REQUIRED_FILES {
+        match path.join(name).metadata() {
+            Ok(metadata) if metadata.is_file() => println!("  {:<20} {:>12} bytes  {}", name, metadata.len(), purpose),
+            _ => {
+                println!("  {:<20} {:>12}        {}", name, "MISSING", purpose);
+                problems.push(format!("{name} is missing"));
+            }
+        }
+    }
+
+    if path.join(EOP_FILE).is_file() {
+        let eop = EopCoverage::load(&path.join(EOP_FILE))?;
+        println!("EOP {} to {}, observed until {}, TAI-UTC {} s", eop.first, eop.last, eop.last_observed, eop.tai_utc);
+        problems.extend(eop.leap_second_mismatch());
+        if let Some((first, last)) = epochs {
+            let data = DataDir { path: path.clone(), eop, strict: true };
+            match data.check_epochs(&first, &last) {
+                Ok(()) => println!("Epochs {} to {} are covered by observed EOP", first, last),
+                Err(e) => problems.push(e.to_string()),
+            }
+        }
+    }
+    if path.join(SPACE_WEATHER_FILE).is_file() {
+        let space_weather = SpaceWeather::load(&path.join(SPACE_WEATHER_FILE).to_string_lossy())?;
+        if let Some((first, last)) = epochs {
+            if let Err(e) = space_weather.check_range(&first, &last) {
+                problems.push(e.to_string());
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!("Data check failed:\n  {}", problems.join("\n  "));
+    }
+    println!("Data check passed");
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    //points satkit at a data directory the frame conversions can run on: the real one when it is complete, otherwise a
+    //stand in with constant EOP and IERS series without terms, so GCRF is off by precession-nutation but every conversion still inverts
+    pub(crate) fn frame_data() {
+        static FIXTURE: std::sync::Once = std::sync::Once::new();
+        FIXTURE.call_once(|| {
+            if DataDir::open(None, false, &[]).is_ok() {
+                return;
+            }
+            let dir = std::env::temp_dir().join("rust_leo_sim_frame_data");
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join(EOP_FILE), "DATE,MJD,X,Y,UT1-UTC,LOD,DPSI,DEPS,DX,DY,DAT,DATA_TYPE\n\
+                2020-01-01,58849,0.1,0.3,-0.1,0.5,0,0,0,0,37,O\n2030-01-01,62502,0.1,0.3,-0.1,0.5,0,0,0,0,37,P\n").unwrap();
+            for name in ["tab5.2a.txt", "tab5.2b.txt", "tab5.2d.txt"] {
+                std::fs::write(dir.join(name), format!("j = 0  Number of terms = 1\n1{}\n", " 0".repeat(16))).unwrap();
+            }
+            satkit::utils::set_datadir(&dir).unwrap();
+        });
+    }
+
+    //a few rows of a CelesTrak EOP-All.csv, observed until the 3rd and predicted after
+    fn eop_fixture(dir: &Path, tai_utc: f64) -> PathBuf {
+        std::fs::create_dir_all(dir).unwrap();
+        let path = dir.join(EOP_FILE);
+        let rows: Vec<String> = [("2024-01-01", "O"), ("2024-01-02", "O"), ("2024-01-03", "O"), ("2024-01-04", "P"), ("2024-01-05", "P")].iter()
+            .map(|(date, data_type)| format!("{date},60310,0.1,0.2,0.01,0.001,0,0,0,0,{tai_utc},{data_type}"))
+            .collect();
+        std::fs::write(&path, format!("DATE,MJD,X,Y,UT1-UTC,LOD,DPSI,DEPS,DX,DY,DAT,DATA_TYPE\n{}\n", rows.join("\n"))).unwrap();
+        path
+    }
+
+    #[test]
+    fn eop_coverage_splits_observed_and_predicted_rows() {
+        let dir = std::env::temp_dir().join(format!("eop_coverage_{}", std::process::id()));
+        let eop = EopCoverage::load(&eop_fixture(&dir, 37.0)).unwrap();
+        assert_eq!(eop.first, Instant::from_date(2024, 1, 1));
+        assert_eq!(eop.last_observed, Instant::from_date(2024, 1, 3));
+        assert_eq!(eop.last, Instant::from_date(2024, 1, 5));
+        assert!(eop.leap_second_mismatch().is_none());
+        assert!(EopCoverage::load(&eop_fixture(&dir, 38.0)).unwrap().leap_second_mismatch().is_some());
+
+        std::fs::write(dir.join(EOP_FILE), "DATE,MJD\n2024-01-01,60310\n").unwrap();
+        assert!(EopCoverage::load(&dir.join(EOP_FILE)).is_err()); //not an EOP-All.csv
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn epochs_have_to_fall_inside_the_eop() {
+        let dir = std::env::temp_dir().join(format!("eop_epochs_{}", std::process::id()));
+        let eop = EopCoverage::load(&eop_fixture(&dir, 37.0)).unwrap();
+        let data = DataDir { path: dir.clone(), eop, strict: false };
+        let day = |d: f64| Instant::from_date(2024, 1, 1) + satkit::Duration::from_days(d);
+        assert!(data.check_epochs(&day(0.5), &day(1.5)).is_ok());
+        assert!(data.check_epochs(&day(-0.5), &day(1.5)).is_err());
+        assert!(data.check_epochs(&day(0.5), &day(4.0)).is_err()); //satkit needs a row after the last epoch
+        assert!(data.check_epochs(&day(0.5), &day(3.5)).is_ok()); //predicted, only a warning
+
+        let strict = DataDir { strict: true, ..data };
+        assert!(strict.check_epochs(&day(0.5), &day(3.5)).is_err());
+        assert!(strict.check_epochs(&day(0.5), &day(2.0)).is_ok());
+
+        //only what the mode uses has to be there
+        assert!(missing_files(&dir, &[]).contains(&"tab5.2a.txt"));
+        for name in ["tab5.2a.txt", "tab5.2b.txt", "tab5.2d.txt"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        assert!(missing_files(&dir, &[DataUse::Frames]).is_empty());
+        assert_eq!(missing_files(&dir, &[DataUse::Integration]), vec!["JGM3.gfc", "linux_p1550p2650.440"]);
+        assert_eq!(missing_files(&dir, &[DataUse::Drag]), vec!["SW-All.csv"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use std::{collections::{BTreeMap, HashMap}, fs::{self, File}, io::{BufWriter, Write}, path::{Path, PathBuf}};
+use satkit::TLE;
+use anyhow::{bail, Result};
+use serde_json::json;
+use crate::analysis::{mean_altitude_km, median};
+use crate::satcat::{ObjectType, SatcatEntry};
+
+const ALTITUDE_BAND_KM: f64 = 100.0;
+const INCLINATION_BAND_DEG: f64 = 10.0;
+const SPLITS: [&str; 3] = ["train", "val", "test"];
+
+pub(crate) struct DatasetOptions {
+    pub(crate) seed: u64,
+    pub(crate) fractions: [f64; 3], //train, val, test
+    pub(crate) stratify: bool, //split each altitude/inclination/B* band separately so every split sees every kind of orbit
+    pub(crate) files: Option<String>, //directory with integration_<id>.txt.zst files, only satellites with a file are used
+    pub(crate) move_files: bool, //moves the files into train/val/test folders next to them
+    pub(crate) satcat: Option<HashMap<String, SatcatEntry>>, //object types are recorded and, when stratifying, split separately
+}
+
+struct DatasetEntry {
+    id: String,
+    hash: u64,
+    stratum: String,
+    object_type: Option<ObjectType>,
+    satcat: Option<SatcatEntry>,
+    altitude_km: f64,
+    inclination: f64,
+    bstar: f64,
+    file: Option<PathBuf>,
+    split: usize, //index into SPLITS
+}
+
+//splits satellites by a seeded hash of their catalog number, so the same seed always gives the same split
+//and every file of a satellite ends up in the same set
+pub(crate) fn build_dataset(map: HashMap<String, Vec<TLE>>, options: &DatasetOptions, output_dir: &str) -> Result<()> {
+    let fractions = split_fractions(&options.fractions)?;
+    fs::create_dir_all(output_dir)?;
+
+    let files: Option<HashMap<String, PathBuf>> = match &options.files {
+        Some(dir) => Some(integration_files(dir)?),
+        None => None,
+    };
+
+    let mut entries: Vec<DatasetEntry> = map.iter()
+        .filter(|(id, _)| files.as_ref().is_none_or(|files| files.contains_key(*id)))
+        .map(|(id, tles)| {
+            let altitude_km = median(tles.iter().map(mean_altitude_km).collect());
+            let inclination = median(tles.iter().map(|tle| tle.inclination).collect());
+            let bstar = median(tles.iter().map(|tle| tle.bstar).collect());
+            let object_type = options.satcat.as_ref().map(|satcat| satcat.get(id).map(|entry| entry.object_type).unwrap_or(ObjectType::Unknown));
+            DatasetEntry {
+                id: id.clone(),
+                hash: splitmix64(options.seed ^ id.parse::<u64>().unwrap_or_else(|_| fnv1a(id))),
+                stratum: if options.stratify { stratum(object_type, altitude_km, inclination, bstar) } else { "all".to_string() },
+                object_type,
+                satcat: options.satcat.as_ref().and_then(|satcat| satcat.get(id).cloned()),
+                altitude_km,
+                inclination,
+                bstar,
+                file: files.as_ref().and_then(|files| files.get(id).cloned()),
+                split: 0,
+            }
+        })
+        .collect();
+    if let Some(files) = &files {
+        let missing = files.keys().filter(|id| !map.contains_key(*id)).count();
+        if missing > 0 {
+            println!("{} files have no TLEs in the catalog and were left out", missing);
+        }
+    }
+
+    assign_splits(&mut entries, &fractions, options.stratify);
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+
+    if options.move_files {
+        move_into_splits(&mut entries, options.files.as_deref())?;
+    }
+    write_manifest(&entries, options, &fractions, output_dir)?;
+
+    for (split, name) in SPLITS.iter().enumerate() {
+        println!("{}: {} satellites", name, entries.iter().filter(|entry| entry.split == split).count());
+    }
+    Ok(())
+}
+
+//train, val and test fractions, three non-negative values adding up to one
+pub(crate) fn split_fractions(values: &[f64]) -> Result<[f64; 3]> {
+    if values.len() != 3 {
+        bail!("Split takes three fractions (train,val,test), got {}", values.len());
+    }
+    if values.iter().any(|f| f.is_nan() || *f < 0.0) {
+        bail!("Split fractions have to be non-negative, got {:?}", values);
+    }
+    let total: f64 = values.iter().sum();
+    if (total - 1.0).abs() > 1.0e-9 {
+        bail!("Split fractions have to add up to 1, got {}", total);
+    }
+    Ok([values[0], values[1], values[2]])
+}
+
+fn assign_splits(entries: &mut [DatasetEntry], fractions: &[f64; 3], stratify: bool) {
+    if stratify {
+        //within each stratum the satellites are ordered by hash and cut at the fractions, so small strata still split evenly
+        let mut strata: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for (i, entry) in entries.iter().enumerate() {
+            strata.entry(entry.stratum.clone()).or_default().push(i);
+        }
+        for members in strata.values_mut() {
+            members.sort_by_key(|i| (entries[*i].hash, entries[*i].id.clone()));
+            let n = members.len() as f64;
+            let train_end = (n * fractions[0]).round() as usize;
+            let val_end = (n * (fractions[0] + fractions[1])).round() as usize;
+            for (rank, i) in members.iter().enumerate() {
+                entries[*i].split = if rank < train_end { 0 } else if rank < val_end { 1 } else { 2 };
+            }
+        }
+    } else {
+        //the hash alone decides, so a satellite keeps its split when others are added to or removed from the catalog
+        for entry in entries.iter_mut() {
+            let u = entry.hash as f64 / u64::MAX as f64;
+            entry.split = if u < fractions[0] { 0 } else if u < fractions[0] + fractions[1] { 1 } else { 2 };
+        }
+    }
+}
+
+//integration_<id>.txt.zst files in a directory, keyed by id
+fn integration_files(dir: &str) -> Result<HashMap<String, PathBuf>> {
+    let mut files: HashMap<String, PathBuf> = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        if let Some(id) = name.strip_prefix("integration_").and_then(|rest| rest.strip_suffix(".txt.zst")) {
+            files.insert(id.to_string(), path.clone());
+        }
+    }
+    Ok(files)
+}
+
+fn move_into_splits(entries: &mut [DatasetEntry], files_dir: Option<&str>) -> Result<()> {
+    let dir = match files_dir {
+        Some(dir) => Path::new(dir),
+        None => bail!("--move needs --files"),
+    };
+    for name in SPLITS {
+        fs::create_dir_all(dir.join(name))?;
+    }
+    for entry in entries.iter_mut() {
+        if let Some(file) = &entry.file {
+            let destination = dir.join(SPLITS[entry.split]).join(file.file_name().unwrap());
+            fs::rename(file, &destination)?;
+            entry.file = Some(destination);
+        }
+    }
+    Ok(())
+}
+
+fn write_manifest(entries: &[DatasetEntry], options: &DatasetOptions, fractions: &[f64], output_dir: &str) -> Result<()> {
+    let counts: serde_json::Map<String, serde_json::Value> = SPLITS.iter().enumerate()
+        .map(|(split, name)| (name.to_string(), json!(entries.iter().filter(|entry| entry.split == split).count())))
+        .collect();
+    let satellites: Vec<serde_json::Value> = entries.iter()
+        .map(|entry| json!({
+            "id": entry.id,
+            "split": SPLITS[entry.split],
+            "stratum": entry.stratum,
+            "object_type": entry.object_type.map(|object_type| object_type.to_string()),
+            "name": entry.satcat.as_ref().map(|satcat| satcat.name.clone()),
+            "owner": entry.satcat.as_ref().map(|satcat| satcat.owner.clone()),
+            "launch_date": entry.satcat.as_ref().and_then(|satcat| satcat.launch_date).map(|date| date.as_iso8601()),
+            "decay_date": entry.satcat.as_ref().and_then(|satcat| satcat.decay_date).map(|date| date.as_iso8601()),
+            "rcs_size": entry.satcat.as_ref().and_then(|satcat| satcat.rcs_size).map(|rcs| rcs.to_string()),
+            "altitude_km": entry.altitude_km,
+            "inclination": entry.inclination,
+            "bstar": entry.bstar,
+            "file": entry.file.as_ref().map(|file| file.to_string_lossy().to_string()),
+        }))
+        .collect();
+    let manifest = json!({
+        "seed": options.seed,
+        "fractions": { "train": fractions[0], "val": fractions[1], "test": fractions[2] },
+        "stratified": options.stratify,
+        "hash": "splitmix64(seed ^ catalog_number)",
+        "counts": counts,
+        "satellites": satellites,
+    });
+
+    let file = File::create(Path::new(output_dir).join("manifest.json"))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, &manifest)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+//e.g. "alt500_inc90_b-4" for a 500-600 km, 90-100 deg orbit with B* between 1e-4 and 1e-3,
+//prefixed with the object type ("debris_alt500_...") when a SATCAT was given
+fn stratum(object_type: Option<ObjectType>, altitude_km: f64, inclination: f64, bstar: f64) -> String {
+    let altitude_band = ((altitude_km / ALTITUDE_BAND_KM).floor() * ALTITUDE_BAND_KM) as i64;
+    let inclination_band = ((inclination / INCLINATION_BAND_DEG).floor() * INCLINATION_BAND_DEG) as i64;
+    let bstar_band = if bstar == 0.0 { "0".to_string() } else { (bstar.abs().log10().floor() as i32).to_string() };
+    let bands = format!("alt{}_inc{}_b{}", altitude_band, inclination_band, bstar_band);
+    match object_type {
+        Some(object_type) => format!("{}_{}", object_type, bands),
+        None => bands,
+    }
+}
+
+//well mixed 64 bit hash, stable across platforms and Rust versions unlike std's hasher
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+//for ids that aren't plain catalog numbers
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(count: u64, seed: u64, stratum: &str) -> Vec<DatasetEntry> {
+        (1..=count).map(|number| DatasetEntry {
+            id: number.to_string(),
+            hash: splitmix64(seed ^ number),
+            stratum: stratum.to_string(),
+            object_type: None,
+            satcat: None,
+            altitude_km: 500.0,
+            inclination: 53.0,
+            bstar: 1.0e-4,
+            file: None,
+            split: 0,
+        }).collect()
+    }
+
+    fn counts(entries: &[DatasetEntry]) -> [usize; 3] {
+        let mut counts = [0; 3];
+        entries.iter().for_each(|entry| counts[entry.split] += 1);
+        counts
+    }
+
+    #[test]
+    fn the_seed_decides_the_split() {
+        let fractions = [0.7, 0.15, 0.15];
+        let splits = |seed: u64| -> Vec<usize> {
+            let mut entries = entries(1000, seed, "all");
+            assign_splits(&mut entries, &fractions, false);
+            entries.iter().map(|entry| entry.split).collect()
+        };
+        assert_eq!(splits(7), splits(7));
+        assert_ne!(splits(7), splits(8));
+    }
+
+    #[test]
+    fn hashed_splits_follow_the_fractions() {
+        let mut entries = entries(10000, 3, "all");
+        assign_splits(&mut entries, &[0.7, 0.15, 0.15], false);
+        let counts = counts(&entries);
+        //binomial standard deviations are under 50 here
+        assert!(counts[0].abs_diff(7000) < 200, "{:?}", counts);
+        assert!(counts[1].abs_diff(1500) < 200, "{:?}", counts);
+        assert!(counts[2].abs_diff(1500) < 200, "{:?}", counts);
+    }
+
+    #[test]
+    fn every_stratum_is_cut_at_the_fractions() {
+        let mut all = entries(20, 5, "alt500_inc50_b-4");
+        all.extend(entries(7, 5, "alt700_inc90_b-5").into_iter().map(|mut entry| {
+            entry.id = format!("x{}", entry.id);
+            entry
+        }));
+        assign_splits(&mut all, &[0.7, 0.15, 0.15], true);
+        let (large, small) = all.split_at(20);
+        assert_eq!(counts(large), [14, 3, 3]);
+        assert_eq!(counts(small), [5, 1, 1]); //7 * 0.7 and 7 * 0.85 round to 5 and 6
+    }
+
+    #[test]
+    fn split_fractions_are_checked() {
+        assert_eq!(split_fractions(&[0.7, 0.15, 0.15]).unwrap(), [0.7, 0.15, 0.15]);
+        assert!(split_fractions(&[0.7, 0.3]).is_err());
+        assert!(split_fractions(&[1.2, -0.1, -0.1]).is_err());
+        assert!(split_fractions(&[0.7, 0.2, 0.2]).is_err());
+        assert!(split_fractions(&[f64::NAN, 0.5, 0.5]).is_err());
+    }
+}
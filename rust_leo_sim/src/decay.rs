@@ -0,0 +1,325 @@
+use std::{collections::HashMap, f64::consts::PI, fs::{self, File}, io::{BufWriter, Write}, path::Path};
+use rayon::prelude::*;
+use satkit::{consts::{EARTH_RADIUS, MU_EARTH}, orbitprop::{propagate, PropSettings, SatState}, types::{Matrix3, Vector3}, Duration, Instant};
+use nalgebra::SVector;
+use anyhow::{anyhow, Result};
+use crate::analysis::median;
+use crate::elements::mean_elements;
+use crate::frames::{gcrf_to_itrf, geodetic};
+use crate::numerical_integration::{make_sat_state, sgp4_gcrf};
+use crate::satellite::{OrbitalInstance, SatelliteRecord};
+use crate::space_weather::{drag_properties, Indices, SpaceWeather};
+
+const CHUNK_DAYS: f64 = 1.0; //integrated at a time before checking for reentry
+const CHECK_SECONDS: f64 = 300.0; //altitude checked this often inside a chunk, far finer than the uncertainty window
+
+pub(crate) struct DecayOptions {
+    pub(crate) reentry_altitude: f64, //m, geodetic, below this the object counts as reentered
+    pub(crate) max_perigee_altitude: f64, //m, objects whose latest mean perigee is higher are not predicted
+    pub(crate) horizon_days: f64, //longest integration, objects still up after it get no reentry epoch
+    pub(crate) history_days: f64, //element sets this far before the latest one give B* and the mean motion trend
+    pub(crate) lead_days: Option<f64>, //validation, predict objects with a SATCAT decay date from element sets this many days before it
+    pub(crate) space_weather: Option<SpaceWeather>, //checked to cover every chunk, satkit's own file is used unchecked without it
+}
+
+pub(crate) struct DecayPrediction {
+    id: String,
+    last_epoch: Instant, //of the newest element set used
+    perigee_altitude: f64, //m, of its mean elements
+    cd_a_over_m: f64,
+    reentry: Option<Instant>, //integrated with the median B* of the history
+    early: Option<Instant>, //with its 90th percentile
+    late: Option<Instant>, //with its 10th percentile
+    trend_reentry: Option<Instant>, //where the fitted mean motion reaches the reentry altitude's
+    decay_date: Option<Instant>, //from the SATCAT
+    indices: Option<Indices>, //solar and geomagnetic activity at the last epoch
+}
+
+//integrates every low enough object with drag until it drops below the reentry altitude,
+//satkit reads the F10.7 and Ap it needs from SW-All.csv in its data directory (see SpaceWeather::install)
+pub(crate) fn predict_decays(satellites: &HashMap<String, SatelliteRecord>, options: &DecayOptions) -> Result<Vec<DecayPrediction>> {
+    let mut ids: Vec<&String> = satellites.keys().collect();
+    ids.sort();
+    let predictions: Vec<Result<Option<DecayPrediction>>> = ids.par_iter()
+        .map(|id| predict_decay(id, &satellites[*id], options))
+        .collect();
+
+    let mut kept: Vec<DecayPrediction> = Vec::new();
+    let mut failed = 0;
+    for prediction in predictions {
+        match prediction {
+            Ok(Some(prediction)) => kept.push(prediction),
+            Ok(None) => {}
+            Err(e) => {
+                println!("{e}");
+                failed += 1;
+            }
+        }
+    }
+    println!("Predicted {} objects, {} failed", kept.len(), failed);
+    Ok(kept)
+}
+
+//None for objects that are too high, or that have no element sets before the validation cutoff
+fn predict_decay(id: &str, record: &SatelliteRecord, options: &DecayOptions) -> Result<Option<DecayPrediction>> {
+    let decay_date = record.satcat.as_ref().and_then(|entry| entry.decay_date);
+    let cutoff = match (options.lead_days, decay_date) {
+        (Some(lead_days), Some(decay)) => Some(decay - Duration::from_days(lead_days)),
+        (Some(_), None) => return Ok(None), //nothing to validate against
+        (None, _) => None,
+    };
+    let history: Vec<&OrbitalInstance> = record.orbital_records.iter()
+        .filter(|instance| cutoff.is_none_or(|cutoff| instance.epoch() <= cutoff))
+        .collect();
+    let last = match history.last() {
+        Some(last) => *last,
+        None => return Ok(None),
+    };
+    let perigee_altitude = mean_elements(last).derived().perigee_altitude;
+    if perigee_altitude > options.max_perigee_altitude {
+        return Ok(None);
+    }
+    let last_epoch = last.epoch();
+    let history: Vec<&OrbitalInstance> = history.into_iter()
+        .filter(|instance| (last_epoch - instance.epoch()).as_days() <= options.history_days)
+        .collect();
+
+    //B* is noisy from one element set to the next, its spread over the history gives the window
+    let bstars: Vec<f64> = history.iter().map(|instance| instance.drag).filter(|bstar| *bstar > 0.0).collect();
+    let cd_a_over_m = drag_properties(percentile(&bstars, 0.5)).cdaoverm; //the B* the reentry below is integrated with
+    let mut tle = last.to_tle(record.catalog_number, &record.international_designator);
+    let start = sgp4_gcrf(&mut tle, &[last_epoch]).remove(0);
+    let (reentry, early, late) = if bstars.is_empty() {
+        (None, None, None) //no drag term to integrate with
+    } else {
+        let reentry_with = |quantile: f64| {
+            reentry_epoch(&start, percentile(&bstars, quantile), options)
+                .map_err(|e| anyhow!("Satellite {id}: {e}"))
+        };
+        (reentry_with(0.5)?, reentry_with(0.9)?, reentry_with(0.1)?)
+    };
+
+    Ok(Some(DecayPrediction {
+        id: id.to_string(),
+        last_epoch,
+        perigee_altitude,
+        cd_a_over_m,
+        reentry,
+        early,
+        late,
+        trend_reentry: trend_reentry(&history, options),
+        decay_date,
+        indices: options.space_weather.as_ref().map(|space_weather| space_weather.at(&last_epoch)).transpose()?,
+    }))
+}
+
+//integrates in day long chunks, checking the geodetic altitude every few minutes, None if still up at the horizon
+fn reentry_epoch(start: &SatState, bstar: f64, options: &DecayOptions) -> Result<Option<Instant>> {
+    let settings = PropSettings { gravity_order: 4, use_spaceweather: true, ..Default::default() };
+    let properties = drag_properties(bstar);
+    let horizon = start.time + Duration::from_days(options.horizon_days);
+    let (pos, vel) = (start.pos_gcrf(), start.vel_gcrf());
+    let mut state = SVector::<f64, 6>::new(pos[0], pos[1], pos[2], vel[0], vel[1], vel[2]);
+    let mut time = start.time;
+    while time < horizon {
+        let stop = earliest(time + Duration::from_days(CHUNK_DAYS), horizon);
+        if let Some(space_weather) = &options.space_weather {
+            space_weather.check_range(&time, &stop)?;
+        }
+        let result = propagate(&state, &time, &stop, &settings, Some(&properties))
+            .map_err(|e| anyhow!("Failed to propagate from {time} to {stop}: {e}"))?;
+        let checks = ((stop - time).as_seconds() / CHECK_SECONDS).ceil() as usize;
+        for k in 1..=checks {
+            let check = earliest(time + Duration::from_seconds(k as f64 * CHECK_SECONDS), stop);
+            let step = make_sat_state(check, result.interp(&check).map_err(|e| anyhow!("Failed to interpolate at {check}: {e}"))?);
+            let (_, _, altitude) = geodetic(&gcrf_to_itrf(&step).0);
+            if altitude < options.reentry_altitude {
+                return Ok(Some(check));
+            }
+        }
+        state = result.state_end;
+        time = stop;
+    }
+    Ok(None)
+}
+
+fn earliest(a: Instant, b: Instant) -> Instant {
+    if a < b { a } else { b }
+}
+
+//least squares quadratic in the mean motion over the history, solved for the reentry altitude's mean motion,
+//a straight line from the TLE's own first derivative when there are fewer than three element sets
+fn trend_reentry(history: &[&OrbitalInstance], options: &DecayOptions) -> Option<Instant> {
+    let last = history.last()?;
+    let last_epoch = last.epoch();
+    let reentry_radius = EARTH_RADIUS + options.reentry_altitude;
+    let reentry_mean_motion = (MU_EARTH / reentry_radius.powi(3)).sqrt() * 86400.0 / (2.0 * PI); //rev/day
+
+    //days relative to the newest element set, so the fit stays well conditioned
+    let points: Vec<(f64, f64)> = history.iter().map(|instance| ((instance.epoch() - last_epoch).as_days(), instance.mean_motion)).collect();
+    let fit: Option<Vector3> = if points.len() >= 3 {
+        let mut normal = Matrix3::zeros();
+        let mut rhs = Vector3::zeros();
+        for (t, n) in points.iter() {
+            let basis = Vector3::new(1.0, *t, t * t);
+            normal += basis * basis.transpose();
+            rhs += basis * *n;
+        }
+        normal.try_inverse().map(|inverse| inverse * rhs)
+    } else {
+        None
+    };
+    let [c0, c1, c2] = match fit {
+        Some(fit) => [fit[0], fit[1], fit[2]],
+        None => [last.mean_motion, 2.0 * last.first_time_derivative, 0.0], //the TLE holds half the derivative
+    };
+
+    //first time after the newest element set where c0 + c1 t + c2 t^2 reaches the reentry mean motion
+    let c0 = c0 - reentry_mean_motion;
+    let roots: Vec<f64> = if c2.abs() < 1e-12 {
+        if c1.abs() < 1e-12 { vec![] } else { vec![-c0 / c1] }
+    } else {
+        let discriminant = c1 * c1 - 4.0 * c2 * c0;
+        if discriminant < 0.0 {
+            vec![]
+        } else {
+            vec![(-c1 - discriminant.sqrt()) / (2.0 * c2), (-c1 + discriminant.sqrt()) / (2.0 * c2)]
+        }
+    };
+    roots.into_iter()
+        .filter(|t| *t > 0.0 && *t <= options.horizon_days)
+        .min_by(|a, b| a.total_cmp(b))
+        .map(|t| last_epoch + Duration::from_days(t))
+}
+
+//linear interpolation between the sorted values, 0 when there are none like median
+fn percentile(values: &[f64], quantile: f64) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let position = quantile * (sorted.len() - 1) as f64;
+    let (below, above) = (position.floor() as usize, position.ceil() as usize);
+    sorted[below] + (sorted[above] - sorted[below]) * (position - below as f64)
+}
+
+//one line per object, epochs in unix seconds and errors in days from the SATCAT decay date where there is one
+pub(crate) fn write_predictions(predictions: &[DecayPrediction], output_dir: &str) -> Result<()> {
+    fs::create_dir_all(output_dir)?;
+    let file = File::create(Path::new(output_dir).join("decay.csv"))?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "id,last_epoch,perigee_altitude_m,cd_a_over_m,reentry,early,late,trend_reentry,decay_date,error_days,trend_error_days,f107_adj,f107_adj_c81,ap,kp")?;
+    let epoch = |instant: Option<Instant>| instant.map(|instant| instant.as_unixtime().to_string()).unwrap_or_default();
+    let error = |instant: Option<Instant>, decay: Option<Instant>| match (instant, decay) {
+        (Some(instant), Some(decay)) => (instant - decay).as_days().to_string(),
+        _ => String::new(),
+    };
+    for prediction in predictions {
+        let indices = prediction.indices
+            .map(|indices| format!("{},{},{},{}", indices.f107_adj, indices.f107_adj_c81, indices.ap, indices.kp))
+            .unwrap_or(",,,".to_string());
+        writeln!(writer, "{},{},{},{},{},{},{},{},{},{},{},{}",
+            prediction.id, prediction.last_epoch.as_unixtime(), prediction.perigee_altitude, prediction.cd_a_over_m,
+            epoch(prediction.reentry), epoch(prediction.early), epoch(prediction.late), epoch(prediction.trend_reentry),
+            epoch(prediction.decay_date), error(prediction.reentry, prediction.decay_date), error(prediction.trend_reentry, prediction.decay_date), indices)?;
+    }
+    writer.flush()?;
+
+    //validation against the objects whose decay date is known
+    let validated: Vec<&DecayPrediction> = predictions.iter().filter(|prediction| prediction.decay_date.is_some()).collect();
+    if !validated.is_empty() {
+        let absolute_errors = |reentry: fn(&DecayPrediction) -> Option<Instant>| -> Vec<f64> {
+            validated.iter()
+                .filter_map(|prediction| Some((reentry(prediction)? - prediction.decay_date?).as_days().abs()))
+                .collect()
+        };
+        let integrated = absolute_errors(|prediction| prediction.reentry);
+        let trend = absolute_errors(|prediction| prediction.trend_reentry);
+        let inside_window = validated.iter()
+            .filter(|prediction| match (prediction.early, prediction.late, prediction.decay_date) {
+                //the decay date has no time of day, so the window is widened to whole days
+                (Some(early), Some(late), Some(decay)) => early <= decay + Duration::from_days(1.0) && decay <= late,
+                _ => false,
+            })
+            .count();
+        println!("{} objects with a known decay date", validated.len());
+        println!("Integrated: {} reentered inside the horizon, median error {} days, {} decay dates inside the window",
+            integrated.len(), median(integrated), inside_window);
+        println!("Mean motion trend: {} reentered inside the horizon, median error {} days", trend.len(), median(trend));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(epoch_day: f64, mean_motion: f64) -> OrbitalInstance {
+        OrbitalInstance {
+            epoch_year: 2020,
+            epoch_day,
+            first_time_derivative: 0.0,
+            second_time_derivative: 0.0,
+            drag: 1e-3,
+            inclination: 51.6,
+            raan: 0.0,
+            eccentricity: 0.001,
+            perigee: 0.0,
+            mean_motion,
+            mean_anomaly: 0.0,
+            element_set_number: 0,
+            revolution_number: 0,
+            ephemeris_type: 0,
+        }
+    }
+
+    #[test]
+    fn trend_reaches_the_reentry_mean_motion_where_the_quadratic_does() {
+        let options = DecayOptions {
+            reentry_altitude: 120_000.0,
+            max_perigee_altitude: 350_000.0,
+            horizon_days: 365.0,
+            history_days: 30.0,
+            lead_days: None,
+            space_weather: None,
+        };
+        let reentry_mean_motion = (MU_EARTH / (EARTH_RADIUS + 120_000.0).powi(3)).sqrt() * 86400.0 / (2.0 * PI);
+        //mean motion rising as 15.5 + 0.001 k^2 rev/day over days k = 0..30, which reaches the reentry value at k = sqrt(dn / 0.001)
+        let history: Vec<OrbitalInstance> = (0..=30).map(|k| instance(70.0 + k as f64, 15.5 + 0.001 * (k as f64).powi(2))).collect();
+        let history: Vec<&OrbitalInstance> = history.iter().collect();
+        let expected_days = ((reentry_mean_motion - 15.5) / 0.001).sqrt() - 30.0;
+        let predicted = trend_reentry(&history, &options).unwrap();
+        assert!(((predicted - history[30].epoch()).as_days() - expected_days).abs() < 1e-3);
+
+        assert!((percentile(&[3.0, 1.0, 2.0, 4.0], 0.5) - 2.5).abs() < 1e-12);
+    }
+
+    //integrating with drag needs satkit's gravity, EOP and space weather files, which sandboxed builds don't have
+    #[test]
+    #[ignore = "needs satkit data files"]
+    fn low_perigee_reenters_inside_a_short_horizon() {
+        crate::data::DataDir::open(None, false, &[crate::data::DataUse::Integration, crate::data::DataUse::Drag]).unwrap();
+        let mut options = DecayOptions {
+            reentry_altitude: 120_000.0,
+            max_perigee_altitude: 350_000.0,
+            horizon_days: 5.0,
+            history_days: 30.0,
+            lead_days: None,
+            space_weather: None,
+        };
+        //circular at 160 km with a large B*, down within a day or two
+        let radius = EARTH_RADIUS + 160_000.0;
+        let speed = (MU_EARTH / radius).sqrt();
+        let inclination = 51.6_f64.to_radians();
+        let start = SatState::from_pv(&Instant::from_date(2024, 1, 1), &Vector3::new(radius, 0.0, 0.0),
+            &Vector3::new(0.0, speed * inclination.cos(), speed * inclination.sin()));
+
+        let reentry = reentry_epoch(&start, 5e-3, &options).unwrap().unwrap();
+        let days = (reentry - start.time).as_days();
+        assert!(days > 0.0 && days < options.horizon_days, "{days}");
+
+        options.horizon_days = 0.1;
+        assert!(reentry_epoch(&start, 5e-3, &options).unwrap().is_none());
+    }
+}
@@ -0,0 +1,275 @@
+use std::{f64::consts::PI, str::FromStr};
+use satkit::{consts::{EARTH_RADIUS, MU_EARTH}, types::{Matrix3, Vector3}};
+use anyhow::{bail, Result};
+use crate::satellite::OrbitalInstance;
+
+//classical elements of an elliptic orbit, meters and radians
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Keplerian {
+    pub(crate) semi_major_axis: f64,
+    pub(crate) eccentricity: f64,
+    pub(crate) inclination: f64,
+    pub(crate) raan: f64,
+    pub(crate) arg_perigee: f64,
+    pub(crate) mean_anomaly: f64,
+}
+
+//equinoctial elements, no singularity for the near circular and near equatorial orbits where raan and arg_perigee are undefined
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Equinoctial {
+    pub(crate) semi_major_axis: f64,
+    pub(crate) h: f64, //e sin(arg_perigee + raan)
+    pub(crate) k: f64, //e cos(arg_perigee + raan)
+    pub(crate) p: f64, //tan(i/2) sin(raan)
+    pub(crate) q: f64, //tan(i/2) cos(raan)
+    pub(crate) mean_longitude: f64, //raan + arg_perigee + mean_anomaly
+}
+
+//what the elements say about the orbit's size, in meters and seconds (the semi-major axis is an element already)
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Derived {
+    pub(crate) perigee_altitude: f64, //above the equatorial radius
+    pub(crate) apogee_altitude: f64,
+    pub(crate) period: f64,
+}
+
+fn wrap_angle(angle: f64) -> f64 {
+    angle.rem_euclid(2.0 * PI)
+}
+
+//osculating elements of a position and velocity in an inertial frame (GCRF or TEME)
+pub(crate) fn cartesian_to_keplerian(pos: &Vector3, vel: &Vector3) -> Keplerian {
+    let r = pos.norm();
+    let h = pos.cross(vel);
+    let e = ((vel.norm_squared() - MU_EARTH / r) * pos - pos.dot(vel) * vel) / MU_EARTH;
+    let semi_major_axis = 1.0 / (2.0 / r - vel.norm_squared() / MU_EARTH);
+    let inclination = (h[2] / h.norm()).clamp(-1.0, 1.0).acos();
+    //the node is undefined for an equatorial orbit (h along z), which puts it on the x axis rather than wherever atan2(0, -0) lands
+    let raan = if h[0].hypot(h[1]) < 1e-12 * h.norm() { 0.0 } else { h[0].atan2(-h[1]) };
+
+    //angles are measured in the orbit plane from the ascending node
+    let node = Vector3::new(raan.cos(), raan.sin(), 0.0);
+    let in_plane = h.normalize().cross(&node);
+    let eccentricity = e.norm();
+    let arg_latitude = pos.dot(&in_plane).atan2(pos.dot(&node));
+    let arg_perigee = if eccentricity > 1e-12 { e.dot(&in_plane).atan2(e.dot(&node)) } else { 0.0 }; //circular, measured from the node
+    let true_anomaly = arg_latitude - arg_perigee;
+    let eccentric_anomaly = ((1.0 - eccentricity.powi(2)).sqrt() * true_anomaly.sin()).atan2(eccentricity + true_anomaly.cos());
+
+    Keplerian {
+        semi_major_axis,
+        eccentricity,
+        inclination,
+        raan: wrap_angle(raan),
+        arg_perigee: wrap_angle(arg_perigee),
+        mean_anomaly: wrap_angle(eccentric_anomaly - eccentricity * eccentric_anomaly.sin()),
+    }
+}
+
+//Newton's method on Kepler's equation, starting from pi for the very eccentric orbits where M is a poor guess
+fn eccentric_anomaly(mean_anomaly: f64, eccentricity: f64) -> f64 {
+    let mean_anomaly = wrap_angle(mean_anomaly);
+    let mut anomaly = if eccentricity > 0.8 { PI } else { mean_anomaly };
+    for _ in 0..50 {
+        let step = (anomaly - eccentricity * anomaly.sin() - mean_anomaly) / (1.0 - eccentricity * anomaly.cos());
+        anomaly -= step;
+        if step.abs() < 1e-14 {
+            break;
+        }
+    }
+    anomaly
+}
+
+impl Keplerian {
+    pub(crate) fn to_cartesian(self) -> (Vector3, Vector3) {
+        let e = self.eccentricity;
+        let anomaly = eccentric_anomaly(self.mean_anomaly, e);
+        let true_anomaly = ((1.0 - e.powi(2)).sqrt() * anomaly.sin()).atan2(anomaly.cos() - e);
+        let r = self.semi_major_axis * (1.0 - e * anomaly.cos());
+        let speed = (MU_EARTH / (self.semi_major_axis * (1.0 - e.powi(2)))).sqrt();
+
+        //perifocal frame, x towards perigee and z along the angular momentum
+        let pos_perifocal = Vector3::new(r * true_anomaly.cos(), r * true_anomaly.sin(), 0.0);
+        let vel_perifocal = Vector3::new(-speed * true_anomaly.sin(), speed * (e + true_anomaly.cos()), 0.0);
+        let rotation = rotation_z(self.raan) * rotation_x(self.inclination) * rotation_z(self.arg_perigee);
+        (rotation * pos_perifocal, rotation * vel_perifocal)
+    }
+
+    pub(crate) fn to_equinoctial(self) -> Equinoctial {
+        let longitude_of_perigee = self.raan + self.arg_perigee;
+        let half_inclination = (self.inclination / 2.0).tan();
+        Equinoctial {
+            semi_major_axis: self.semi_major_axis,
+            h: self.eccentricity * longitude_of_perigee.sin(),
+            k: self.eccentricity * longitude_of_perigee.cos(),
+            p: half_inclination * self.raan.sin(),
+            q: half_inclination * self.raan.cos(),
+            mean_longitude: wrap_angle(longitude_of_perigee + self.mean_anomaly),
+        }
+    }
+
+    pub(crate) fn perigee_radius(self) -> f64 {
+        self.semi_major_axis * (1.0 - self.eccentricity)
+    }
+
+    pub(crate) fn apogee_radius(self) -> f64 {
+        self.semi_major_axis * (1.0 + self.eccentricity)
+    }
+
+    pub(crate) fn derived(self) -> Derived {
+        Derived {
+            perigee_altitude: self.perigee_radius() - EARTH_RADIUS,
+            apogee_altitude: self.apogee_radius() - EARTH_RADIUS,
+            period: 2.0 * PI * (self.semi_major_axis.powi(3) / MU_EARTH).sqrt(),
+        }
+    }
+}
+
+impl Equinoctial {
+    pub(crate) fn to_keplerian(self) -> Keplerian {
+        let raan = self.p.atan2(self.q);
+        let longitude_of_perigee = self.h.atan2(self.k);
+        Keplerian {
+            semi_major_axis: self.semi_major_axis,
+            eccentricity: self.h.hypot(self.k),
+            inclination: 2.0 * self.p.hypot(self.q).atan(),
+            raan: wrap_angle(raan),
+            arg_perigee: wrap_angle(longitude_of_perigee - raan),
+            mean_anomaly: wrap_angle(self.mean_longitude - longitude_of_perigee),
+        }
+    }
+}
+
+fn rotation_x(angle: f64) -> Matrix3 {
+    let (sin, cos) = angle.sin_cos();
+    Matrix3::new(1.0, 0.0, 0.0, 0.0, cos, -sin, 0.0, sin, cos)
+}
+
+fn rotation_z(angle: f64) -> Matrix3 {
+    let (sin, cos) = angle.sin_cos();
+    Matrix3::new(cos, -sin, 0.0, sin, cos, 0.0, 0.0, 0.0, 1.0)
+}
+
+//the TLE's mean elements as Keplerian ones, the semi-major axis from the mean motion
+//these are SGP4 mean elements, not osculating ones, so they differ from cartesian_to_keplerian of the SGP4 state by kilometers
+pub(crate) fn mean_elements(instance: &OrbitalInstance) -> Keplerian {
+    Keplerian {
+        semi_major_axis: instance.semi_major_axis(),
+        eccentricity: instance.eccentricity,
+        inclination: instance.inclination.to_radians(),
+        raan: instance.raan.to_radians(),
+        arg_perigee: instance.perigee.to_radians(),
+        mean_anomaly: instance.mean_anomaly.to_radians(),
+    }
+}
+
+//which elements are written after each step's state
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum ElementSet {
+    Keplerian,
+    Equinoctial,
+}
+
+impl FromStr for ElementSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "keplerian" => Ok(ElementSet::Keplerian),
+            "equinoctial" => Ok(ElementSet::Equinoctial),
+            _ => bail!("Unknown element set {s}, expected keplerian or equinoctial"),
+        }
+    }
+}
+
+impl ElementSet {
+    pub(crate) fn columns(self) -> [&'static str; 6] {
+        match self {
+            ElementSet::Keplerian => ["a", "e", "i", "raan", "arg_perigee", "mean_anomaly"],
+            ElementSet::Equinoctial => ["a", "h", "k", "p", "q", "mean_longitude"],
+        }
+    }
+
+    //the six elements of a state in the order of columns, angles in degrees
+    pub(crate) fn elements_of(self, pos: &Vector3, vel: &Vector3) -> [f64; 6] {
+        let keplerian = cartesian_to_keplerian(pos, vel);
+        match self {
+            ElementSet::Keplerian => [keplerian.semi_major_axis, keplerian.eccentricity, keplerian.inclination.to_degrees(),
+                keplerian.raan.to_degrees(), keplerian.arg_perigee.to_degrees(), keplerian.mean_anomaly.to_degrees()],
+            ElementSet::Equinoctial => {
+                let equinoctial = keplerian.to_equinoctial();
+                [equinoctial.semi_major_axis, equinoctial.h, equinoctial.k, equinoctial.p, equinoctial.q, equinoctial.mean_longitude.to_degrees()]
+            }
+        }
+    }
+
+    //back to position and velocity from elements laid out like elements_of's
+    pub(crate) fn to_cartesian(self, elements: &[f64; 6]) -> (Vector3, Vector3) {
+        let keplerian = match self {
+            ElementSet::Keplerian => Keplerian {
+                semi_major_axis: elements[0],
+                eccentricity: elements[1],
+                inclination: elements[2].to_radians(),
+                raan: elements[3].to_radians(),
+                arg_perigee: elements[4].to_radians(),
+                mean_anomaly: elements[5].to_radians(),
+            },
+            ElementSet::Equinoctial => Equinoctial {
+                semi_major_axis: elements[0],
+                h: elements[1],
+                k: elements[2],
+                p: elements[3],
+                q: elements[4],
+                mean_longitude: elements[5].to_radians(),
+            }.to_keplerian(),
+        };
+        keplerian.to_cartesian()
+    }
+}
+
+pub(crate) const DERIVED_COLUMNS: [&str; 3] = ["perigee_altitude", "apogee_altitude", "period"];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &Vector3, b: &Vector3, tolerance: f64) {
+        assert!((a - b).norm() < tolerance, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn cartesian_round_trips_through_both_element_sets() {
+        //an ISS like orbit, a slightly eccentric one and an equatorial circular one
+        let states = [
+            (Vector3::new(-4_400_000.0, 2_300_000.0, 4_700_000.0), Vector3::new(-3_900.0, -6_200.0, -600.0)),
+            (Vector3::new(6_900_000.0, 0.0, 0.0), Vector3::new(0.0, 5_800.0, 5_300.0)),
+            (Vector3::new(7_000_000.0, 0.0, 0.0), Vector3::new(0.0, (MU_EARTH / 7_000_000.0).sqrt(), 0.0)),
+        ];
+        for (pos, vel) in states {
+            for set in [ElementSet::Keplerian, ElementSet::Equinoctial] {
+                let (pos_back, vel_back) = set.to_cartesian(&set.elements_of(&pos, &vel));
+                assert_close(&pos, &pos_back, 1e-3);
+                assert_close(&vel, &vel_back, 1e-6);
+            }
+        }
+
+        //the equatorial circular one has its node and perigee on the x axis, where it starts
+        let (pos, vel) = states[2];
+        let keplerian = cartesian_to_keplerian(&pos, &vel);
+        assert_eq!(keplerian.raan, 0.0);
+        assert_eq!(keplerian.arg_perigee, 0.0);
+        assert!(keplerian.mean_anomaly.min(2.0 * PI - keplerian.mean_anomaly) < 1e-9);
+    }
+
+    #[test]
+    fn derived_quantities_of_a_circular_orbit() {
+        let radius = EARTH_RADIUS + 500_000.0;
+        let keplerian = cartesian_to_keplerian(&Vector3::new(0.0, radius, 0.0), &Vector3::new(0.0, 0.0, (MU_EARTH / radius).sqrt()));
+        assert!(keplerian.eccentricity < 1e-12);
+        assert!((keplerian.inclination.to_degrees() - 90.0).abs() < 1e-9);
+        let derived = keplerian.derived();
+        assert!((derived.perigee_altitude - 500_000.0).abs() < 1e-3);
+        assert!((derived.apogee_altitude - 500_000.0).abs() < 1e-3);
+        assert!((derived.period - 5_677.0).abs() < 1.0); //about 94.6 minutes
+    }
+}
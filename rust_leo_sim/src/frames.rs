@@ -0,0 +1,329 @@
+use std::{collections::HashMap, fmt, fs::{self, File}, io::{BufWriter, Write}, path::Path, str::FromStr};
+use rayon::prelude::*;
+use satkit::{consts::OMEGA_EARTH, frametransform::{qgcrf2itrf, qitrf2gcrf, qteme2gcrf}, itrfcoord::ITRFCoord, orbitprop::SatState, sgp4::{sgp4, SGP4Error}, types::{Matrix3, Vector3}, Duration, Instant, TLE};
+use anyhow::{bail, Result};
+use crate::collision::CovarianceColumns;
+use crate::elements::{ElementSet, DERIVED_COLUMNS};
+use crate::satellite::SatelliteRecord;
+
+//every propagated state is kept in GCRF internally, this is the frame it is written out in
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub(crate) enum Frame {
+    Teme, //what SGP4 and the ML-dSGP4 model work in
+    #[default]
+    Gcrf,
+    Itrf, //earth fixed, velocity relative to the rotating earth
+    Rtn, //offset from a reference state (the SGP4 state of the same TLE) in the reference's radial/in-track/cross-track axes
+}
+
+impl FromStr for Frame {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "teme" => Ok(Frame::Teme),
+            "gcrf" => Ok(Frame::Gcrf),
+            "itrf" => Ok(Frame::Itrf),
+            "rtn" => Ok(Frame::Rtn),
+            _ => bail!("Unknown frame {s}, expected teme, gcrf, itrf or rtn"),
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Frame::Teme => "TEME",
+            Frame::Gcrf => "GCRF",
+            Frame::Itrf => "ITRF",
+            Frame::Rtn => "RTN",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+//difference of a state from a reference state, expressed in the radial/in-track/cross-track frame of the reference
+#[derive(Clone, Copy)]
+pub(crate) struct RtnError {
+    pub(crate) position: Vector3,
+    pub(crate) velocity: Vector3,
+}
+
+//rotation matrix whose rows are the radial, in-track and cross-track unit vectors of a state
+pub(crate) fn rtn_rotation(pos: &Vector3, vel: &Vector3) -> Matrix3 {
+    let r = pos.normalize();
+    let n = pos.cross(vel).normalize();
+    let t = n.cross(&r);
+    Matrix3::from_rows(&[r.transpose(), t.transpose(), n.transpose()])
+}
+
+pub(crate) fn rtn_error(reference: &SatState, other: &SatState) -> RtnError {
+    let rotation = rtn_rotation(&reference.pos_gcrf(), &reference.vel_gcrf());
+    RtnError {
+        position: rotation * (other.pos_gcrf() - reference.pos_gcrf()),
+        velocity: rotation * (other.vel_gcrf() - reference.vel_gcrf()),
+    }
+}
+
+pub(crate) fn teme_to_gcrf(time: &Instant, pos: &Vector3, vel: &Vector3) -> (Vector3, Vector3) {
+    let rotation = qteme2gcrf(time).to_rotation_matrix();
+    (rotation * pos, rotation * vel)
+}
+
+pub(crate) fn gcrf_to_teme(state: &SatState) -> (Vector3, Vector3) {
+    let rotation = qteme2gcrf(&state.time).to_rotation_matrix().transpose();
+    (rotation * state.pos_gcrf(), rotation * state.vel_gcrf())
+}
+
+//earth fixed position and velocity, the velocity relative to the rotating earth
+pub(crate) fn gcrf_to_itrf(state: &SatState) -> (Vector3, Vector3) {
+    let rotation = qgcrf2itrf(&state.time).to_rotation_matrix();
+    let pos_itrf: Vector3 = rotation * state.pos_gcrf();
+    let omega = Vector3::new(0.0, 0.0, OMEGA_EARTH);
+    let vel_itrf: Vector3 = rotation * state.vel_gcrf() - omega.cross(&pos_itrf);
+    (pos_itrf, vel_itrf)
+}
+
+pub(crate) fn itrf_to_gcrf(time: &Instant, pos: &Vector3, vel: &Vector3) -> (Vector3, Vector3) {
+    let rotation = qitrf2gcrf(time).to_rotation_matrix();
+    let omega = Vector3::new(0.0, 0.0, OMEGA_EARTH);
+    (rotation * pos, rotation * (vel + omega.cross(pos)))
+}
+
+//position and velocity of a GCRF state in another frame, RTN needs the state it is measured from
+pub(crate) fn convert(state: &SatState, frame: Frame, reference: Option<&SatState>) -> Result<(Vector3, Vector3)> {
+    Ok(match frame {
+        Frame::Teme => gcrf_to_teme(state),
+        Frame::Gcrf => (state.pos_gcrf(), state.vel_gcrf()),
+        Frame::Itrf => gcrf_to_itrf(state),
+        Frame::Rtn => match reference {
+            Some(reference) => {
+                let error = rtn_error(reference, state);
+                (error.position, error.velocity)
+            }
+            None => bail!("RTN output needs a reference state"),
+        },
+    })
+}
+
+//back to a GCRF SatState, not possible for RTN without the reference it was measured from
+pub(crate) fn to_gcrf(time: &Instant, pos: &Vector3, vel: &Vector3, frame: Frame) -> Result<SatState> {
+    let (pos, vel) = match frame {
+        Frame::Teme => teme_to_gcrf(time, pos, vel),
+        Frame::Gcrf => (*pos, *vel),
+        Frame::Itrf => itrf_to_gcrf(time, pos, vel),
+        Frame::Rtn => bail!("RTN states can't be converted back without their reference state"),
+    };
+    Ok(SatState::from_pv(time, &pos, &vel))
+}
+
+//WGS-84 latitude and longitude in degrees and height above the ellipsoid in meters
+pub(crate) fn geodetic(pos_itrf: &Vector3) -> (f64, f64, f64) {
+    ITRFCoord { itrf: *pos_itrf }.to_geodetic_deg()
+}
+
+//what each written step holds: the state in one frame, then optional earth fixed, geodetic, orbital element and covariance columns
+#[derive(Clone, Copy, Default)]
+pub(crate) struct OutputFrames {
+    pub(crate) frame: Frame,
+    pub(crate) itrf: bool, //ECEF position and velocity, m and m/s
+    pub(crate) geodetic: bool, //WGS-84 latitude and longitude in degrees, altitude in m
+    pub(crate) elements: Option<ElementSet>, //osculating elements of the GCRF state, a in m and angles in degrees
+    pub(crate) derived: bool, //perigee and apogee altitude in m and period in s, from the same osculating elements
+    pub(crate) covariance: Option<CovarianceColumns>, //the propagated covariance, only filled in when it was propagated
+}
+
+impl OutputFrames {
+    //first line of a written file, e.g. "#frame=TEME columns=time,x,y,z,vx,vy,vz"
+    pub(crate) fn header(&self) -> String {
+        let mut columns: Vec<&str> = vec!["time", "x", "y", "z", "vx", "vy", "vz"];
+        if self.itrf {
+            columns.extend(["x_itrf", "y_itrf", "z_itrf", "vx_itrf", "vy_itrf", "vz_itrf"]);
+        }
+        if self.geodetic {
+            columns.extend(["latitude", "longitude", "altitude"]);
+        }
+        if let Some(elements) = self.elements {
+            columns.extend(elements.columns());
+        }
+        if self.derived {
+            columns.extend(DERIVED_COLUMNS);
+        }
+        let covariance_columns: Vec<String> = self.covariance.map(|covariance| covariance.columns()).unwrap_or_default();
+        columns.extend(covariance_columns.iter().map(|column| column.as_str()));
+        format!("{}frame={} columns={}", HEADER_PREFIX, self.frame, columns.join(","))
+    }
+}
+
+pub(crate) const HEADER_PREFIX: &str = "#";
+
+//reads the frame back out of the file header, None for any other line (including "#segment" lines)
+pub(crate) fn parse_header(line: &str) -> Result<Option<Frame>> {
+    let fields = match line.strip_prefix(HEADER_PREFIX) {
+        Some(fields) => fields,
+        None => return Ok(None),
+    };
+    for field in fields.split_whitespace() {
+        if let Some(frame) = field.strip_prefix("frame=") {
+            return Ok(Some(frame.parse::<Frame>()?));
+        }
+    }
+    Ok(None)
+}
+
+pub(crate) struct GroundTrackOptions {
+    pub(crate) start: Instant,
+    pub(crate) stop: Instant,
+    pub(crate) step: f64, //s
+    pub(crate) ids: Option<Vec<String>>, //all satellites when None
+}
+
+//writes one groundtrack_<id>.csv per satellite with SGP4 sub-satellite points over the window
+pub(crate) fn write_ground_tracks(satellites: &HashMap<String, SatelliteRecord>, options: &GroundTrackOptions, output_dir: &str) -> Result<()> {
+    if options.stop <= options.start || options.step <= 0.0 {
+        bail!("Ground track window must end after it starts and use a positive step");
+    }
+    fs::create_dir_all(output_dir)?;
+
+    let ids: Vec<&String> = match &options.ids {
+        Some(ids) => ids.iter().filter(|id| satellites.contains_key(*id)).collect(),
+        None => satellites.keys().collect(),
+    };
+
+    let count = ((options.stop - options.start).as_seconds() / options.step).floor() as usize;
+    let times: Vec<Instant> = (0..=count).map(|k| options.start + Duration::from_seconds(k as f64 * options.step)).collect();
+
+    ids.par_iter().try_for_each(|id| -> Result<()> {
+        let mut tle = match satellites[*id].tle_at(&options.start) {
+            Some(tle) => tle,
+            None => return Ok(()),
+        };
+
+        let file = File::create(Path::new(output_dir).join(format!("groundtrack_{}.csv", id)))?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "time,latitude,longitude,altitude,x_itrf,y_itrf,z_itrf")?;
+        for (time, pos_itrf) in ground_track(&mut tle, &times) {
+            let (latitude, longitude, altitude) = geodetic(&pos_itrf);
+            writeln!(writer, "{},{},{},{},{},{},{}",
+                time.as_unixtime(), latitude, longitude, altitude, pos_itrf[0], pos_itrf[1], pos_itrf[2])?;
+        }
+        writer.flush()?;
+        Ok(())
+    })?;
+    println!("Wrote ground tracks for {} satellites", ids.len());
+    Ok(())
+}
+
+//ITRF position at each time SGP4 succeeds, the steps where it fails (e.g. the object has decayed) are left out
+fn ground_track(tle: &mut TLE, times: &[Instant]) -> Vec<(Instant, Vector3)> {
+    let (r_teme, v_teme, errs) = sgp4(tle, times);
+    times.iter().enumerate()
+        .filter(|(i, _)| matches!(errs.get(*i), Some(SGP4Error::SGP4Success)))
+        .map(|(i, time)| {
+            let (pos, vel) = teme_to_gcrf(time, &Vector3::new(r_teme[(0, i)], r_teme[(1, i)], r_teme[(2, i)]), &Vector3::new(v_teme[(0, i)], v_teme[(1, i)], v_teme[(2, i)]));
+            (*time, gcrf_to_itrf(&SatState::from_pv(time, &pos, &vel)).0)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::tests::frame_data;
+
+    fn state() -> SatState {
+        SatState::from_pv(&Instant::from_unixtime(1.7e9), &Vector3::new(5.0e6, 4.0e6, 1.0e6), &Vector3::new(-4.0e3, 5.0e3, 2.0e3))
+    }
+
+    fn close(a: (Vector3, Vector3), b: (Vector3, Vector3)) -> bool {
+        (a.0 - b.0).norm() < 1e-6 && (a.1 - b.1).norm() < 1e-9
+    }
+
+    #[test]
+    fn conversions_invert() {
+        frame_data();
+        let state = state();
+        let gcrf = (state.pos_gcrf(), state.vel_gcrf());
+
+        let teme = gcrf_to_teme(&state);
+        assert!(close(teme_to_gcrf(&state.time, &teme.0, &teme.1), gcrf));
+        let itrf = gcrf_to_itrf(&state);
+        assert!((itrf.0.norm() - gcrf.0.norm()).abs() < 1e-6);
+        assert!(close(itrf_to_gcrf(&state.time, &itrf.0, &itrf.1), gcrf));
+
+        for frame in [Frame::Teme, Frame::Gcrf, Frame::Itrf] {
+            let (pos, vel) = convert(&state, frame, None).unwrap();
+            let back = to_gcrf(&state.time, &pos, &vel, frame).unwrap();
+            assert!(close((back.pos_gcrf(), back.vel_gcrf()), gcrf), "{frame}");
+        }
+    }
+
+    #[test]
+    fn rtn_needs_its_reference() {
+        let state = state();
+        assert!(convert(&state, Frame::Rtn, None).is_err());
+        assert!(to_gcrf(&state.time, &state.pos_gcrf(), &state.vel_gcrf(), Frame::Rtn).is_err());
+
+        let ahead = SatState::from_pv(&state.time, &(state.pos_gcrf() + state.vel_gcrf().normalize() * 10.0), &state.vel_gcrf());
+        let (pos, vel) = convert(&ahead, Frame::Rtn, Some(&state)).unwrap();
+        let in_track = rtn_rotation(&state.pos_gcrf(), &state.vel_gcrf()) * state.vel_gcrf().normalize() * 10.0;
+        assert!((pos - in_track).norm() < 1e-9 && pos[1] > 9.0 && vel.norm() == 0.0);
+    }
+
+    //the ISS, moved to 2024 so the stand in EOP cover it. Geodetic latitude runs a little past the 51.64 degree inclination
+    fn iss() -> TLE {
+        let mut tle = TLE::load_2line("1 25544U 98067A   08264.51782528 -.00002182  00000-0 -11606-4 0  2927",
+            "2 25544  51.6416 247.4627 0006703 130.5360 325.0288 15.72125391563537").unwrap();
+        tle.epoch = Instant::from_date(2024, 1, 1);
+        tle
+    }
+
+    #[test]
+    fn ground_track_stays_under_the_orbit_and_drops_failed_steps() {
+        frame_data();
+        let times: Vec<Instant> = (0..=95).map(|k| Instant::from_date(2024, 1, 1) + Duration::from_seconds(60.0 * k as f64)).collect();
+        let track = ground_track(&mut iss(), &times);
+        assert_eq!(track.len(), times.len());
+        for (_, pos_itrf) in track.iter() {
+            let (latitude, longitude, altitude) = geodetic(pos_itrf);
+            assert!(latitude.abs() < 52.0 && longitude.abs() <= 180.0, "{latitude} {longitude}");
+            assert!((300.0e3..450.0e3).contains(&altitude), "{altitude}");
+        }
+        assert!(track.iter().any(|(_, pos)| geodetic(pos).0 > 50.0) && track.iter().any(|(_, pos)| geodetic(pos).0 < -50.0));
+
+        //heavy drag brings it down within the window, the steps after that are not written
+        let mut decaying = iss();
+        decaying.bstar = 0.05;
+        let days: Vec<Instant> = (0..=60).map(|k| Instant::from_date(2024, 1, 1) + Duration::from_days(k as f64)).collect();
+        let track = ground_track(&mut decaying, &days);
+        assert!(!track.is_empty() && track.len() < days.len(), "{} of {}", track.len(), days.len());
+        assert!(track.iter().all(|(_, pos)| geodetic(pos).2 > 0.0));
+
+        let satellites = HashMap::from([("25544".to_string(), SatelliteRecord {
+            catalog_number: 25544,
+            international_designator: "98067A".to_string(),
+            orbital_records: vec![crate::satellite::OrbitalInstance::from_tle(&decaying)],
+            satcat: None,
+        })]);
+        let dir = std::env::temp_dir().join(format!("ground_track_{}", std::process::id()));
+        let options = GroundTrackOptions { start: days[0], stop: days[60], step: 86400.0, ids: None };
+        write_ground_tracks(&satellites, &options, &dir.to_string_lossy()).unwrap();
+        let written = fs::read_to_string(dir.join("groundtrack_25544.csv")).unwrap();
+        assert_eq!(written.lines().count(), 1 + track.len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn header_gives_back_the_frame() {
+        for frame in [Frame::Teme, Frame::Gcrf, Frame::Itrf, Frame::Rtn] {
+            let frames = OutputFrames { frame, itrf: true, geodetic: true, derived: true, ..Default::default() };
+            let header = frames.header();
+            assert!(header.ends_with(&format!("vz_itrf,latitude,longitude,altitude,{}", DERIVED_COLUMNS.join(","))));
+            assert_eq!(parse_header(&header).unwrap(), Some(frame));
+            assert_eq!(frame.to_string().parse::<Frame>().unwrap(), frame);
+        }
+        assert_eq!(parse_header("#segment tle=0").unwrap(), None);
+        assert_eq!(parse_header("1700000000,1,2,3,4,5,6").unwrap(), None);
+        assert!(parse_header("#frame=ecef columns=time").is_err());
+    }
+}
@@ -0,0 +1,399 @@
+use std::{collections::{HashMap, HashSet}, env};
+use satkit::{Duration, Instant};
+pub mod read;
+pub mod merge;
+pub mod satellite;
+pub mod propagate;
+pub mod numerical_integration;
+pub mod analysis;
+pub mod cli;
+pub mod consistency;
+pub mod maneuver;
+pub mod conjunction;
+pub mod collision;
+pub mod frames;
+pub mod elements;
+pub mod passes;
+pub mod dataset;
+pub mod training;
+pub mod python_module;
+pub mod python_env;
+pub mod spacetrack;
+pub mod catalog_store;
+pub mod satcat;
+pub mod decay;
+pub mod space_weather;
+pub mod data;
+
+const DENSITY: u16 = 5000; //samples per gap, shared so every mode works on the same time grid
+const COMPRESSION_LEVEL: i32 = 3;
+const MANEUVER_SIGMA: f64 = 6.0;
+
+//the binary's entry point, args as they come from env::args with the program name first
+pub fn run(args: Vec<String>) -> anyhow::Result<()> {
+    if args.len() > 1 {
+        let flags = cli::Flags::parse(&args[2..]);
+        match args[1].as_str() {
+            "n" => { //key for numerical integration for now
+                let data = data_dir(&flags, &[data::DataUse::Integration])?;
+                let mut satellites = read_integration_tles(&flags, "./data/tle2024.txt")?;
+                satcat_tles(&flags, &mut satellites);
+                check_tle_epochs(&data, &satellites)?;
+                //a covariance is propagated with every segment when one is given, as for the Pc of the conjunctions mode
+                let propagate_covariance = flags.has("covariance") || flags.has("sigma-rtn");
+                let covariance_frame = flags.get_or("covariance-frame", frames::Frame::Gcrf)?; //rtn is the axes of each integrated state
+                if !matches!(covariance_frame, frames::Frame::Gcrf | frames::Frame::Rtn) {
+                    anyhow::bail!("--covariance-frame is gcrf or rtn");
+                }
+                let options = numerical_integration::IntegrationOptions {
+                    sampling: flags.get_or("sampling", numerical_integration::Sampling::FixedCount(DENSITY)).unwrap(),
+                    compression_level: COMPRESSION_LEVEL,
+                    maneuvers: flags.get_or("maneuvers", maneuver::ManeuverHandling::Ignore).unwrap(),
+                    maneuver_sigma: flags.get_or("maneuver-sigma", MANEUVER_SIGMA).unwrap(),
+                    frames: frames::OutputFrames {
+                        frame: flags.get_or("frame", frames::Frame::Gcrf).unwrap(),
+                        itrf: flags.has("itrf"),
+                        geodetic: flags.has("geodetic"),
+                        elements: flags.get_parsed("elements").unwrap(),
+                        derived: flags.has("derived"),
+                        covariance: propagate_covariance.then(|| collision::CovarianceColumns {
+                            form: flags.get_or("covariance-form", collision::CovarianceForm::Full).unwrap(),
+                            rtn: covariance_frame == frames::Frame::Rtn,
+                        }),
+                    },
+                    gaps: gap_limits(&flags),
+                    samples: flags.get("samples").map(|output_dir| training::SampleOptions {
+                        frame: flags.get_or("samples-frame", frames::Frame::Teme).unwrap(),
+                        output_dir: output_dir.to_string(),
+                    }),
+                    drag: space_weather(&flags)?,
+                    covariance: propagate_covariance.then(|| covariance_source(&flags)).transpose()?,
+                };
+                numerical_integration::integrate(satellites, &options).unwrap();
+            }
+            "t" => { //key for training model for now
+                println!("Nothing yet");
+            }
+            "p" => { //key for reading and propagating
+                let python_env = python_env::PythonEnv::new(flags.get("python-path").unwrap_or("./python"), flags.get("venv"));
+                python_env::init(&python_env).unwrap();
+                let satellites = read_catalog(&flags, "./data/tle2006.txt");
+                let options = propagate::BatchOptions {
+                    batch_size: flags.get_or("batch-size", 1_000_000).unwrap(),
+                    density: flags.get_or("density", 10000).unwrap(),
+                };
+                let mut propagated: HashSet<String> = HashSet::new();
+                let rows = propagate::propagate_satellites(satellites, &options, |id, _states| {
+                    propagated.insert(id.to_string());
+                    Ok(())
+                })?;
+                println!("Propagated {} rows of {} satellites", rows, propagated.len());
+            }
+            "analyze" => { //SGP4 vs numerical integration error analysis
+                let output = flags.get("output").unwrap_or("./data/output/analysis");
+                let options = analysis::AnalysisOptions {
+                    sampling: flags.get_or("sampling", numerical_integration::Sampling::FixedCount(DENSITY)).unwrap(),
+                    compression_level: COMPRESSION_LEVEL,
+                    maneuvers: flags.get_or("maneuvers", maneuver::ManeuverHandling::Ignore).unwrap(),
+                    maneuver_sigma: flags.get_or("maneuver-sigma", MANEUVER_SIGMA).unwrap(),
+                    gaps: gap_limits(&flags),
+                };
+                let data = data_dir(&flags, &[data::DataUse::Integration])?;
+                let mut satellites = read_integration_tles(&flags, "./data/tle2024.txt")?;
+                satcat_tles(&flags, &mut satellites);
+                check_tle_epochs(&data, &satellites)?;
+                analysis::analyze(satellites, &options, output).unwrap();
+            }
+            "consistency" => { //propagates each TLE to the next epoch and measures the jump
+                let output = flags.get("output").unwrap_or("./data/output/consistency");
+                let options = consistency::ConsistencyOptions {
+                    integrate: flags.has("integrate"),
+                    max_gap_days: flags.get_or("max-gap-days", 3.0).unwrap(),
+                    flag_sigma: flags.get_or("flag-sigma", 5.0).unwrap(),
+                    maneuvers: flags.get_or("maneuvers", maneuver::ManeuverHandling::Ignore).unwrap(),
+                    maneuver_sigma: flags.get_or("maneuver-sigma", MANEUVER_SIGMA).unwrap(),
+                    gaps: gap_limits(&flags),
+                };
+                let uses: &[data::DataUse] = if options.integrate { &[data::DataUse::Integration] } else { &[] };
+                let data = data_dir(&flags, uses)?;
+                let mut satellites = read_integration_tles(&flags, "./data/tle2024.txt")?;
+                satcat_tles(&flags, &mut satellites);
+                check_tle_epochs(&data, &satellites)?;
+                consistency::check_consistency(satellites, &options, output).unwrap();
+            }
+            "maneuvers" => { //flags maneuvers in each satellite's TLE history
+                let output = flags.get("output").unwrap_or("./data/output/maneuvers");
+                let sigma: f64 = flags.get_or("sigma", MANEUVER_SIGMA).unwrap();
+                let satellites = read_catalog(&flags, "./data/tle2024.txt");
+                maneuver::find_maneuvers(satellites, sigma, output).unwrap();
+            }
+            "conjunctions" => { //screens the catalog for close approaches over a time window
+                let output = flags.get("output").unwrap_or("./data/output/conjunctions");
+                let data = data_dir(&flags, &[])?;
+                let satellites = read_catalog(&flags, "./data/tle2024.txt");
+                let (start, stop) = time_window(&flags, &satellites);
+                data.check_epochs(&start, &stop)?;
+                let options = conjunction::ScreeningOptions {
+                    start,
+                    stop,
+                    threshold: flags.get_or("threshold", 5000.0).unwrap(),
+                    step: flags.get_or("step", 10.0).unwrap(),
+                };
+                let mut conjunctions = conjunction::screen(&satellites, &options).unwrap();
+
+                //Pc needs a covariance from the consistency check output, a default RTN sigma, or both
+                let source = covariance_source(&flags)?;
+                let hard_body_radius: f64 = flags.get_or("hard-body-radius", 20.0).unwrap();
+                let method = flags.get_or("pc-method", collision::PcMethod::Foster).unwrap();
+                collision::assess(&mut conjunctions, &source, hard_body_radius, method);
+
+                conjunction::write_conjunctions(&conjunctions, output).unwrap();
+            }
+            "groundtrack" => { //sub-satellite points over a time window, one file per satellite
+                let output = flags.get("output").unwrap_or("./data/output/groundtrack");
+                let data = data_dir(&flags, &[])?;
+                let satellites = read_catalog(&flags, "./data/tle2024.txt");
+                let (start, stop) = time_window(&flags, &satellites);
+                data.check_epochs(&start, &stop)?;
+                let options = frames::GroundTrackOptions {
+                    start,
+                    stop,
+                    step: flags.get_or("step", 60.0).unwrap(),
+                    ids: flags.get_list::<String>("ids").unwrap(),
+                };
+                frames::write_ground_tracks(&satellites, &options, output).unwrap();
+            }
+            "passes" => { //AOS/TCA/LOS of every satellite over a list of ground stations
+                let stations = passes::read_stations(flags.get("stations").unwrap_or("./data/stations.csv")).unwrap();
+                let output = flags.get("output").unwrap_or("./data/output/passes");
+                let data = data_dir(&flags, &[])?;
+                let satellites = read_catalog(&flags, "./data/tle2024.txt");
+                let (start, stop) = time_window(&flags, &satellites);
+                data.check_epochs(&start, &stop)?;
+                let options = passes::PassOptions { start, stop, step: flags.get_or("step", 30.0).unwrap() };
+                let found = passes::predict_passes(&satellites, &stations, &options).unwrap();
+                passes::write_passes(&found, output).unwrap();
+            }
+            "dataset" => { //reproducible train/val/test split by satellite
+                let output = flags.get("output").unwrap_or("./data/output/dataset");
+                let fractions = flags.get_list::<f64>("split")?.unwrap_or(vec![0.7, 0.15, 0.15]);
+                let options = dataset::DatasetOptions {
+                    seed: flags.get_or("seed", 0).unwrap(),
+                    fractions: dataset::split_fractions(&fractions)?,
+                    stratify: flags.has("stratify"),
+                    files: flags.get("files").map(|files| files.to_string()),
+                    move_files: flags.has("move"),
+                    satcat: flags.get("satcat").map(satcat::load_satcat).transpose()?,
+                };
+                let mut satellites = read_integration_tles(&flags, "./data/tle2024.txt")?;
+                if let Some(satcat) = &options.satcat {
+                    filter_by_satcat(&flags, &mut satellites, satcat);
+                }
+                dataset::build_dataset(satellites, &options, output).unwrap();
+            }
+            "decay" => { //reentry prediction with drag, validated against SATCAT decay dates with --satcat and --lead-days
+                let output = flags.get("output").unwrap_or("./data/output/decay");
+                //satkit's own SW-All.csv only without --space-weather, which is copied in next to the rest
+                let uses: &[data::DataUse] = if flags.has("space-weather") { &[data::DataUse::Integration] } else { &[data::DataUse::Integration, data::DataUse::Drag] };
+                let data = data_dir(&flags, uses)?; //before --space-weather, which is copied into it
+                let satellites = read_catalog(&flags, "./data/tle2024.txt");
+                let options = decay::DecayOptions {
+                    reentry_altitude: flags.get_or("reentry-altitude-km", 120.0).unwrap() * 1000.0,
+                    max_perigee_altitude: flags.get_or("max-perigee-km", 350.0).unwrap() * 1000.0,
+                    horizon_days: flags.get_or("horizon-days", 365.0).unwrap(),
+                    history_days: flags.get_or("history-days", 30.0).unwrap(),
+                    lead_days: flags.get_parsed("lead-days").unwrap(),
+                    space_weather: space_weather(&flags)?,
+                };
+                if let Some((first, last)) = catalog_epochs(&satellites) {
+                    data.check_epochs(&first, &(last + Duration::from_days(options.horizon_days)))?;
+                }
+                let predictions = decay::predict_decays(&satellites, &options).unwrap();
+                decay::write_predictions(&predictions, output).unwrap();
+            }
+            "fetch" => { //GP history from space-track, credentials from SPACETRACK_USERNAME and SPACETRACK_PASSWORD
+                let today = chrono::Utc::now().timestamp().div_euclid(86400) * 86400;
+                let end = flags.get_instant("end").unwrap().map(|end| end.as_unixtime() as i64).unwrap_or(today);
+                let all_orbits = flags.has("all-orbits"); //otherwise only the LEO orbits the readers keep
+                let options = spacetrack::FetchOptions {
+                    base_url: flags.get("base-url").unwrap_or(spacetrack::SPACE_TRACK_URL).trim_end_matches('/').to_string(),
+                    identity: env::var("SPACETRACK_USERNAME").map_err(|_| anyhow::anyhow!("SPACETRACK_USERNAME is not set"))?,
+                    password: env::var("SPACETRACK_PASSWORD").map_err(|_| anyhow::anyhow!("SPACETRACK_PASSWORD is not set"))?,
+                    start: flags.get_instant("start").unwrap().map(|start| start.as_unixtime() as i64).unwrap_or(end - 7305 * 86400), //20 years
+                    end,
+                    window_days: flags.get_or("window-days", 4).unwrap(),
+                    max_eccentricity: (!all_orbits).then_some(flags.get_or("max-eccentricity", read::LEO_MAX_ECCENTRICITY).unwrap()),
+                    min_mean_motion: (!all_orbits).then_some(flags.get_or("min-mean-motion", read::LEO_MIN_MEAN_MOTION).unwrap()),
+                    format: flags.get_or("format", spacetrack::OutputFormat::Tle).unwrap(),
+                    output_dir: flags.get("output").unwrap_or("./data/fetched").to_string(), //kept apart from the catalogs already in ./data
+                    rate_limits: vec![(30, 60.0), (300, 3600.0)],
+                    retries: 5,
+                    retry_delay: 3.0,
+                };
+                let summary = spacetrack::fetch(&options).unwrap();
+                println!("Fetched {} records in {} windows", summary.records, summary.windows);
+            }
+            "store" => { //persistent catalog keyed by satellite and epoch, "store append" or "store query"
+                let mut store = catalog_store::CatalogStore::open(flags.get("store").unwrap_or("./data/store")).unwrap();
+                match args.get(2).map(|s| s.as_str()) {
+                    Some("append") => {
+                        let satellites = read_catalog(&flags, "./data/tle2024.txt");
+                        let summary = store.append(&satellites).unwrap();
+                        println!("{} satellites, {} new element sets, {} already stored", summary.satellites, summary.added, summary.duplicates);
+                        println!("Store holds {} element sets of {} satellites", store.element_sets(), store.len());
+                    }
+                    Some("query") => {
+                        let ids = flags.get_list::<String>("ids").unwrap();
+                        let satellites = store.query(ids.as_deref(), flags.get_instant("start").unwrap(), flags.get_instant("stop").unwrap()).unwrap();
+                        let output = flags.get("output").unwrap_or("./data/output/store_query.txt");
+                        if let Some(parent) = std::path::Path::new(output).parent() {
+                            std::fs::create_dir_all(parent).unwrap();
+                        }
+                        let mut writer = std::io::BufWriter::new(std::fs::File::create(output).unwrap());
+                        let mut ids: Vec<&String> = satellites.keys().collect();
+                        ids.sort();
+                        for id in ids {
+                            let record = &satellites[id];
+                            for instance in record.orbital_records.iter() {
+                                numerical_integration::write_tle_data(&mut writer, &instance.to_tle(record.catalog_number, &record.international_designator)).unwrap();
+                            }
+                        }
+                        std::io::Write::flush(&mut writer).unwrap();
+                        println!("Wrote {} satellites to {}", satellites.len(), output);
+                    }
+                    _ => println!("store needs append or query"),
+                }
+            }
+            "data" => { //"data verify" checks satkit's data files for offline runs, optionally against --start and --stop
+                match args.get(2).map(|s| s.as_str()) {
+                    Some("verify") => {
+                        let epochs = match (flags.get_instant("start")?, flags.get_instant("stop")?) {
+                            (Some(start), Some(stop)) => Some((start, stop)),
+                            (None, None) => None,
+                            _ => anyhow::bail!("--start and --stop go together"),
+                        };
+                        data::verify(flags.get("data-dir"), epochs)?;
+                    }
+                    _ => println!("data needs verify"),
+                }
+            }
+            _ => println!("Did not recognize commands"),
+        }
+    } else { 
+        println!("Need args")
+    }
+    Ok(())
+}
+
+//the TLEs of input_files for the integrating modes, read with at most --threads files at once
+fn read_integration_tles(flags: &cli::Flags, default: &str) -> anyhow::Result<HashMap<String, Vec<satkit::TLE>>> {
+    read::read_txt_for_integration(&input_files(flags, default)?, flags.get_parsed("threads")?)
+}
+
+//the catalog of input_files, read with at most --threads files and chunks at once
+fn read_catalog(flags: &cli::Flags, default: &str) -> HashMap<String, satellite::SatelliteRecord> {
+    let inputs = input_files(flags, default).unwrap();
+    let mut satellites = read::read_many(&inputs, flags.get_parsed("threads").unwrap()).unwrap();
+    if let Some(path) = flags.get("satcat") { //e.g. --satcat satcat.csv --object-types payload,rocket_body
+        let satcat = satcat::load_satcat(path).unwrap();
+        let (matched, dropped) = satcat::attach(&mut satellites, &satcat);
+        println!("{} satellites found in the SATCAT, {} element sets after decay dropped", matched, dropped);
+        if let Some(types) = flags.get_list::<satcat::ObjectType>("object-types").unwrap() {
+            satellites.retain(|id, _| satcat::has_type(&satcat, id, &types));
+            println!("{} satellites of the wanted types", satellites.len());
+        }
+    }
+    satellites
+}
+
+//--years first,last (inclusive, e.g. 6,7 or 2006,2007) or --input with comma separated files, directories or globs
+fn input_files(flags: &cli::Flags, default: &str) -> anyhow::Result<Vec<String>> {
+    match flags.get_list::<u16>("years")? {
+        Some(years) if years.len() == 2 => Ok(read::year_files(years[0], years[1])),
+        Some(years) => anyhow::bail!("--years takes the first and last year: first,last, got {} values", years.len()),
+        None => Ok(flags.get_list::<String>("input")?.unwrap_or(vec![default.to_string()])),
+    }
+}
+
+//--data-dir with satkit's data files (EOP, IERS tables, gravity, space weather) so nothing is downloaded,
+//--eop-strict to refuse epochs that only have predicted EOP, only the files for the mode's uses (and the frames) have to be there
+fn data_dir(flags: &cli::Flags, uses: &[data::DataUse]) -> anyhow::Result<data::DataDir> {
+    data::DataDir::open(flags.get("data-dir"), flags.has("eop-strict"), uses)
+}
+
+fn check_tle_epochs(data: &data::DataDir, satellites: &HashMap<String, Vec<satkit::TLE>>) -> anyhow::Result<()> {
+    let epochs = satellites.values().flat_map(|tles| tles.iter().map(|tle| tle.epoch));
+    if let Some((first, last)) = epoch_range(epochs) {
+        data.check_epochs(&first, &last)?;
+    }
+    Ok(())
+}
+
+fn catalog_epochs(satellites: &HashMap<String, satellite::SatelliteRecord>) -> Option<(Instant, Instant)> {
+    epoch_range(satellites.values().flat_map(|record| record.orbital_records.iter().map(|instance| instance.epoch())))
+}
+
+fn epoch_range(epochs: impl Iterator<Item = Instant>) -> Option<(Instant, Instant)> {
+    epochs.fold(None, |range, epoch| match range {
+        None => Some((epoch, epoch)),
+        Some((first, last)) => Some((if epoch < first { epoch } else { first }, if epoch > last { epoch } else { last })),
+    })
+}
+
+//--min-gap-minutes, --max-gap-days and --cap-days, shared by every mode that integrates between TLEs
+fn gap_limits(flags: &cli::Flags) -> numerical_integration::GapLimits {
+    numerical_integration::GapLimits {
+        min_seconds: flags.get_or("min-gap-minutes", 30.0).unwrap() * 60.0,
+        max_seconds: flags.get_parsed::<f64>("max-gap-days").unwrap().map(|days| days * 86400.0),
+        cap_seconds: flags.get_parsed::<f64>("cap-days").unwrap().map(|days| days * 86400.0),
+    }
+}
+
+//--space-weather with a CelesTrak SW-All.csv, handed to satkit's density model before anything is propagated
+fn space_weather(flags: &cli::Flags) -> anyhow::Result<Option<space_weather::SpaceWeather>> {
+    let Some(path) = flags.get("space-weather") else {
+        return Ok(None);
+    };
+    let mut space_weather = space_weather::SpaceWeather::load(path)?;
+    space_weather.install()?;
+    Ok(Some(space_weather))
+}
+
+//--covariance with the consistency check's covariance.csv, --sigma-rtn r,t,n (m) for objects it doesn't cover
+fn covariance_source(flags: &cli::Flags) -> anyhow::Result<collision::CovarianceSource> {
+    let default_sigma = match flags.get_list::<f64>("sigma-rtn")? {
+        Some(sigma) if sigma.len() == 3 => Some(satkit::types::Vector3::new(sigma[0], sigma[1], sigma[2])),
+        Some(sigma) => anyhow::bail!("--sigma-rtn takes three values: r,t,n, got {}", sigma.len()),
+        None => None,
+    };
+    collision::CovarianceSource::new(flags.get("covariance"), default_sigma)
+}
+
+//--satcat for the modes that work on plain TLE lists
+fn satcat_tles(flags: &cli::Flags, satellites: &mut HashMap<String, Vec<satkit::TLE>>) {
+    if let Some(path) = flags.get("satcat") {
+        filter_by_satcat(flags, satellites, &satcat::load_satcat(path).unwrap());
+    }
+}
+
+fn filter_by_satcat(flags: &cli::Flags, satellites: &mut HashMap<String, Vec<satkit::TLE>>, satcat: &HashMap<String, satcat::SatcatEntry>) {
+    let types = flags.get_list::<satcat::ObjectType>("object-types").unwrap();
+    let dropped = satcat::filter_tles(satellites, satcat, types.as_deref());
+    println!("{} satellites kept, {} element sets after decay dropped", satellites.len(), dropped);
+}
+
+//--start and --stop (or --hours after the start), starting by default at the newest epoch in the catalog
+fn time_window(flags: &cli::Flags, satellites: &HashMap<String, satellite::SatelliteRecord>) -> (Instant, Instant) {
+    let start = match flags.get_instant("start").unwrap() {
+        Some(start) => start,
+        None => satellites.values()
+            .flat_map(|record| record.orbital_records.iter().map(|instance| instance.epoch()))
+            .max_by(|a, b| a.partial_cmp(b).unwrap())
+            .expect("No satellites in the catalog"),
+    };
+    let stop = match flags.get_instant("stop").unwrap() {
+        Some(stop) => stop,
+        None => start + Duration::from_hours(flags.get_or("hours", 24.0).unwrap()),
+    };
+    (start, stop)
+}
@@ -0,0 +1,196 @@
+use std::{collections::HashMap, fs::{self, File}, io::{BufWriter, Write}, path::Path, str::FromStr};
+use rayon::prelude::*;
+use satkit::{consts::MU_EARTH, Instant};
+use anyhow::{bail, Result};
+use crate::analysis::median;
+use crate::consistency::MAD_TO_SIGMA;
+use crate::satellite::{OrbitalInstance, SatelliteRecord};
+
+const MIN_RECORDS_FOR_DETECTION: usize = 10;
+
+//lower bounds on the robust spread of each quantity, so quiet objects with near identical element sets don't flag noise
+const MIN_SIGMA_A: f64 = 20.0; //m
+const MIN_SIGMA_I: f64 = 1.0e-3; //deg
+const MIN_SIGMA_E: f64 = 1.0e-5;
+const MIN_SIGMA_NDOT: f64 = 1.0e-6; //rev/day^2
+
+//what the integrator does with a gap that contains a detected maneuver
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum ManeuverHandling {
+    Ignore, //integrate straight through it, like before
+    Skip, //drop the gap entirely
+    Split, //integrate forward from the earlier TLE and backward from the later TLE, meeting in the middle of the gap
+}
+
+impl FromStr for ManeuverHandling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ignore" => Ok(ManeuverHandling::Ignore),
+            "skip" => Ok(ManeuverHandling::Skip),
+            "split" => Ok(ManeuverHandling::Split),
+            _ => bail!("Unknown maneuver handling {s}, expected ignore, skip or split"),
+        }
+    }
+}
+
+pub(crate) struct Maneuver {
+    pub(crate) index: usize, //the maneuver happened between orbital record index and index + 1
+    pub(crate) epoch_before: Instant,
+    pub(crate) epoch_after: Instant,
+    pub(crate) delta_a: f64, //m, beyond what the mean motion derivative predicts
+    pub(crate) delta_i: f64, //deg
+    pub(crate) delta_e: f64,
+    pub(crate) delta_ndot: f64, //rev/day^2
+    pub(crate) delta_v: f64, //m/s, rough estimate from the element changes
+}
+
+//changes between two consecutive element sets
+struct ElementJump {
+    delta_a: f64,
+    delta_i: f64,
+    delta_e: f64,
+    delta_ndot: f64,
+}
+
+//flags consecutive element sets whose change in semi-major axis, inclination, eccentricity or mean motion derivative
+//is more than `sigma` robust standard deviations away from the satellite's typical change
+//records must be sorted by epoch
+pub(crate) fn detect_maneuvers(records: &[OrbitalInstance], sigma: f64) -> Vec<Maneuver> {
+    if records.len() < MIN_RECORDS_FOR_DETECTION {
+        return Vec::new();
+    }
+
+    let jumps: Vec<ElementJump> = records.windows(2).map(|window| element_jump(&window[0], &window[1])).collect();
+
+    let limit = |values: Vec<f64>, min_sigma: f64| -> (f64, f64) {
+        let center = median(values.clone());
+        let mad = median(values.iter().map(|v| (v - center).abs()).collect());
+        (center, sigma * (MAD_TO_SIGMA * mad).max(min_sigma))
+    };
+    let (a_center, a_limit) = limit(jumps.iter().map(|j| j.delta_a).collect(), MIN_SIGMA_A);
+    let (i_center, i_limit) = limit(jumps.iter().map(|j| j.delta_i).collect(), MIN_SIGMA_I);
+    let (e_center, e_limit) = limit(jumps.iter().map(|j| j.delta_e).collect(), MIN_SIGMA_E);
+    let (ndot_center, ndot_limit) = limit(jumps.iter().map(|j| j.delta_ndot).collect(), MIN_SIGMA_NDOT);
+
+    jumps.iter()
+        .enumerate()
+        .filter(|(_, jump)| {
+            (jump.delta_a - a_center).abs() > a_limit
+                || (jump.delta_i - i_center).abs() > i_limit
+                || (jump.delta_e - e_center).abs() > e_limit
+                || (jump.delta_ndot - ndot_center).abs() > ndot_limit
+        })
+        .map(|(index, jump)| Maneuver {
+            index,
+            epoch_before: records[index].epoch(),
+            epoch_after: records[index + 1].epoch(),
+            delta_a: jump.delta_a,
+            delta_i: jump.delta_i,
+            delta_e: jump.delta_e,
+            delta_ndot: jump.delta_ndot,
+            delta_v: estimate_delta_v(&records[index], jump),
+        })
+        .collect()
+}
+
+fn element_jump(before: &OrbitalInstance, after: &OrbitalInstance) -> ElementJump {
+    //the TLE first derivative field is half the rate of change of mean motion, in rev/day^2
+    let dt_days = (after.epoch() - before.epoch()).as_days();
+    let predicted_mean_motion = before.mean_motion + 2.0 * before.first_time_derivative * dt_days;
+    let n = predicted_mean_motion.max(f64::MIN_POSITIVE);
+    //a ~ n^(-2/3), so an unexplained change in mean motion maps to a change in semi-major axis
+    let delta_a = -2.0 / 3.0 * before.semi_major_axis() * (after.mean_motion - predicted_mean_motion) / n;
+
+    ElementJump {
+        delta_a,
+        delta_i: after.inclination - before.inclination,
+        delta_e: after.eccentricity - before.eccentricity,
+        delta_ndot: after.first_time_derivative - before.first_time_derivative,
+    }
+}
+
+//in-plane change from the larger of the semi-major axis and eccentricity changes of a tangential burn, combined with the plane change
+fn estimate_delta_v(before: &OrbitalInstance, jump: &ElementJump) -> f64 {
+    let a = before.semi_major_axis();
+    let v = (MU_EARTH / a).sqrt();
+    let in_plane = (v / (2.0 * a) * jump.delta_a.abs()).max(v / 2.0 * jump.delta_e.abs());
+    let plane_change = 2.0 * v * (jump.delta_i.abs().to_radians() / 2.0).sin();
+    (in_plane.powi(2) + plane_change.powi(2)).sqrt()
+}
+
+pub(crate) fn find_maneuvers(mut satellites: HashMap<String, SatelliteRecord>, sigma: f64, output_dir: &str) -> Result<()> {
+    fs::create_dir_all(output_dir)?;
+
+    let mut results: Vec<(String, Vec<Maneuver>)> = satellites.par_iter_mut()
+        .map(|(id, record)| {
+            record.sort_by_epoch();
+            (id.clone(), detect_maneuvers(&record.orbital_records, sigma))
+        })
+        .collect();
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let file = File::create(Path::new(output_dir).join("maneuvers.csv"))?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "id,epoch_before,epoch_after,delta_a_m,delta_i_deg,delta_e,delta_ndot,delta_v_mps")?;
+    let mut count = 0;
+    for (id, maneuvers) in results.iter() {
+        for maneuver in maneuvers {
+            writeln!(writer, "{},{},{},{},{},{},{},{}",
+                id, maneuver.epoch_before, maneuver.epoch_after, maneuver.delta_a, maneuver.delta_i,
+                maneuver.delta_e, maneuver.delta_ndot, maneuver.delta_v)?;
+            count += 1;
+        }
+    }
+    writer.flush()?;
+    println!("Found {} maneuvers across {} satellites", count, results.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //a slowly decaying orbit tracked daily, with a 2 km raise between the 12th and 13th element sets
+    fn history(raise_after: Option<usize>) -> Vec<OrbitalInstance> {
+        let decay = -1.0e-4; //rev/day^2, half of it goes in the TLE derivative field
+        (0..20).map(|i| {
+            let noise = if i % 2 == 0 { 2.0e-6 } else { -2.0e-6 };
+            let raise = match raise_after {
+                Some(index) if i > index => -0.0068, //rev/day, dn/n = -3/2 da/a at a = 6.8e6 m
+                _ => 0.0,
+            };
+            OrbitalInstance {
+                epoch_year: 2024,
+                epoch_day: 10.0 + i as f64,
+                first_time_derivative: decay / 2.0,
+                second_time_derivative: 0.0,
+                drag: 1.0e-4,
+                inclination: 51.6 + noise,
+                raan: 100.0,
+                eccentricity: 5.0e-4,
+                perigee: 90.0,
+                mean_anomaly: 0.0,
+                mean_motion: 15.5 + decay * i as f64 + noise + raise,
+                element_set_number: i as i32,
+                revolution_number: 0,
+                ephemeris_type: 0,
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn finds_a_semi_major_axis_raise() {
+        let maneuvers = detect_maneuvers(&history(Some(11)), 5.0);
+        assert_eq!(maneuvers.len(), 1);
+        let maneuver = &maneuvers[0];
+        assert_eq!(maneuver.index, 11);
+        assert_eq!((maneuver.epoch_before, maneuver.epoch_after), (history(None)[11].epoch(), history(None)[12].epoch()));
+        assert!((maneuver.delta_a - 2000.0).abs() < 100.0, "delta a {}", maneuver.delta_a);
+        assert!(maneuver.delta_v > 0.5 && maneuver.delta_v < 2.0, "delta v {}", maneuver.delta_v); //about 1.1 m/s for a tangential burn
+
+        assert!(detect_maneuvers(&history(None), 5.0).is_empty());
+        assert!(detect_maneuvers(&history(Some(4))[..9], 5.0).is_empty()); //too short a history to tell
+    }
+}
@@ -1,97 +1,233 @@
-use std::{collections::{HashMap, VecDeque}, fs::File, io::{BufWriter, Write}};
+use std::{collections::{HashMap, HashSet}, fmt, fs::{read_to_string, File}, io::{BufWriter, Write}, str::FromStr};
 use rayon::prelude::*;
-use satkit::{frametransform::qteme2gcrf, orbitprop::{propagate, PropSettings, PropagationResult, SatState}, sgp4::sgp4, types::Vector3, Duration, Instant, TLE};
-use anyhow::Result;
-use nalgebra::SVector;
+use satkit::{orbitprop::{propagate, PropSettings, PropagationResult, SatProperties, SatState, StateCov}, sgp4::sgp4, types::{Matrix6, Vector3}, Duration, Instant, TLE};
+use anyhow::{anyhow, bail, Result};
+use nalgebra::{SMatrix, SVector};
 use std::mem;
 use zstd::Encoder;
 use chrono::{Datelike, TimeZone, Timelike, Utc};
+use crate::cli::parse_instant;
+use crate::collision::CovarianceSource;
+use crate::elements::cartesian_to_keplerian;
+use crate::frames::{convert, Frame, gcrf_to_itrf, geodetic, teme_to_gcrf, OutputFrames, HEADER_PREFIX};
+use crate::maneuver::{detect_maneuvers, ManeuverHandling};
+use crate::satellite::OrbitalInstance;
+use crate::space_weather::{drag_properties, SpaceWeather};
+use crate::training::{write_metadata, SampleOptions, SampleStats, SampleWriter};
+
+const MIN_GAP_SECONDS: f64 = 60.0*30.0; //gaps shorter than this are TLEs updated too frequently to propagate in between
+
+//which gaps between consecutive TLEs get integrated and how far
+#[derive(Clone, Copy)]
+pub(crate) struct GapLimits {
+    pub(crate) min_seconds: f64, //shorter gaps are skipped
+    pub(crate) max_seconds: Option<f64>, //longer gaps are skipped, the TLE says little about the orbit that far out
+    pub(crate) cap_seconds: Option<f64>, //integration stops this long after the start TLE even if the next one is further away
+}
+
+impl Default for GapLimits {
+    fn default() -> Self {
+        GapLimits { min_seconds: MIN_GAP_SECONDS, max_seconds: None, cap_seconds: None }
+    }
+}
+
+impl GapLimits {
+    //None when a gap of dt seconds (either direction) should be integrated
+    fn skip_reason(&self, dt: f64) -> Option<SkipReason> {
+        let dt = dt.abs();
+        if dt == 0.0 || dt < self.min_seconds {
+            return Some(SkipReason::TooShort);
+        }
+        match self.max_seconds {
+            Some(max) if dt > max => Some(SkipReason::TooLong),
+            _ => None,
+        }
+    }
+
+    //where integration from start towards stop ends once the cap is applied
+    fn capped_stop(&self, start: &Instant, stop: &Instant) -> Instant {
+        let dt: f64 = (stop - start).as_seconds();
+        match self.cap_seconds {
+            Some(cap) if dt.abs() > cap => *start + Duration::from_seconds(cap * dt.signum()),
+            _ => *stop,
+        }
+    }
+}
+
+enum SkipReason {
+    TooShort,
+    TooLong,
+}
+
+//one stretch of integration, labelled with the TLE it starts from and the epochs it runs between
+//so the output never has to rely on the order results come back in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Segment {
+    pub(crate) tle_index: usize, //TLE (and GCRF state) the integration starts from
+    pub(crate) start_epoch_index: usize, //epoch the segment starts at, always the TLE's own
+    pub(crate) end_epoch_index: usize, //epoch it runs towards, the previous one for the backward half of a split gap
+    pub(crate) start: Instant,
+    pub(crate) stop: Instant, //earlier than the end epoch when the integration cap cut it short
+}
+
+//what happened to every gap in a run, summed over satellites
+#[derive(Default, Clone)]
+pub(crate) struct RunReport {
+    pub(crate) satellites: usize,
+    pub(crate) gaps: usize,
+    pub(crate) integrated: usize,
+    pub(crate) capped: usize, //integrated, but stopped short of the next TLE
+    pub(crate) skipped_short: usize,
+    pub(crate) skipped_long: usize,
+    pub(crate) skipped_maneuver: usize,
+    pub(crate) split_maneuver: usize,
+    pub(crate) samples: SampleStats, //training samples written, empty unless they were asked for
+}
+
+impl RunReport {
+    fn merge(&mut self, other: &RunReport) {
+        self.satellites += other.satellites;
+        self.gaps += other.gaps;
+        self.integrated += other.integrated;
+        self.capped += other.capped;
+        self.skipped_short += other.skipped_short;
+        self.skipped_long += other.skipped_long;
+        self.skipped_maneuver += other.skipped_maneuver;
+        self.split_maneuver += other.split_maneuver;
+        self.samples.merge(&other.samples);
+    }
 
-pub(crate) fn integrate(map: HashMap<String, Vec<TLE>>, density:u16, compression_level:i32) -> Result<()> { //integration using streaming to upload to S3 and save space on device
+    fn skip(&mut self, reason: SkipReason) {
+        match reason {
+            SkipReason::TooShort => self.skipped_short += 1,
+            SkipReason::TooLong => self.skipped_long += 1,
+        }
+    }
+
+    fn print(&self) {
+        println!("Satellites: {}, gaps: {}", self.satellites, self.gaps);
+        println!("Integrated: {} ({} capped), split at maneuvers: {}", self.integrated, self.capped, self.split_maneuver);
+        println!("Skipped: {} too short, {} too long, {} with maneuvers", self.skipped_short, self.skipped_long, self.skipped_maneuver);
+    }
+
+    fn write(&self, writer: &mut impl Write) -> Result<()> {
+        writeln!(writer, "satellites,gaps,integrated,capped,split_maneuver,skipped_short,skipped_long,skipped_maneuver")?;
+        writeln!(writer, "{},{},{},{},{},{},{},{}", self.satellites, self.gaps, self.integrated, self.capped,
+            self.split_maneuver, self.skipped_short, self.skipped_long, self.skipped_maneuver)?;
+        Ok(())
+    }
+}
+
+pub(crate) struct IntegrationOptions {
+    pub(crate) sampling: Sampling,
+    pub(crate) compression_level: i32,
+    pub(crate) maneuvers: ManeuverHandling,
+    pub(crate) maneuver_sigma: f64,
+    pub(crate) frames: OutputFrames,
+    pub(crate) gaps: GapLimits,
+    pub(crate) samples: Option<SampleOptions>, //also writes (TLE, tsince, truth, SGP4) training samples for ML-dSGP4
+    pub(crate) drag: Option<SpaceWeather>, //integrates with drag from each TLE's B*, the density model fed from this space weather
+    pub(crate) covariance: Option<CovarianceSource>, //propagates a covariance seeded from this with each segment's state transition matrix
+}
+
+pub(crate) fn integrate(map: HashMap<String, Vec<TLE>>, options: &IntegrationOptions) -> Result<()> { //integration using streaming to upload to S3 and save space on device
     println!("Converting to SatStates");
     let time = std::time::Instant::now();
-    let map: HashMap<String, (Vec<TLE>, Vec<SatState>)> = convert_map_to_gcrf(map)?;
+    let map: HashMap<String, GcrfRecords> = convert_map_to_gcrf(map)?;
     println!("Converted in {}", time.elapsed().as_secs_f64());
 
-    let mut settings = PropSettings::default();
-    settings.gravity_order = 8;
+    let settings = integration_settings();
 
     println!("Starting Numerical Integration Process");
     let time = std::time::Instant::now();
-    let _integration_results = parallel_stream_integration(map, &settings, density, compression_level)?; //integrates satellites in parallel and saves to a .txt file in a streaming fashion (compressed with zstandard)
+    let report = parallel_stream_integration(map, &settings, options)?; //integrates satellites in parallel and saves to a .txt file in a streaming fashion (compressed with zstandard)
     println!("Integrated in {}", time.elapsed().as_secs_f64());
+    report.print();
+    let mut report_writer = BufWriter::new(create_file(&"integration_report.csv".to_string())?);
+    report.write(&mut report_writer)?;
+    report_writer.flush()?;
+    if let Some(samples) = &options.samples {
+        write_metadata(samples, &report.samples)?;
+    }
 
     Ok(())
 }
 
-fn stream(records: (Vec<TLE>, Vec<SatState>), settings: &PropSettings, id: &String, density:u16, max_vec_size: usize, compression_level: i32) -> Result<()>{
+pub(crate) fn integration_settings() -> PropSettings {
+    PropSettings {
+        gravity_order: 8,
+        ..Default::default()
+    }
+}
+
+fn stream(records: GcrfRecords, settings: &PropSettings, id: &String, options: &IntegrationOptions, max_vec_size: usize) -> Result<RunReport>{
     let (tles, states) = records;
-    let results = integrate_between_gaps(states, settings)?; //this generates a propagation result object in between every SatState (instance in time)
+    let (results, mut report) = integrate_between_gaps(id, &tles, &states, settings, options)?; //this generates a propagation result object for every segment between SatStates (instances in time), each labelled with the TLE it starts from
     
-    let mut time_batches: Vec<Vec<SatState>> = Vec::new(); //stores each "step"
+    let mut time_batches: Vec<(Segment, Vec<SatState>)> = Vec::new(); //stores each segment with its "steps"
     let filename: String = format!("integration_{}.txt.zst", &id);
     let file: File = create_file(&filename)?;
     let writer: BufWriter<File> = BufWriter::new(file);
-    let mut encoder: Encoder<'static, BufWriter<File>> = Encoder::new(writer, compression_level)?; //used to compress data with
-
-    let mut tle_iter = tles.into_iter(); //consuming iterator for TLEs
-    let mut queue: VecDeque<TLE> = VecDeque::new(); //used to store TLE order before writing to buffer
-
-    for result in results.into_iter() { //this then uses the propagation results generated to save n = density instances in time between each interval
-        let start: Instant = result.time_start;                                       //each instance contains the time, position, and velocity of the satellite (i.e they're all SatStates)
-        let end: Instant = result.time_end;
-        let dt: Duration = end -  result.time_start;
-
-        let current_tle = tle_iter.next().unwrap(); //writes the TLE lines for this specific instance, to then be used in training (as needed by the DSGP4 model)
-        queue.push_back(current_tle);
-
-        let interval: f64 = dt.as_seconds() / density as f64;
-        let mut steps: Vec<SatState> = Vec::new();
-        for j in 0..density {
-            let shift: f64 = interval * j as f64;
-            let time: Instant = start + Duration::from_seconds(shift);
-            let matrix_at_time = result.interp(&time).unwrap();
-            let state_at_time: SatState = make_sat_state(time, matrix_at_time);
-            steps.push(state_at_time);
+    let mut encoder: Encoder<'static, BufWriter<File>> = Encoder::new(writer, options.compression_level)?; //used to compress data with
+    writeln!(encoder, "{} sampling={}", options.frames.header(), options.sampling)?; //tells readers which frame the states are in and how they were sampled
+    let mut sample_writer: Option<SampleWriter> = options.samples.as_ref().map(|samples| SampleWriter::create(samples, id)).transpose()?;
+
+    for (segment, result) in results.into_iter() { //this then uses the propagation results generated to save the sampled instances in time between each interval
+        let steps: Vec<SatState> = result.sample(&options.sampling, tles[segment.tle_index].mean_motion)?; //each instance contains the time, position, and velocity of the satellite (i.e they're all SatStates)
+        if let Some(sample_writer) = sample_writer.as_mut() {
+            sample_writer.write(&tles[segment.tle_index], &steps)?;
         }
-        steps.push(make_sat_state(end, result.state_end));
-        time_batches.push(steps);
+        time_batches.push((segment, steps));
 
-        let vec_size_in_bytes = (time_batches.len() * mem::size_of::<Vec<SatState>>()) + time_batches.iter()
-        .map(|batch| batch.capacity() * mem::size_of::<SatState>()).sum::<usize>();
-        let queue_size_in_bytes = queue.len() * mem::size_of::<TLE>();
-        let total_size_in_bytes = vec_size_in_bytes + queue_size_in_bytes;
+        let total_size_in_bytes = (time_batches.len() * mem::size_of::<(Segment, Vec<SatState>)>()) + time_batches.iter()
+        .map(|(_, batch)| batch.capacity() * mem::size_of::<SatState>()).sum::<usize>();
 
         if total_size_in_bytes > max_vec_size { //we flush the data generated to a new file
-            let _ = dump_data_batches(&mut encoder, &mut queue, &time_batches);
+            dump_data_batches(&mut encoder, &tles, &time_batches, &options.frames)?;
             time_batches.clear();
         }
     }
     if !time_batches.is_empty() {
-        let _ = dump_data_batches(&mut encoder, &mut queue, &time_batches);
+        dump_data_batches(&mut encoder, &tles, &time_batches, &options.frames)?;
     }
 
     let mut inner_writer = encoder.finish()?;
-    let _flush = inner_writer.flush()?;
+    inner_writer.flush()?;
+    if let Some(sample_writer) = sample_writer {
+        report.samples = sample_writer.finish()?;
+    }
     // let destination: String = format!("/mnt/IronWolfPro8TB/SWARM/data/output/raw/{}", &filename);
     // let _ = move_file(&filename, &destination);
 
-    Ok(())
+    Ok(report)
 }
 
-fn dump_data_batches(encoder: &mut Encoder<'static, BufWriter<File>>, queue: &mut VecDeque<TLE>, batches: &Vec<Vec<SatState>>) {
-    for batch in batches {
-        let corresponding_tle = queue.pop_front().unwrap();
-        let _ = write_tle_data(encoder, &corresponding_tle);
-        let _ = write_time_steps_zstd(encoder, &batch);
+//each segment is written as a "#segment" line, the TLE it was integrated from (as needed by the DSGP4 model) and its steps
+fn dump_data_batches<W: Write>(writer: &mut W, tles: &[TLE], batches: &[(Segment, Vec<SatState>)], frames: &OutputFrames) -> Result<()> {
+    for (segment, batch) in batches {
+        if batch.is_empty() { //epochs sampling can leave a gap with nothing in it
+            continue;
+        }
+        let corresponding_tle = &tles[segment.tle_index];
+        writeln!(writer, "{}segment tle_index={} start_epoch_index={} end_epoch_index={}",
+            HEADER_PREFIX, segment.tle_index, segment.start_epoch_index, segment.end_epoch_index)?;
+        write_tle_data(writer, corresponding_tle)?;
+        //RTN steps are written relative to what SGP4 gives for the same TLE at the same times
+        let references: Option<Vec<SatState>> = if frames.frame == Frame::Rtn {
+            let times: Vec<Instant> = batch.iter().map(|step| step.time).collect();
+            Some(sgp4_gcrf(&mut corresponding_tle.clone(), &times))
+        } else {
+            None
+        };
+        write_time_steps(writer, batch, frames, references.as_deref())?;
     }
+    Ok(())
 }
 
-
-fn write_time_steps_zstd(encoder: &mut Encoder<'static, BufWriter<File>>, steps: &Vec<SatState>) -> Result<()> {
-    for step in steps.into_iter() {
-        let formatted_step: String = format_step(&step);
-        let _ = writeln!(encoder, "{}", formatted_step);
+fn write_time_steps<W: Write>(writer: &mut W, steps: &[SatState], frames: &OutputFrames, references: Option<&[SatState]>) -> Result<()> {
+    for (i, step) in steps.iter().enumerate() {
+        let formatted_step: String = format_step(step, frames, references.map(|r| &r[i]))?;
+        writeln!(writer, "{}", formatted_step)?;
     }
     Ok(())
 }
@@ -102,79 +238,297 @@ fn create_file(filename: &String) -> Result<File> {
     Ok(file)
 }
 
-fn format_step(step: &SatState) -> String {
+fn format_step(step: &SatState, frames: &OutputFrames, reference: Option<&SatState>) -> Result<String> {
     let time = step.time.as_unixtime();
-    let pos_gcrf = step.pos_gcrf();
-    let vel_gcrf = step.vel_gcrf();
-    let formatted_step = format!("{},{},{},{},{},{},{}", time, pos_gcrf[0], pos_gcrf[1], pos_gcrf[2], vel_gcrf[0], vel_gcrf[1], vel_gcrf[2]);
-    return formatted_step;
+    let (pos, vel) = convert(step, frames.frame, reference)?;
+    let mut formatted_step = format!("{},{},{},{},{},{},{}", time, pos[0], pos[1], pos[2], vel[0], vel[1], vel[2]);
+    if frames.itrf || frames.geodetic { //earth fixed and geodetic columns are appended only when requested
+        let (pos_itrf, vel_itrf) = gcrf_to_itrf(step);
+        if frames.itrf {
+            formatted_step.push_str(&format!(",{},{},{},{},{},{}", pos_itrf[0], pos_itrf[1], pos_itrf[2], vel_itrf[0], vel_itrf[1], vel_itrf[2]));
+        }
+        if frames.geodetic {
+            let (latitude, longitude, altitude) = geodetic(&pos_itrf);
+            formatted_step.push_str(&format!(",{},{},{}", latitude, longitude, altitude));
+        }
+    }
+    if let Some(elements) = frames.elements {
+        for element in elements.elements_of(&step.pos_gcrf(), &step.vel_gcrf()) {
+            formatted_step.push_str(&format!(",{}", element));
+        }
+    }
+    if frames.derived {
+        let derived = cartesian_to_keplerian(&step.pos_gcrf(), &step.vel_gcrf()).derived();
+        formatted_step.push_str(&format!(",{},{},{}", derived.perigee_altitude, derived.apogee_altitude, derived.period));
+    }
+    if let Some(covariance) = frames.covariance {
+        for value in covariance.values(step) {
+            formatted_step.push_str(&format!(",{}", value));
+        }
+    }
+    Ok(formatted_step)
+}
+
+//how the states written for each gap are spaced in time
+#[derive(Clone)]
+pub(crate) enum Sampling {
+    FixedStep(f64), //every N seconds from the start of the gap, plus its end point
+    FixedCount(u16), //N evenly spaced points plus the end point, regardless of the gap length
+    PerOrbit(u16), //N points per revolution from the TLE mean motion, plus the end point
+    Epochs(String, Vec<Instant>), //only the epochs read from this file, wherever they fall inside a gap
+}
+
+//"step:60", "count:5000", "orbit:100" or "epochs:<file>" with one time per line (unix seconds or RFC 3339)
+impl FromStr for Sampling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, value) = s.split_once(':').ok_or_else(|| anyhow!("Sampling {s} should look like step:60, count:5000, orbit:100 or epochs:<file>"))?;
+        let sampling = match kind {
+            "step" => Sampling::FixedStep(value.parse()?),
+            "count" => Sampling::FixedCount(value.parse()?),
+            "orbit" => Sampling::PerOrbit(value.parse()?),
+            "epochs" => {
+                let mut epochs: Vec<Instant> = read_to_string(value)?.lines()
+                    .map(|line| line.trim())
+                    .filter(|line| !line.is_empty())
+                    .map(parse_instant)
+                    .collect::<Result<Vec<Instant>>>()?;
+                epochs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                Sampling::Epochs(value.to_string(), epochs)
+            }
+            _ => bail!("Unknown sampling {kind}, expected step, count, orbit or epochs"),
+        };
+        match sampling {
+            Sampling::FixedStep(step) if step <= 0.0 => bail!("Sampling step has to be positive"),
+            Sampling::FixedCount(0) | Sampling::PerOrbit(0) => bail!("Sampling count has to be positive"),
+            sampling => Ok(sampling),
+        }
+    }
+}
+
+impl fmt::Display for Sampling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sampling::FixedStep(step) => write!(f, "step:{}", step),
+            Sampling::FixedCount(count) => write!(f, "count:{}", count),
+            Sampling::PerOrbit(count) => write!(f, "orbit:{}", count),
+            Sampling::Epochs(path, _) => write!(f, "epochs:{}", path), //the same form it was parsed from
+        }
+    }
+}
+
+impl Sampling {
+    //times to sample between start and end, which can run backwards for gaps integrated from the later TLE
+    pub(crate) fn times(&self, start: Instant, end: Instant, mean_motion: f64) -> Vec<Instant> {
+        let span: f64 = (end - start).as_seconds();
+        let direction: f64 = if span < 0.0 { -1.0 } else { 1.0 };
+        let interval: f64 = match self {
+            Sampling::FixedStep(step) => *step,
+            Sampling::FixedCount(count) => span.abs() / *count as f64,
+            Sampling::PerOrbit(count) => 86400.0 / mean_motion / *count as f64, //mean motion is in rev/day
+            Sampling::Epochs(_, epochs) => {
+                let (lo, hi) = if direction > 0.0 { (start, end) } else { (end, start) };
+                let mut times: Vec<Instant> = epochs.iter().filter(|epoch| **epoch >= lo && **epoch <= hi).copied().collect();
+                if direction < 0.0 {
+                    times.reverse();
+                }
+                return times;
+            }
+        };
+        if interval <= 0.0 || !interval.is_finite() {
+            return vec![end];
+        }
+
+        let count = (span.abs() / interval - 1.0e-9).ceil().max(0.0) as usize; //the tolerance keeps rounding from adding a point right before the end
+        let mut times: Vec<Instant> = (0..count)
+            .map(|j| start + Duration::from_seconds(direction * interval * j as f64))
+            .collect();
+        times.push(end);
+        times
+    }
+}
+
+//samples a propagation result at the times the sampling strategy picks, mean motion (rev/day) is only used per orbit
+pub(crate) fn sample_result(result: &PropagationResult<1>, sampling: &Sampling, mean_motion: f64) -> Result<Vec<SatState>> {
+    Ok(sample_matrices(result, sampling, mean_motion)?.into_iter().map(|(time, state)| make_sat_state(time, state)).collect())
+}
+
+//the same for a propagation that carried the state transition matrix, each state gets initial covariance P0 mapped to Phi * P0 * Phi^T
+pub(crate) fn sample_covariance_result(result: &PropagationResult<7>, initial: &Matrix6, sampling: &Sampling, mean_motion: f64) -> Result<Vec<SatState>> {
+    Ok(sample_matrices(result, sampling, mean_motion)?.into_iter()
+        .map(|(time, matrix)| {
+            let mut state = make_sat_state(time, matrix.fixed_view::<6, 1>(0, 0).into_owned());
+            let transition: Matrix6 = matrix.fixed_view::<6, 6>(0, 1).into_owned();
+            state.set_cov(StateCov::PVCov(transition * initial * transition.transpose()));
+            state
+        })
+        .collect())
 }
 
-fn make_sat_state(time: Instant, svec: SVector<f64, 6>) -> SatState {
+fn sample_matrices<const C: usize>(result: &PropagationResult<C>, sampling: &Sampling, mean_motion: f64) -> Result<Vec<(Instant, SMatrix<f64, 6, C>)>> {
+    let times: Vec<Instant> = sampling.times(result.time_start, result.time_end, mean_motion);
+    let mut steps: Vec<(Instant, SMatrix<f64, 6, C>)> = Vec::with_capacity(times.len());
+    for time in times {
+        if time == result.time_end {
+            steps.push((time, result.state_end));
+            continue;
+        }
+        let matrix_at_time = result.interp(&time).map_err(|e| anyhow!("Failed to interpolate at {time}: {e}"))?;
+        steps.push((time, matrix_at_time));
+    }
+    if result.time_end < result.time_start { //the backward half of a split maneuver gap, written in time order like every other segment
+        steps.reverse();
+    }
+    Ok(steps)
+}
+
+//one segment's integration, with the state transition matrix alongside the state when a covariance is propagated
+pub(crate) enum SegmentResult {
+    State(PropagationResult<1>),
+    Covariance(Box<PropagationResult<7>>, Matrix6), //and the GCRF covariance at the start of the segment
+}
+
+impl SegmentResult {
+    pub(crate) fn sample(&self, sampling: &Sampling, mean_motion: f64) -> Result<Vec<SatState>> {
+        match self {
+            SegmentResult::State(result) => sample_result(result, sampling, mean_motion),
+            SegmentResult::Covariance(result, initial) => sample_covariance_result(result, initial, sampling, mean_motion),
+        }
+    }
+}
+
+pub(crate) fn make_sat_state(time: Instant, svec: SVector<f64, 6>) -> SatState {
     let pos = Vector3::new(svec[0], svec[1], svec[2]);
     let vel = Vector3::new(svec[3], svec[4], svec[5]);
 
     SatState::from_pv(&time, &pos, &vel)
 }
 
-fn integrate_between_gaps(states: Vec<SatState>, settings: &PropSettings) -> Result<Vec<PropagationResult<1>>> {
-    let mut result_vec: Vec<PropagationResult<1>> =  Vec::new();
-    for window in states.windows(2) {
-        let current_record: &SatState = &window[0];
-        let next_record: &SatState = &window[1];
+//integrates every planned segment, skipped gaps simply have no entry. With a covariance source each segment
+//starts from the covariance it gives for the satellite at the segment's TLE, objects it knows nothing about are integrated without one
+pub(crate) fn integrate_between_gaps(id: &str, tles: &[TLE], states: &[SatState], settings: &PropSettings, options: &IntegrationOptions) -> Result<(Vec<(Segment, SegmentResult)>, RunReport)> {
+    let maneuver_gaps: HashSet<usize> = maneuver_gaps(tles, options.maneuvers, options.maneuver_sigma);
+    let epochs: Vec<Instant> = states.iter().map(|state| state.time).collect();
+    let (segments, report) = plan_segments(&epochs, &maneuver_gaps, options.maneuvers, &options.gaps);
+
+    let mut result_vec: Vec<(Segment, SegmentResult)> = Vec::with_capacity(segments.len());
+    for segment in segments {
+        let start = &states[segment.tle_index];
+        let properties = match &options.drag {
+            Some(space_weather) => {
+                space_weather.check_range(&segment.start, &segment.stop)?;
+                Some(drag_properties(tles[segment.tle_index].bstar))
+            }
+            None => None,
+        };
+        let properties = properties.as_ref().map(|properties| properties as &dyn SatProperties);
+        let result = match options.covariance.as_ref().and_then(|source| source.covariance_for(id, start)) {
+            Some(initial) => SegmentResult::Covariance(Box::new(integrate_covariance_to(start, &segment.stop, settings, properties)?), initial),
+            None => SegmentResult::State(integrate_to(start, &segment.stop, settings, properties)?),
+        };
+        result_vec.push((segment, result));
+    }
+    Ok((result_vec, report))
+}
 
-        let start: &Instant = &current_record.time;
-        let stop: &Instant = &next_record.time;
-        let dt: f64 = (stop - start).as_seconds(); 
+//gaps (by the index of the TLE before them) a maneuver falls in, none when maneuvers are ignored
+pub(crate) fn maneuver_gaps(tles: &[TLE], maneuvers: ManeuverHandling, maneuver_sigma: f64) -> HashSet<usize> {
+    if maneuvers == ManeuverHandling::Ignore {
+        return HashSet::new();
+    }
+    let records: Vec<OrbitalInstance> = tles.iter().map(OrbitalInstance::from_tle).collect();
+    detect_maneuvers(&records, maneuver_sigma).iter().map(|maneuver| maneuver.index).collect()
+}
 
-        if start == stop || dt < 60.0*30.0 { //skips cases where TLEs updated too frequently to propagate in between
+//decides which gaps between consecutive epochs get integrated, from where and how far, without integrating anything
+pub(crate) fn plan_segments(epochs: &[Instant], maneuver_gaps: &HashSet<usize>, maneuvers: ManeuverHandling, limits: &GapLimits) -> (Vec<Segment>, RunReport) {
+    let mut report = RunReport { satellites: 1, ..Default::default() };
+    let mut segments: Vec<Segment> = Vec::new();
+    for (i, window) in epochs.windows(2).enumerate() {
+        report.gaps += 1;
+        let dt: f64 = (window[1] - window[0]).as_seconds();
+        if let Some(reason) = limits.skip_reason(dt) {
+            report.skip(reason);
             continue;
         }
-
-        //extracts position and velocity from SatState to create a state
-        let pos = current_record.pos_gcrf();
-        let vel = current_record.vel_gcrf();
-        let state = SVector::<f64, 6>::new(pos[0], pos[1], pos[2], vel[0], vel[1], vel[2]);
-
-        let result = propagate(&state, start, stop, settings, None).unwrap();
-        result_vec.push(result)
+        if maneuver_gaps.contains(&i) {
+            if maneuvers == ManeuverHandling::Split {
+                //the maneuver epoch isn't known any better than "somewhere in the gap", so each side covers half of it
+                let midpoint: Instant = window[0] + Duration::from_seconds(dt / 2.0);
+                segments.push(Segment { tle_index: i, start_epoch_index: i, end_epoch_index: i + 1, start: window[0], stop: limits.capped_stop(&window[0], &midpoint) });
+                segments.push(Segment { tle_index: i + 1, start_epoch_index: i + 1, end_epoch_index: i, start: window[1], stop: limits.capped_stop(&window[1], &midpoint) });
+                report.split_maneuver += 1;
+            } else {
+                report.skipped_maneuver += 1;
+            }
+            continue;
+        }
+        let stop: Instant = limits.capped_stop(&window[0], &window[1]);
+        if stop != window[1] {
+            report.capped += 1;
+        }
+        segments.push(Segment { tle_index: i, start_epoch_index: i, end_epoch_index: i + 1, start: window[0], stop });
+        report.integrated += 1;
     }
-    Ok(result_vec)
+    (segments, report)
 }
 
-fn tle_teme_to_gcrf(mut records:Vec<TLE>) -> Result<(Vec<TLE>, Vec<SatState>)> {
-    //Converts TLEs in TEME to GCRF SatStates
+//integrates a SatState forward (or backward) to the stop time, without drag unless its properties are given
+pub(crate) fn integrate_to(record: &SatState, stop: &Instant, settings: &PropSettings, properties: Option<&dyn SatProperties>) -> Result<PropagationResult<1>> {
+    let start: &Instant = &record.time;
 
-    let mut gcrf_states:Vec<SatState> = Vec::new();
+    //extracts position and velocity from SatState to create a state
+    let pos = record.pos_gcrf();
+    let vel = record.vel_gcrf();
+    let state = SVector::<f64, 6>::new(pos[0], pos[1], pos[2], vel[0], vel[1], vel[2]);
 
-    for mut tle in records.iter_mut() {
-        let epoch: Instant = tle.epoch;
-        let (r_teme, v_teme, _errs) = sgp4(&mut tle, &[epoch]);
+    propagate(&state, start, stop, settings, properties).map_err(|e| anyhow!("Failed to propagate from {start} to {stop}: {e}"))
+}
 
-        //used to convert to geocentric (GCRF)
-        let q_teme_to_gcrf = qteme2gcrf(&epoch);
+//the same with the state transition matrix integrated alongside, as columns 1 to 6 of the result starting from identity
+pub(crate) fn integrate_covariance_to(record: &SatState, stop: &Instant, settings: &PropSettings, properties: Option<&dyn SatProperties>) -> Result<PropagationResult<7>> {
+    let start: &Instant = &record.time;
+    let mut state = SMatrix::<f64, 6, 7>::zeros();
+    state.fixed_view_mut::<3, 1>(0, 0).copy_from(&record.pos_gcrf());
+    state.fixed_view_mut::<3, 1>(3, 0).copy_from(&record.vel_gcrf());
+    state.fixed_view_mut::<6, 6>(0, 1).copy_from(&Matrix6::identity());
 
-        //converts position and velocity vectors from TEME formatted matrices to GCRF formatted matrices (both in kilometers)
-        let r_gcrf = q_teme_to_gcrf.to_rotation_matrix() * r_teme;
-        let v_gcrf = q_teme_to_gcrf.to_rotation_matrix() * v_teme;
+    propagate(&state, start, stop, settings, properties).map_err(|e| anyhow!("Failed to propagate the state transition matrix from {start} to {stop}: {e}"))
+}
 
-        //fixed object as SatState::from_pv expects it
-        let r_fixed = Vector3::new(r_gcrf[0], r_gcrf[1], r_gcrf[2]);
-        let v_fixed = Vector3::new(v_gcrf[0], v_gcrf[1], v_gcrf[2]);
+pub(crate) fn tle_teme_to_gcrf(mut records:Vec<TLE>) -> Result<GcrfRecords> {
+    //Converts TLEs in TEME to GCRF SatStates
 
-        let state: SatState = SatState::from_pv(&epoch, &r_fixed, &v_fixed);
+    let mut gcrf_states:Vec<SatState> = Vec::new();
 
-        gcrf_states.push(state);
+    for tle in records.iter_mut() {
+        let epoch: Instant = tle.epoch;
+        let mut states: Vec<SatState> = sgp4_gcrf(tle, &[epoch]);
+        gcrf_states.push(states.remove(0));
     }
     Ok((records, gcrf_states))
 }
 
-// fn gcrf_to_teme(states: Vec<SatState>) { //todo, possibly not needed
-//     let teme_states: Vec<SatState> = Vec::new();
+//runs SGP4 on a TLE at the given times and converts each TEME output to a GCRF SatState
+pub(crate) fn sgp4_gcrf(tle: &mut TLE, times: &[Instant]) -> Vec<SatState> {
+    let (r_teme, v_teme, _errs) = sgp4(tle, times);
+
+    let mut gcrf_states: Vec<SatState> = Vec::with_capacity(times.len());
+    for (i, time) in times.iter().enumerate() {
+        //fixed object as SatState::from_pv expects it (both in meters)
+        let r_teme = Vector3::new(r_teme[(0, i)], r_teme[(1, i)], r_teme[(2, i)]);
+        let v_teme = Vector3::new(v_teme[(0, i)], v_teme[(1, i)], v_teme[(2, i)]);
+        let (r_gcrf, v_gcrf) = teme_to_gcrf(time, &r_teme, &v_teme);
 
-// }
+        gcrf_states.push(SatState::from_pv(time, &r_gcrf, &v_gcrf));
+    }
+    gcrf_states
+}
 
-fn convert_map_to_gcrf(map:HashMap<String, Vec<TLE>>) -> Result<HashMap<String, (Vec<TLE>, Vec<SatState>)>> {
+pub(crate) type GcrfRecords = (Vec<TLE>, Vec<SatState>);
+
+pub(crate) fn convert_map_to_gcrf(map:HashMap<String, Vec<TLE>>) -> Result<HashMap<String, GcrfRecords>> {
     map.into_par_iter()
         .map(|(id, records)| {
             tle_teme_to_gcrf(records).map(|states| (id, states))
@@ -182,13 +536,19 @@ fn convert_map_to_gcrf(map:HashMap<String, Vec<TLE>>) -> Result<HashMap<String,
         .collect()
 }
 
-fn parallel_stream_integration(map: HashMap<String, (Vec<TLE>, Vec<SatState>)>, settings: &PropSettings, density:u16, compression_level:i32) -> Result<()> {
+fn parallel_stream_integration(map: HashMap<String, GcrfRecords>, settings: &PropSettings, options: &IntegrationOptions) -> Result<RunReport> {
     const MAX_VEC_SIZE:usize = 1_073_741_824 ; //1 GB in mem change as needed
-    map.into_par_iter()
-        .try_for_each(|(id, records)| -> Result<()>{
-            Ok(stream(records, settings, &id, density, MAX_VEC_SIZE, compression_level)?)
-        })?;
-    Ok(())
+    let reports: Vec<RunReport> = map.into_par_iter()
+        .map(|(id, records)| -> Result<RunReport>{
+            stream(records, settings, &id, options, MAX_VEC_SIZE)
+        })
+        .collect::<Result<Vec<RunReport>>>()?;
+
+    let mut report = RunReport::default();
+    for satellite_report in reports.iter() {
+        report.merge(satellite_report);
+    }
+    Ok(report)
 }
 
 #[allow(dead_code)]
@@ -198,17 +558,18 @@ fn move_file(filepath: &str, destination_filepath: &str) -> Result<()> {
     Ok(deleted)
 }
 
-fn write_tle_data(encoder: &mut Encoder<'static, BufWriter<File>>, tle:&TLE) -> Result<()>{
+pub(crate) fn write_tle_data<W: Write>(writer: &mut W, tle:&TLE) -> Result<()>{
     let line1: String = write_line1(tle)?;
     let line2: String = write_line2(tle)?;
 
     let two_lines: String = format!("{} \n{} ", line1, line2);
 
-    let _ = writeln!(encoder, "{}", two_lines);
+    writeln!(writer, "{}", two_lines)?;
 
     Ok(())
 }
 
+#[allow(clippy::assign_op_pattern)]
 fn write_line1(tle:&TLE) -> Result<String> {
     use std::fmt::Write;
 
@@ -223,7 +584,7 @@ fn write_line1(tle:&TLE) -> Result<String> {
 
     //last two digits of the launch year
     let mut year: i32 = tle.epoch.as_datetime().0;
-    if year > 2000 {
+    if year >= 2000 {
         year = year - 2000
     } else {
         year = year - 1900
@@ -231,7 +592,7 @@ fn write_line1(tle:&TLE) -> Result<String> {
 
     //day of the year + fractional part of day
     let unix_t = tle.epoch.as_unixtime();
-    let unix_t_ns = (unix_t.fract() * 1_000_000.0) as u32; //integer number of nanoseconds
+    let unix_t_ns = (unix_t.fract() * 1_000_000_000.0) as u32; //integer number of nanoseconds
     let unix_t_int = unix_t.trunc() as i64;
     let datetime = Utc.timestamp_opt(unix_t_int, unix_t_ns).unwrap();
     let ordinal = datetime.ordinal();
@@ -249,14 +610,14 @@ fn write_line1(tle:&TLE) -> Result<String> {
     if first_dt_string.starts_with("-0.") {
         first_dt_string.replace_range(1..2, ""); //removes zero in negative values
     } else {
-        first_dt_string.replace_range(0..1, "+"); //removes zero in positive values
+        first_dt_string.replace_range(0..1, " "); //removes zero in positive values
     }
     
     //second derivative of mean motion
     let (second_dt_sign, second_dt_val) = if tle.mean_motion_dot_dot < 0.0 {
         ('-', -tle.mean_motion_dot_dot)
     } else {
-        ('+', tle.mean_motion_dot_dot)
+        (' ', tle.mean_motion_dot_dot)
     };
     let (second_dt_mantissa, second_dt_exp) = to_tle_scientific(second_dt_val);
 
@@ -269,7 +630,7 @@ fn write_line1(tle:&TLE) -> Result<String> {
     let (bstar_mantissa, bstar_exp) = to_tle_scientific(bstar_val);
 
     //now we build line1
-    write!(&mut line1, "1 {} {:8} {:02}{:012.8} {} {}{:5}-{} {}{:4}-{} {} {:04}",
+    write!(&mut line1, "1 {} {:8} {:02}{:012.8} {} {}{:5}-{} {}{:4}-{} {} {:4}",
         sat_num_string,
         international_designator,
         year,
@@ -343,6 +704,7 @@ fn to_tle_scientific(value: f64) -> (String, i32) {
     (mantissa_str, final_exp)
 }
 
+#[allow(clippy::ptr_arg, clippy::is_digit_ascii_radix, clippy::assign_op_pattern)]
 fn compute_checksum(line:&String) -> Result<u32> {
     let mut checksum: u32 = 0;
 
@@ -359,4 +721,173 @@ fn compute_checksum(line:&String) -> Result<u32> {
     checksum = checksum % 10;
 
     Ok(checksum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE1: &str = "1 25544U 98067A   08264.51782528 -.00002182  00000-0 -11606-4 0  2927";
+    const LINE2: &str = "2 25544  51.6416 247.4627 0006703 130.5360 325.0288 15.72125391563537";
+
+    //epochs at the given hours after a fixed start
+    fn epochs(hours: &[f64]) -> Vec<Instant> {
+        let start = Instant::from_unixtime(1.7e9);
+        hours.iter().map(|h| start + Duration::from_hours(*h)).collect()
+    }
+
+    //TLEs that only differ in epoch and element set number, so each one is recognisable in the output
+    fn tles(epochs: &[Instant]) -> Vec<TLE> {
+        epochs.iter().enumerate().map(|(i, epoch)| {
+            let mut tle = TLE::load_2line(LINE1, LINE2).unwrap();
+            tle.epoch = *epoch;
+            tle.element_num = 100 + i as i32;
+            tle
+        }).collect()
+    }
+
+    fn labels(segments: &[Segment]) -> Vec<(usize, usize, usize)> {
+        segments.iter().map(|s| (s.tle_index, s.start_epoch_index, s.end_epoch_index)).collect()
+    }
+
+    //the case that used to mislabel everything: a skipped short gap early on
+    #[test]
+    fn skipped_short_gap_keeps_tle_indices() {
+        let epochs = epochs(&[0.0, 0.1, 6.0, 12.0, 12.2, 30.0]);
+        let (segments, report) = plan_segments(&epochs, &HashSet::new(), ManeuverHandling::Ignore, &GapLimits::default());
+
+        assert_eq!(labels(&segments), vec![(1, 1, 2), (2, 2, 3), (4, 4, 5)]);
+        for segment in segments.iter() {
+            assert_eq!(segment.start, epochs[segment.tle_index]);
+            assert_eq!(segment.stop, epochs[segment.end_epoch_index]);
+        }
+        assert_eq!((report.gaps, report.integrated, report.skipped_short), (5, 3, 2));
+    }
+
+    #[test]
+    fn long_gaps_are_skipped_and_capped_gaps_stop_early() {
+        let epochs = epochs(&[0.0, 12.0, 12.0 + 24.0 * 20.0, 12.0 + 24.0 * 21.5]);
+        let limits = GapLimits { min_seconds: MIN_GAP_SECONDS, max_seconds: Some(7.0 * 86400.0), cap_seconds: Some(86400.0) };
+        let (segments, report) = plan_segments(&epochs, &HashSet::new(), ManeuverHandling::Ignore, &limits);
+
+        assert_eq!(labels(&segments), vec![(0, 0, 1), (2, 2, 3)]);
+        assert_eq!(segments[0].stop, epochs[1]);
+        assert_eq!(segments[1].stop, epochs[2] + Duration::from_days(1.0));
+        assert_eq!((report.integrated, report.capped, report.skipped_long), (2, 1, 1));
+    }
+
+    #[test]
+    fn maneuver_gaps_are_skipped_or_split_with_their_own_tles() {
+        let epochs = epochs(&[0.0, 10.0, 20.0, 30.0]);
+        let maneuver_gaps: HashSet<usize> = [1].into_iter().collect();
+
+        let (skipped, report) = plan_segments(&epochs, &maneuver_gaps, ManeuverHandling::Skip, &GapLimits::default());
+        assert_eq!(labels(&skipped), vec![(0, 0, 1), (2, 2, 3)]);
+        assert_eq!(report.skipped_maneuver, 1);
+
+        let (split, report) = plan_segments(&epochs, &maneuver_gaps, ManeuverHandling::Split, &GapLimits::default());
+        assert_eq!(labels(&split), vec![(0, 0, 1), (1, 1, 2), (2, 2, 1), (2, 2, 3)]);
+        assert_eq!(split[1].stop, epochs[1] + Duration::from_hours(5.0));
+        assert_eq!(split[2].stop, split[1].stop);
+        assert_eq!(report.split_maneuver, 1);
+    }
+
+    //every block written has to carry the TLE its segment was integrated from, whatever was skipped before it
+    #[test]
+    fn written_blocks_carry_their_segment_tle() {
+        let epochs = epochs(&[0.0, 0.1, 6.0, 6.2, 12.0, 18.0]);
+        let tles = tles(&epochs);
+        let (segments, _) = plan_segments(&epochs, &HashSet::new(), ManeuverHandling::Ignore, &GapLimits::default());
+
+        let batches: Vec<(Segment, Vec<SatState>)> = segments.iter().map(|segment| {
+            let steps: Vec<SatState> = Sampling::FixedCount(4).times(segment.start, segment.stop, 15.7).iter()
+                .map(|time| SatState::from_pv(time, &Vector3::new(7.0e6, 0.0, 0.0), &Vector3::new(0.0, 7.5e3, 0.0)))
+                .collect();
+            (*segment, steps)
+        }).collect();
+
+        let mut output: Vec<u8> = Vec::new();
+        dump_data_batches(&mut output, &tles, &batches, &OutputFrames::default()).unwrap();
+        let text = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+
+        let block_starts: Vec<usize> = lines.iter().enumerate().filter(|(_, line)| line.starts_with("#segment")).map(|(i, _)| i).collect();
+        assert_eq!(block_starts.len(), segments.len());
+        for (block, segment) in block_starts.iter().zip(segments.iter()) {
+            assert!(lines[*block].contains(&format!("tle_index={}", segment.tle_index)));
+            assert_eq!(lines[block + 1].trim_end(), write_line1(&tles[segment.tle_index]).unwrap());
+            assert_eq!(lines[block + 2].trim_end(), write_line2(&tles[segment.tle_index]).unwrap());
+            let first_time: f64 = lines[block + 3].split(',').next().unwrap().parse().unwrap();
+            assert_eq!(first_time, segment.start.as_unixtime());
+        }
+        assert_eq!(lines.len(), segments.len() * (3 + 5));
+    }
+
+    //the header names the epochs file so the run can be repeated, and gaps without any of its epochs write nothing
+    #[test]
+    fn epochs_sampling_names_its_file_and_skips_empty_gaps() {
+        let epochs = epochs(&[0.0, 6.0, 12.0]);
+        let tles = tles(&epochs);
+        let path = std::env::temp_dir().join(format!("epochs_{}.txt", std::process::id()));
+        std::fs::write(&path, format!("{}\n", (epochs[0] + Duration::from_hours(1.0)).as_unixtime())).unwrap();
+        let text = format!("epochs:{}", path.display());
+        let sampling: Sampling = text.parse().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sampling.to_string(), text);
+
+        let (segments, _) = plan_segments(&epochs, &HashSet::new(), ManeuverHandling::Ignore, &GapLimits::default());
+        let batches: Vec<(Segment, Vec<SatState>)> = segments.iter().map(|segment| {
+            let steps: Vec<SatState> = sampling.times(segment.start, segment.stop, 15.7).iter()
+                .map(|time| SatState::from_pv(time, &Vector3::new(7.0e6, 0.0, 0.0), &Vector3::new(0.0, 7.5e3, 0.0)))
+                .collect();
+            (*segment, steps)
+        }).collect();
+        assert_eq!(batches.iter().filter(|(_, steps)| steps.is_empty()).count(), 1);
+
+        let mut output: Vec<u8> = Vec::new();
+        dump_data_batches(&mut output, &tles, &batches, &OutputFrames::default()).unwrap();
+        let text = String::from_utf8(output).unwrap();
+        assert_eq!(text.lines().filter(|line| line.starts_with("#segment")).count(), 1);
+        assert_eq!(text.lines().count(), 3 + 1);
+    }
+
+    //the state transition matrix starts at identity and matches finite differences of the integrated state over a short arc
+    #[test]
+    #[ignore = "needs satkit data files"]
+    fn covariance_follows_the_state_transition_matrix() {
+        crate::data::DataDir::open(None, false, &[crate::data::DataUse::Integration]).unwrap();
+        let settings = integration_settings();
+        let start = SatState::from_pv(&Instant::from_unixtime(1.7e9), &Vector3::new(6.778e6, 0.0, 0.0), &Vector3::new(0.0, 4.6e3, 6.0e3));
+        let stop = start.time + Duration::from_seconds(1200.0);
+        let sampling = Sampling::FixedStep(300.0);
+        let mut initial = Matrix6::zeros();
+        for (i, sigma) in [100.0, 200.0, 50.0, 0.1, 0.2, 0.05].iter().enumerate() {
+            initial[(i, i)] = sigma * sigma;
+        }
+        let result = integrate_covariance_to(&start, &stop, &settings, None).unwrap();
+        let steps = sample_covariance_result(&result, &initial, &sampling, 15.5).unwrap();
+        let covariance = |state: &SatState| match &state.cov {
+            StateCov::PVCov(covariance) => *covariance,
+            StateCov::None => panic!("no covariance at {}", state.time),
+        };
+        assert_eq!(steps.len(), 5);
+        assert!((covariance(&steps[0]) - initial).abs().max() < 1.0e-9 * initial.abs().max());
+
+        //column j of the transition matrix from a nudge of component j, 1 m or 1 mm/s
+        let nominal = sample_result(&integrate_to(&start, &stop, &settings, None).unwrap(), &sampling, 15.5).unwrap();
+        let vector = |state: &SatState| SVector::<f64, 6>::from_iterator(state.pos_gcrf().iter().chain(state.vel_gcrf().iter()).copied());
+        let mut transition = Matrix6::zeros();
+        for j in 0..6 {
+            let delta = if j < 3 { 1.0 } else { 1.0e-3 };
+            let mut nudged = vector(&start);
+            nudged[j] += delta;
+            let nudged = make_sat_state(start.time, nudged);
+            let steps = sample_result(&integrate_to(&nudged, &stop, &settings, None).unwrap(), &sampling, 15.5).unwrap();
+            transition.set_column(j, &((vector(&steps[4]) - vector(&nominal[4])) / delta));
+        }
+        let expected = transition * initial * transition.transpose();
+        let error = (covariance(&steps[4]) - expected).abs().max();
+        assert!(error < 1.0e-3 * expected.abs().max(), "covariance off by {error} after 20 minutes");
+        assert!((vector(&steps[4]) - vector(&nominal[4])).abs().max() < 1.0e-3);
+    }
 }
\ No newline at end of file
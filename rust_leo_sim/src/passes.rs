@@ -0,0 +1,309 @@
+use std::{collections::HashMap, fs::{self, read_to_string, File}, io::{BufWriter, Write}, path::Path};
+use rayon::prelude::*;
+use satkit::{frametransform::qteme2itrf, itrfcoord::ITRFCoord, types::{Matrix3, Vector3}, Duration, Instant, TLE};
+use anyhow::{anyhow, bail, Result};
+use crate::conjunction::position_teme;
+use crate::satellite::SatelliteRecord;
+
+const EVENT_TOLERANCE: f64 = 0.1; //s, AOS/LOS/TCA resolution
+
+pub(crate) struct Station {
+    pub(crate) name: String,
+    pub(crate) coord: ITRFCoord,
+    pub(crate) min_elevation: f64, //deg
+    pub(crate) azimuth_mask: Vec<(f64, f64)>, //(azimuth, minimum elevation) in deg, sorted by azimuth, linearly interpolated
+    itrf_to_enu: Matrix3,
+}
+
+//where a satellite appears from a station
+#[derive(Clone, Copy)]
+pub(crate) struct Look {
+    pub(crate) azimuth: f64, //deg, clockwise from north
+    pub(crate) elevation: f64, //deg
+    pub(crate) range: f64, //m
+}
+
+pub(crate) struct Pass {
+    pub(crate) satellite: String,
+    pub(crate) station: String,
+    pub(crate) aos: Instant,
+    pub(crate) tca: Instant, //time of maximum elevation
+    pub(crate) los: Instant,
+    pub(crate) aos_look: Look,
+    pub(crate) tca_look: Look,
+    pub(crate) los_look: Look,
+}
+
+pub(crate) struct PassOptions {
+    pub(crate) start: Instant,
+    pub(crate) stop: Instant,
+    pub(crate) step: f64, //s, coarse search step, passes shorter than this can be missed
+}
+
+impl Station {
+    pub(crate) fn new(name: String, latitude: f64, longitude: f64, altitude: f64, min_elevation: f64, mut azimuth_mask: Vec<(f64, f64)>) -> Station {
+        let coord = ITRFCoord::from_geodetic_deg(latitude, longitude, altitude);
+        let itrf_to_enu = coord.q_enu2itrf().conjugate().to_rotation_matrix().into_inner();
+        azimuth_mask.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Station { name, coord, min_elevation, azimuth_mask, itrf_to_enu }
+    }
+
+    pub(crate) fn look(&self, pos_itrf: &Vector3) -> Look {
+        let enu: Vector3 = self.itrf_to_enu * (pos_itrf - self.coord.itrf);
+        let range = enu.norm();
+        Look {
+            azimuth: enu[0].atan2(enu[1]).to_degrees().rem_euclid(360.0),
+            elevation: (enu[2] / range).asin().to_degrees(),
+            range,
+        }
+    }
+
+    //elevation the satellite has to be above at this azimuth, the larger of the minimum elevation and the mask
+    pub(crate) fn horizon(&self, azimuth: f64) -> f64 {
+        let mask = &self.azimuth_mask;
+        if mask.is_empty() {
+            return self.min_elevation;
+        }
+        //the mask wraps around north, so the first point follows the last one
+        let next = mask.iter().position(|(az, _)| *az > azimuth).unwrap_or(mask.len());
+        let (az0, el0) = if next == 0 { (mask[mask.len() - 1].0 - 360.0, mask[mask.len() - 1].1) } else { mask[next - 1] };
+        let (az1, el1) = if next == mask.len() { (mask[0].0 + 360.0, mask[0].1) } else { mask[next] };
+        let masked = if az1 > az0 { el0 + (el1 - el0) * (azimuth - az0) / (az1 - az0) } else { el0 };
+        masked.max(self.min_elevation)
+    }
+
+    //degrees above the horizon, negative when the satellite can't be seen
+    fn margin(&self, pos_itrf: &Vector3) -> f64 {
+        let look = self.look(pos_itrf);
+        look.elevation - self.horizon(look.azimuth)
+    }
+}
+
+//stations file: name,latitude,longitude,altitude,min_elevation[,mask] with the mask as "az:el;az:el;..." in degrees
+//altitude in meters above the ellipsoid, lines starting with # and a header line starting with "name" are skipped
+pub(crate) fn read_stations(path: &str) -> Result<Vec<Station>> {
+    let contents = read_to_string(path)?;
+    let mut stations: Vec<Station> = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("name") {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        if fields.len() < 5 {
+            bail!("Station line needs name,latitude,longitude,altitude,min_elevation: {line}");
+        }
+        let number = |i: usize| -> Result<f64> {
+            fields[i].parse::<f64>().map_err(|_| anyhow!("Invalid number {} in station line: {line}", fields[i]))
+        };
+        let mask: Vec<(f64, f64)> = match fields.get(5) {
+            Some(mask) if !mask.is_empty() => mask.split(';')
+                .map(|point| {
+                    let (az, el) = point.split_once(':').ok_or_else(|| anyhow!("Mask point {point} should be azimuth:elevation"))?;
+                    Ok((az.trim().parse::<f64>()?, el.trim().parse::<f64>()?))
+                })
+                .collect::<Result<Vec<(f64, f64)>>>()?,
+            _ => Vec::new(),
+        };
+        stations.push(Station::new(fields[0].to_string(), number(1)?, number(2)?, number(3)?, number(4)?, mask));
+    }
+    Ok(stations)
+}
+
+//TEME position from SGP4 rotated straight to ITRF
+fn position_itrf(tle: &mut TLE, time: &Instant) -> Option<Vector3> {
+    position_teme(tle, time).map(|pos| qteme2itrf(time).to_rotation_matrix() * pos)
+}
+
+pub(crate) fn predict_passes(satellites: &HashMap<String, SatelliteRecord>, stations: &[Station], options: &PassOptions) -> Result<Vec<Pass>> {
+    if options.stop <= options.start || options.step <= 0.0 {
+        bail!("Pass window must end after it starts and use a positive step");
+    }
+    let window = (options.stop - options.start).as_seconds();
+    let count = (window / options.step).ceil() as usize;
+    let offsets: Vec<f64> = (0..=count).map(|k| (k as f64 * options.step).min(window)).collect();
+
+    let mut passes: Vec<Pass> = satellites.par_iter()
+        .flat_map_iter(|(id, record)| {
+            let mut tle = match record.tle_at(&options.start) {
+                Some(tle) => tle,
+                None => return Vec::new(),
+            };
+            let mut position_at = |t: f64| position_itrf(&mut tle, &(options.start + Duration::from_seconds(t)));
+            let positions: Vec<Option<Vector3>> = offsets.iter().map(|offset| position_at(*offset)).collect();
+            stations.iter()
+                .flat_map(|station| find_passes(id, station, &mut position_at, &offsets, &positions, options.start))
+                .collect::<Vec<Pass>>()
+        })
+        .collect();
+    passes.sort_by(|a, b| a.aos.partial_cmp(&b.aos).unwrap());
+    Ok(passes)
+}
+
+//walks the coarse samples for horizon crossings, then refines each crossing and the elevation peak in between
+//position_at gives the ITRF position at an offset in seconds from start, positions are its values at the offsets
+fn find_passes(id: &str, station: &Station, position_at: &mut impl FnMut(f64) -> Option<Vector3>, offsets: &[f64], positions: &[Option<Vector3>], start: Instant) -> Vec<Pass> {
+    let margins: Vec<Option<f64>> = positions.iter().map(|p| p.as_ref().map(|p| station.margin(p))).collect();
+    let mut margin_at = |t: f64| -> f64 {
+        match position_at(t) {
+            Some(p) => station.margin(&p),
+            None => f64::NEG_INFINITY,
+        }
+    };
+
+    //(aos, los) offsets, a pass already in progress at the start of the window begins there
+    let mut windows: Vec<(f64, f64)> = Vec::new();
+    let mut aos: Option<f64> = match margins.first() {
+        Some(Some(m)) if *m >= 0.0 => Some(offsets[0]),
+        _ => None,
+    };
+    for k in 1..offsets.len() {
+        let (before, after) = (margins[k - 1].unwrap_or(f64::NEG_INFINITY), margins[k].unwrap_or(f64::NEG_INFINITY));
+        if before < 0.0 && after >= 0.0 {
+            aos = Some(bisect(&mut margin_at, offsets[k - 1], offsets[k]));
+        } else if before >= 0.0 && after < 0.0 {
+            if let Some(rise) = aos.take() {
+                windows.push((rise, bisect(&mut margin_at, offsets[k - 1], offsets[k])));
+            }
+        }
+    }
+    if let Some(rise) = aos {
+        windows.push((rise, offsets[offsets.len() - 1]));
+    }
+
+    //the mask only decides when the satellite can be seen, the peak is of the elevation itself
+    let mut elevation_at = |t: f64| -> f64 {
+        match position_at(t) {
+            Some(p) => station.look(&p).elevation,
+            None => f64::NEG_INFINITY,
+        }
+    };
+    let events: Vec<(f64, f64, f64)> = windows.into_iter()
+        .map(|(rise, set)| (rise, golden_section_max(&mut elevation_at, rise, set), set))
+        .collect();
+
+    let mut look_at = |t: f64| position_at(t).map(|p| station.look(&p));
+    events.into_iter()
+        .filter_map(|(rise, peak, set)| {
+            Some(Pass {
+                satellite: id.to_string(),
+                station: station.name.clone(),
+                aos: start + Duration::from_seconds(rise),
+                tca: start + Duration::from_seconds(peak),
+                los: start + Duration::from_seconds(set),
+                aos_look: look_at(rise)?,
+                tca_look: look_at(peak)?,
+                los_look: look_at(set)?,
+            })
+        })
+        .collect()
+}
+
+//time in [lo, hi] where the margin crosses zero, given that it changes sign over the interval
+fn bisect(f: &mut impl FnMut(f64) -> f64, mut lo: f64, mut hi: f64) -> f64 {
+    let rising = f(lo) < 0.0;
+    while hi - lo > EVENT_TOLERANCE {
+        let mid = (lo + hi) / 2.0;
+        if (f(mid) < 0.0) == rising {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+fn golden_section_max(f: &mut impl FnMut(f64) -> f64, mut lo: f64, mut hi: f64) -> f64 {
+    let ratio = (5.0_f64.sqrt() - 1.0) / 2.0;
+    let mut x1 = hi - ratio * (hi - lo);
+    let mut x2 = lo + ratio * (hi - lo);
+    let (mut f1, mut f2) = (f(x1), f(x2));
+    while hi - lo > EVENT_TOLERANCE {
+        if f1 > f2 {
+            hi = x2;
+            x2 = x1;
+            f2 = f1;
+            x1 = hi - ratio * (hi - lo);
+            f1 = f(x1);
+        } else {
+            lo = x1;
+            x1 = x2;
+            f1 = f2;
+            x2 = lo + ratio * (hi - lo);
+            f2 = f(x2);
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+pub(crate) fn write_passes(passes: &[Pass], output_dir: &str) -> Result<()> {
+    fs::create_dir_all(output_dir)?;
+    let file = File::create(Path::new(output_dir).join("passes.csv"))?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "satellite,station,aos,tca,los,duration_s,max_elevation,aos_azimuth,tca_azimuth,los_azimuth,aos_range_m,tca_range_m,los_range_m")?;
+    for pass in passes {
+        writeln!(writer, "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            pass.satellite, pass.station, pass.aos.as_unixtime(), pass.tca.as_unixtime(), pass.los.as_unixtime(),
+            (pass.los - pass.aos).as_seconds(), pass.tca_look.elevation,
+            pass.aos_look.azimuth, pass.tca_look.azimuth, pass.los_look.azimuth,
+            pass.aos_look.range, pass.tca_look.range, pass.los_look.range)?;
+    }
+    writer.flush()?;
+    println!("Found {} passes", passes.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //straight line 500 km above a station on the equator at 0 longitude, passing 1000 km north of it
+    //heading east at 7 km/s, overhead of the closest point at 600 s, so the elevation peaks there
+    fn flyby(t: f64) -> Option<Vector3> {
+        Some(Vector3::new(satkit::consts::EARTH_RADIUS + 500e3, 7000.0 * (t - 600.0), 1000e3))
+    }
+
+    fn passes_over(station: &Station) -> Vec<Pass> {
+        let start = Instant::from_datetime(2024, 1, 1, 0, 0, 0.0);
+        let offsets: Vec<f64> = (0..=40).map(|k| k as f64 * 30.0).collect();
+        let positions: Vec<Option<Vector3>> = offsets.iter().map(|t| flyby(*t)).collect();
+        find_passes("1", station, &mut flyby, &offsets, &positions, start)
+    }
+
+    #[test]
+    fn mask_wraps_past_north() {
+        let station = Station::new("s".to_string(), 0.0, 0.0, 0.0, 0.0, vec![(10.0, 5.0), (350.0, 30.0)]);
+        assert!((station.horizon(0.0) - 17.5).abs() < 1e-12);
+        assert!((station.horizon(355.0) - 23.75).abs() < 1e-12);
+        assert!((station.horizon(5.0) - 11.25).abs() < 1e-12);
+        assert!((station.horizon(180.0) - (5.0 + 25.0 * 170.0 / 340.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn aos_and_los_are_on_the_horizon() {
+        let station = Station::new("s".to_string(), 0.0, 0.0, 0.0, 10.0, Vec::new());
+        let passes = passes_over(&station);
+        assert_eq!(passes.len(), 1);
+        let pass = &passes[0];
+        assert!(pass.aos < pass.tca && pass.tca < pass.los);
+        assert!((pass.aos_look.elevation - 10.0).abs() < 0.01);
+        assert!((pass.los_look.elevation - 10.0).abs() < 0.01);
+        //the track is symmetric about the closest point
+        let start = Instant::from_datetime(2024, 1, 1, 0, 0, 0.0);
+        assert!(((pass.aos - start).as_seconds() + (pass.los - start).as_seconds() - 1200.0).abs() < 2.0 * EVENT_TOLERANCE);
+    }
+
+    #[test]
+    fn tca_is_the_elevation_peak_on_a_masked_station() {
+        //the mask falls from west to east, so the margin above it peaks after the elevation does
+        let station = Station::new("s".to_string(), 0.0, 0.0, 0.0, 10.0, vec![(60.0, 10.0), (300.0, 20.0)]);
+        let passes = passes_over(&station);
+        assert_eq!(passes.len(), 1);
+        let pass = &passes[0];
+        let start = Instant::from_datetime(2024, 1, 1, 0, 0, 0.0);
+        assert!(((pass.tca - start).as_seconds() - 600.0).abs() < EVENT_TOLERANCE);
+        assert!((pass.tca_look.elevation - station.look(&flyby(600.0).unwrap()).elevation).abs() < 1e-6);
+        assert!((pass.aos_look.elevation - station.horizon(pass.aos_look.azimuth)).abs() < 0.01);
+        assert!((pass.los_look.elevation - station.horizon(pass.los_look.azimuth)).abs() < 0.01);
+    }
+}
\ No newline at end of file
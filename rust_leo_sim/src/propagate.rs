@@ -1,33 +1,122 @@
-use pyo3::prelude::*;
+use pyo3::{prelude::*, types::PyList};
+use numpy::{IntoPyArray, PyReadonlyArray2};
 use rayon::prelude::*;
 use std::{collections::HashMap, time::Instant};
+use anyhow::{anyhow, bail, Result};
 use crate::satellite::SatelliteRecord;
 
-pub(crate) fn propagate_satellites(satellites: HashMap<String, SatelliteRecord>) { 
-    Python::with_gil(|outer_py|{
-        let _ = outer_py.allow_threads(|| {
-            satellites.par_iter().for_each(|(id, satellite_record)| {
-                simulate(id, satellite_record);
+const LAST_GAP_MINUTES: f64 = 60.0 * 24.0; //the newest TLE has no next epoch, so it is propagated for a day
+
+pub(crate) struct BatchOptions {
+    pub(crate) batch_size: usize, //rows (TLE, tsince pairs) per python call, one model forward pass each
+    pub(crate) density: usize, //time steps per TLE, from its epoch to the next one
+}
+
+//ML-dSGP4 output for one satellite, rows in TLE order then time order
+#[derive(Default)]
+pub(crate) struct ModelStates {
+    pub(crate) tle_index: Vec<usize>, //into the satellite's epoch sorted orbital records
+    pub(crate) tsince: Vec<f64>, //minutes since that TLE's epoch
+    pub(crate) states: Vec<[f64; 6]>, //TEME, normalised like customMLDSGP4's output
+}
+
+//one TLE to propagate across the gap to the next one
+struct Job {
+    satellite: usize,
+    tle_index: usize,
+    gap_minutes: f64,
+}
+
+//python_env::init has to have run first so propagate can be imported. Each batch's rows go to consume per satellite and are
+//dropped after, so a satellite whose TLEs span batches comes in several pieces (in order). Returns the number of rows
+pub(crate) fn propagate_satellites(mut satellites: HashMap<String, SatelliteRecord>, options: &BatchOptions, mut consume: impl FnMut(&str, ModelStates) -> Result<()>) -> Result<usize> {
+    if options.batch_size == 0 || options.density == 0 {
+        bail!("Batch size and density have to be positive");
+    }
+    let mut ids: Vec<String> = satellites.keys().cloned().collect();
+    ids.sort();
+    satellites.par_iter_mut().for_each(|(_, record)| record.sort_by_epoch());
+
+    let jobs: Vec<Job> = ids.par_iter().enumerate()
+        .flat_map_iter(|(satellite, id)| {
+            let epochs: Vec<satkit::Instant> = satellites[id].orbital_records.iter().map(|instance| instance.epoch()).collect();
+            (0..epochs.len()).map(move |tle_index| Job {
+                satellite,
+                tle_index,
+                gap_minutes: match epochs.get(tle_index + 1) {
+                    Some(next) => (*next - epochs[tle_index]).as_seconds() / 60.0,
+                    None => LAST_GAP_MINUTES,
+                },
+            })
+        })
+        .collect();
+
+    //a TLE's steps are never split across calls, so a batch holds at least one TLE even if that overshoots batch_size
+    let jobs_per_batch = (options.batch_size / options.density).max(1);
+    let batch_count = jobs.len().div_ceil(jobs_per_batch);
+    println!("Propagating {} TLEs of {} satellites in {} batches", jobs.len(), ids.len(), batch_count);
+
+    let mut total_rows: usize = 0;
+    Python::with_gil(|py| -> Result<()> {
+        let propagate_batched = PyModule::import(py, "propagate")?.getattr("propagate_batched")?;
+        for (k, batch) in jobs.chunks(jobs_per_batch).enumerate() {
+            let time = Instant::now();
+            //tsince grids are built without the GIL, python only builds the TLE objects and runs the model
+            let (tle_rows, tsinces): (Vec<usize>, Vec<f64>) = py.allow_threads(|| {
+                let unzipped: Vec<(Vec<usize>, Vec<f64>)> = batch.par_iter().enumerate()
+                    .map(|(row, job)| (vec![row; options.density], linspace(job.gap_minutes, options.density)))
+                    .collect();
+                (unzipped.iter().flat_map(|(rows, _)| rows.iter().copied()).collect(),
+                 unzipped.iter().flat_map(|(_, tsinces)| tsinces.iter().copied()).collect())
             });
-        });
-    })
+            let records: Vec<PyObject> = batch.iter()
+                .map(|job| {
+                    let record = &satellites[&ids[job.satellite]];
+                    record.to_py_dict(py, &record.orbital_records[job.tle_index])
+                })
+                .collect();
+
+            let rows = tsinces.len();
+            let output = propagate_batched.call1((PyList::new(py, records)?, tle_rows.into_pyarray(py), tsinces.into_pyarray(py)))?;
+            let output: PyReadonlyArray2<f64> = output.extract()?;
+            let output = output.as_array();
+            if output.shape() != [rows, 6] {
+                bail!("propagate_batched returned shape {:?}, expected [{}, 6]", output.shape(), rows);
+            }
+
+            //every job has density rows in a row, so the job and its tsince follow from the row number
+            let mut states: HashMap<usize, ModelStates> = HashMap::new();
+            let mut order: Vec<usize> = Vec::new();
+            for (i, state) in output.rows().into_iter().enumerate() {
+                let job = &batch[i / options.density];
+                let satellite = states.entry(job.satellite).or_insert_with(|| {
+                    order.push(job.satellite);
+                    ModelStates::default()
+                });
+                satellite.tle_index.push(job.tle_index);
+                satellite.tsince.push(tsince_at(job.gap_minutes, options.density, i % options.density));
+                satellite.states.push([state[0], state[1], state[2], state[3], state[4], state[5]]);
+            }
+            for satellite in order {
+                consume(&ids[satellite], states.remove(&satellite).unwrap())?;
+            }
+            total_rows += rows;
+            println!("Batch {}/{}: {} rows in {}", k + 1, batch_count, rows, time.elapsed().as_secs_f64());
+        }
+        Ok(())
+    }).map_err(|e| anyhow!("ML-dSGP4 propagation failed: {e}"))?;
+    Ok(total_rows)
+}
+
+//count evenly spaced minutes from 0 to the end inclusive, like torch.linspace
+fn linspace(end: f64, count: usize) -> Vec<f64> {
+    (0..count).map(|j| tsince_at(end, count, j)).collect()
 }
 
-fn simulate(id: &String, satellite_record: &SatelliteRecord) {
-    Python::with_gil(|py| {
-        let sys = py.import("sys").expect("Couldn't import sys");
-        let path = sys.getattr("path").unwrap();
-        let path = path.downcast::<pyo3::types::PyList>().unwrap();
-        path.insert(0, "./python").unwrap();
-
-        let propagate_py = PyModule::import(py, "propagate").expect("Failed to import module");
-        let satellite_record_py = satellite_record.to_python(py);
-        
-        // let model = start_up_model.call0().expect("Failed to start up ml_dsgp4 model");
-        let simulator = propagate_py.getattr("propagate_between_gaps").expect("Failed to get 'propagate_between_gaps'");
-        println!("[{id}] Simulating Orbits");
-        let time = Instant::now();
-        let _sim_result = simulator.call1((satellite_record_py, 10000)).expect("Failed to simulate orbits");
-        println!("[{id} Finished simulating orbits in {}", time.elapsed().as_secs_f64())
-    });
+//the j-th of them
+fn tsince_at(end: f64, count: usize, j: usize) -> f64 {
+    if count == 1 {
+        return 0.0;
+    }
+    end * j as f64 / (count - 1) as f64
 }
\ No newline at end of file
@@ -0,0 +1,83 @@
+use std::{env, fs, path::{Path, PathBuf}, sync::OnceLock};
+use pyo3::{prelude::*, types::PyList};
+use anyhow::{anyhow, bail, Result};
+
+//what propagate.py needs, checked before any satellite is read so a broken environment fails straight away
+pub(crate) const REQUIRED_MODULES: [&str; 4] = ["torch", "customMLDSGP4", "CustomTLE", "propagate"];
+
+pub(crate) struct PythonEnv {
+    pub(crate) module_path: PathBuf, //directory with our python modules, ./python by default
+    pub(crate) venv: Option<PathBuf>, //virtualenv whose site-packages are added, $VIRTUAL_ENV when not given
+    pub(crate) required: Vec<String>,
+}
+
+impl PythonEnv {
+    pub(crate) fn new(module_path: &str, venv: Option<&str>) -> PythonEnv {
+        PythonEnv {
+            module_path: PathBuf::from(module_path),
+            venv: venv.map(PathBuf::from).or_else(|| env::var_os("VIRTUAL_ENV").map(PathBuf::from)),
+            required: REQUIRED_MODULES.iter().map(|module| module.to_string()).collect(),
+        }
+    }
+}
+
+static INITIALISED: OnceLock<PathBuf> = OnceLock::new();
+
+//sets up the embedded interpreter once per process, later calls only check they asked for the same module path
+pub(crate) fn init(python_env: &PythonEnv) -> Result<()> {
+    let module_path = fs::canonicalize(&python_env.module_path)
+        .map_err(|e| anyhow!("Python module path {} not found: {e}", python_env.module_path.display()))?;
+    if let Some(initialised) = INITIALISED.get() {
+        if *initialised != module_path {
+            bail!("Python was already set up with {}, not {}", initialised.display(), module_path.display());
+        }
+        return Ok(());
+    }
+
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| -> Result<()> {
+        let sys = py.import("sys")?;
+        if let Some(venv) = &python_env.venv {
+            let site_packages = site_packages(py, venv)?;
+            py.import("site")?.call_method1("addsitedir", (site_packages.to_string_lossy().to_string(),))?;
+        }
+
+        let path = sys.getattr("path")?;
+        let path = path.downcast::<PyList>().map_err(|e| anyhow!("sys.path is not a list: {e}"))?;
+        let module_path_str = module_path.to_string_lossy().to_string();
+        if !path.contains(&module_path_str)? {
+            path.insert(0, &module_path_str)?;
+        }
+
+        let missing: Vec<String> = python_env.required.iter()
+            .filter_map(|module| py.import(module.as_str()).err().map(|e| format!("  {module}: {e}")))
+            .collect();
+        if !missing.is_empty() {
+            let version: String = sys.getattr("version")?.extract()?;
+            bail!("Could not import the required python modules with python {} (module path {}, virtualenv {}):\n{}",
+                version.split_whitespace().next().unwrap_or_default(), module_path.display(),
+                python_env.venv.as_ref().map(|venv| venv.display().to_string()).unwrap_or("none".to_string()),
+                missing.join("\n"));
+        }
+        Ok(())
+    })?;
+
+    let _ = INITIALISED.set(module_path);
+    Ok(())
+}
+
+//lib/pythonX.Y/site-packages for the version embedded here, Lib/site-packages on windows
+fn site_packages(py: Python, venv: &Path) -> Result<PathBuf> {
+    let (major, minor): (u8, u8) = {
+        let version = py.version_info();
+        (version.major, version.minor)
+    };
+    let candidates = [
+        venv.join("lib").join(format!("python{major}.{minor}")).join("site-packages"),
+        venv.join("Lib").join("site-packages"),
+    ];
+    match candidates.iter().find(|candidate| candidate.is_dir()) {
+        Some(site_packages) => Ok(site_packages.clone()),
+        None => bail!("No site-packages for python {major}.{minor} in virtualenv {}, was it made with a different python?", venv.display()),
+    }
+}
@@ -0,0 +1,279 @@
+use numpy::{ndarray::{Array1, Array2, Array3, ArrayView2}, IntoPyArray, PyArray1, PyArray2, PyArray3, PyReadonlyArray1, PyReadonlyArray2};
+use pyo3::{exceptions::PyRuntimeError, prelude::*, types::PyDict};
+use rayon::prelude::*;
+use satkit::{orbitprop::SatState, sgp4::{sgp4, SGP4Error}, types::Vector3, Instant, TLE};
+use anyhow::anyhow;
+use crate::elements::{mean_elements, ElementSet, DERIVED_COLUMNS};
+use crate::frames::{convert, Frame};
+use crate::maneuver::ManeuverHandling;
+use crate::numerical_integration::{integrate_between_gaps, integration_settings, sgp4_gcrf, tle_teme_to_gcrf, GapLimits, IntegrationOptions, Sampling};
+use crate::satellite::OrbitalInstance;
+use crate::read::{read_tles, read_txt_integrated, LEO_MAX_ECCENTRICITY, LEO_MIN_MEAN_MOTION};
+use crate::training::{tle_features, TLE_FEATURES};
+
+//the crate as a python module, built with `maturin develop --release` (see pyproject.toml)
+//every array is float64, times are unix seconds and states are in meters and m/s
+
+fn to_py_err(e: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+fn parse_tles(lines1: &[String], lines2: &[String]) -> anyhow::Result<Vec<TLE>> {
+    if lines1.len() != lines2.len() {
+        return Err(anyhow!("Got {} first lines and {} second lines", lines1.len(), lines2.len()));
+    }
+    lines1.iter().zip(lines2.iter())
+        .map(|(line1, line2)| TLE::load_2line(line1, line2).map_err(|e| anyhow!("Invalid TLE {line1}: {e}")))
+        .collect()
+}
+
+//one satellite's history in epoch order, as the integration modes read it
+fn history(lines1: &[String], lines2: &[String]) -> anyhow::Result<Vec<TLE>> {
+    let mut tles = parse_tles(lines1, lines2)?;
+    tles.sort_by(|a, b| a.epoch.partial_cmp(&b.epoch).unwrap());
+    Ok(tles)
+}
+
+//epoch followed by TLE_FEATURES
+fn element_columns() -> Vec<&'static str> {
+    let mut columns: Vec<&'static str> = vec!["epoch"];
+    columns.extend(TLE_FEATURES);
+    columns
+}
+
+//{catalog number: (N, 10) array of epoch and elements in file order}, only LEO TLEs unless leo_only is False
+#[pyfunction]
+#[pyo3(signature = (path, leo_only=true))]
+fn read_tle_file<'py>(py: Python<'py>, path: &str, leo_only: bool) -> PyResult<Bound<'py, PyDict>> {
+    let satellites = py.allow_threads(|| read_tles(path, leo_only)).map_err(to_py_err)?;
+    let dict = PyDict::new(py);
+    for (id, tles) in satellites {
+        let mut rows: Vec<f64> = Vec::with_capacity(tles.len() * element_columns().len());
+        for tle in tles.iter() {
+            rows.push(tle.epoch.as_unixtime());
+            rows.extend(tle_features(tle));
+        }
+        let array = Array2::from_shape_vec((tles.len(), element_columns().len()), rows).map_err(|e| to_py_err(e.into()))?;
+        dict.set_item(id, array.into_pyarray(py))?;
+    }
+    Ok(dict)
+}
+
+//the same LEO filter the readers use, on rows laid out like read_tle_file's arrays
+#[pyfunction]
+fn leo_mask<'py>(py: Python<'py>, elements: PyReadonlyArray2<'py, f64>) -> PyResult<Bound<'py, PyArray1<bool>>> {
+    Ok(leo_rows(elements.as_array()).into_pyarray(py))
+}
+
+fn leo_rows(elements: ArrayView2<f64>) -> Array1<bool> {
+    let eccentricity = element_columns().iter().position(|c| *c == "eccentricity").unwrap();
+    let mean_motion = element_columns().iter().position(|c| *c == "mean_motion").unwrap();
+    elements.rows().into_iter()
+        .map(|row| row[eccentricity] < LEO_MAX_ECCENTRICITY && row[mean_motion] > LEO_MIN_MEAN_MOTION)
+        .collect()
+}
+
+//(N TLEs, T times, 6) TEME states, NaN where SGP4 fails, run in parallel without holding the GIL
+#[pyfunction]
+fn sgp4_batch<'py>(py: Python<'py>, lines1: Vec<String>, lines2: Vec<String>, times: PyReadonlyArray1<'py, f64>) -> PyResult<Bound<'py, PyArray3<f64>>> {
+    let tles = parse_tles(&lines1, &lines2).map_err(to_py_err)?;
+    let times: Vec<Instant> = times.as_array().iter().map(|t| Instant::from_unixtime(*t)).collect();
+    let rows: Vec<f64> = py.allow_threads(|| sgp4_rows(tles, &times));
+    let array = Array3::from_shape_vec((lines1.len(), times.len(), 6), rows).map_err(|e| to_py_err(e.into()))?;
+    Ok(array.into_pyarray(py))
+}
+
+fn sgp4_rows(tles: Vec<TLE>, times: &[Instant]) -> Vec<f64> {
+    tles.into_par_iter()
+        .flat_map_iter(|mut tle| {
+            let (r, v, errs) = sgp4(&mut tle, times);
+            (0..times.len())
+                .flat_map(|i| match errs.get(i) {
+                    Some(SGP4Error::SGP4Success) => [r[(0, i)], r[(1, i)], r[(2, i)], v[(0, i)], v[(1, i)], v[(2, i)]],
+                    _ => [f64::NAN; 6],
+                })
+                .collect::<Vec<f64>>()
+        })
+        .collect()
+}
+
+//(N, 6) osculating elements of (N, 6) inertial states, "keplerian" (a, e, i, raan, arg_perigee, mean_anomaly)
+//or "equinoctial" (a, h, k, p, q, mean_longitude), a in meters and angles in degrees
+#[pyfunction]
+#[pyo3(signature = (states, elements="keplerian"))]
+fn to_elements<'py>(py: Python<'py>, states: PyReadonlyArray2<'py, f64>, elements: &str) -> PyResult<Bound<'py, PyArray2<f64>>> {
+    let set: ElementSet = elements.parse().map_err(to_py_err)?;
+    let states = states.as_array();
+    if states.ncols() != 6 {
+        return Err(to_py_err(anyhow!("Expected (N, 6) states, got {:?}", states.shape())));
+    }
+    let rows: Vec<f64> = states.rows().into_iter()
+        .flat_map(|row| set.elements_of(&Vector3::new(row[0], row[1], row[2]), &Vector3::new(row[3], row[4], row[5])))
+        .collect();
+    let array = Array2::from_shape_vec((states.nrows(), 6), rows).map_err(|e| to_py_err(e.into()))?;
+    Ok(array.into_pyarray(py))
+}
+
+//the inverse of to_elements
+#[pyfunction]
+#[pyo3(signature = (elements_array, elements="keplerian"))]
+fn from_elements<'py>(py: Python<'py>, elements_array: PyReadonlyArray2<'py, f64>, elements: &str) -> PyResult<Bound<'py, PyArray2<f64>>> {
+    let set: ElementSet = elements.parse().map_err(to_py_err)?;
+    let elements_array = elements_array.as_array();
+    if elements_array.ncols() != 6 {
+        return Err(to_py_err(anyhow!("Expected (N, 6) elements, got {:?}", elements_array.shape())));
+    }
+    let rows: Vec<f64> = elements_array.rows().into_iter()
+        .flat_map(|row| {
+            let (pos, vel) = set.to_cartesian(&[row[0], row[1], row[2], row[3], row[4], row[5]]);
+            [pos[0], pos[1], pos[2], vel[0], vel[1], vel[2]]
+        })
+        .collect();
+    let array = Array2::from_shape_vec((elements_array.nrows(), 6), rows).map_err(|e| to_py_err(e.into()))?;
+    Ok(array.into_pyarray(py))
+}
+
+//(N, 9) mean Keplerian elements of each TLE followed by DERIVED_COLUMNS, laid out like to_elements
+#[pyfunction]
+fn tle_mean_elements<'py>(py: Python<'py>, lines1: Vec<String>, lines2: Vec<String>) -> PyResult<Bound<'py, PyArray2<f64>>> {
+    let tles = parse_tles(&lines1, &lines2).map_err(to_py_err)?;
+    let rows: Vec<f64> = tles.iter()
+        .flat_map(|tle| {
+            let keplerian = mean_elements(&OrbitalInstance::from_tle(tle));
+            let derived = keplerian.derived();
+            [keplerian.semi_major_axis, keplerian.eccentricity, keplerian.inclination.to_degrees(), keplerian.raan.to_degrees(),
+                keplerian.arg_perigee.to_degrees(), keplerian.mean_anomaly.to_degrees(), derived.perigee_altitude, derived.apogee_altitude, derived.period]
+        })
+        .collect();
+    let array = Array2::from_shape_vec((tles.len(), 6 + DERIVED_COLUMNS.len()), rows).map_err(|e| to_py_err(e.into()))?;
+    Ok(array.into_pyarray(py))
+}
+
+//rows of time and state, and the TLE index of each row
+type IntegratedArrays<'py> = (Bound<'py, PyArray2<f64>>, Bound<'py, PyArray1<usize>>);
+
+//numerically integrates one satellite's TLE history between its epochs like the "n" mode does, in any order it is given,
+//returns (M, 7) rows of time and state in the frame and the index (into the epoch sorted TLEs) each row was integrated from
+#[pyfunction]
+#[pyo3(signature = (lines1, lines2, sampling="count:5000", frame="gcrf"))]
+fn integrate_satellite<'py>(py: Python<'py>, lines1: Vec<String>, lines2: Vec<String>, sampling: &str, frame: &str) -> PyResult<IntegratedArrays<'py>> {
+    let tles = history(&lines1, &lines2).map_err(to_py_err)?;
+    let sampling: Sampling = sampling.parse().map_err(to_py_err)?;
+    let frame: Frame = frame.parse().map_err(to_py_err)?;
+    let (rows, tle_indices) = py.allow_threads(|| -> anyhow::Result<(Vec<f64>, Vec<usize>)> {
+        let options = IntegrationOptions {
+            sampling,
+            compression_level: 0,
+            maneuvers: ManeuverHandling::Ignore,
+            maneuver_sigma: 0.0,
+            frames: Default::default(),
+            gaps: GapLimits::default(),
+            samples: None,
+            drag: None,
+            covariance: None,
+        };
+        let (tles, states) = tle_teme_to_gcrf(tles)?;
+        let id: String = tles.first().map(|tle| tle.sat_num.to_string()).unwrap_or_default();
+        let (results, _report) = integrate_between_gaps(&id, &tles, &states, &integration_settings(), &options)?;
+
+        let (mut rows, mut tle_indices): (Vec<f64>, Vec<usize>) = (Vec::new(), Vec::new());
+        for (segment, result) in results {
+            let tle = &tles[segment.tle_index];
+            let steps: Vec<SatState> = result.sample(&options.sampling, tle.mean_motion)?;
+            let references: Option<Vec<SatState>> = (frame == Frame::Rtn)
+                .then(|| sgp4_gcrf(&mut tle.clone(), &steps.iter().map(|step| step.time).collect::<Vec<Instant>>()));
+            for (i, step) in steps.iter().enumerate() {
+                let (pos, vel) = convert(step, frame, references.as_ref().map(|r| &r[i]))?;
+                rows.extend([step.time.as_unixtime(), pos[0], pos[1], pos[2], vel[0], vel[1], vel[2]]);
+                tle_indices.push(segment.tle_index);
+            }
+        }
+        Ok((rows, tle_indices))
+    }).map_err(to_py_err)?;
+    let array = Array2::from_shape_vec((tle_indices.len(), 7), rows).map_err(|e| to_py_err(e.into()))?;
+    Ok((array.into_pyarray(py), tle_indices.into_pyarray(py)))
+}
+
+//(frame, (N, 7) rows of time and state) from an integration output file, the state in GCRF for TEME, GCRF and ITRF files
+//and the offsets as written for RTN ones
+#[pyfunction]
+fn read_integrated<'py>(py: Python<'py>, path: &str) -> PyResult<(String, Bound<'py, PyArray2<f64>>)> {
+    let (frame, states) = py.allow_threads(|| read_txt_integrated(path)).map_err(to_py_err)?;
+    let array = Array2::from_shape_vec((states.len(), 7), state_rows(&states)).map_err(|e| to_py_err(e.into()))?;
+    Ok((frame.to_string(), array.into_pyarray(py)))
+}
+
+fn state_rows(states: &[SatState]) -> Vec<f64> {
+    states.iter()
+        .flat_map(|state| {
+            let mut row = vec![state.time.as_unixtime()];
+            row.extend(state.pv.iter());
+            row
+        })
+        .collect()
+}
+
+#[pymodule]
+pub fn rust_leo_sim(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("ELEMENT_COLUMNS", element_columns())?;
+    m.add_function(wrap_pyfunction!(read_tle_file, m)?)?;
+    m.add_function(wrap_pyfunction!(leo_mask, m)?)?;
+    m.add_function(wrap_pyfunction!(sgp4_batch, m)?)?;
+    m.add_function(wrap_pyfunction!(integrate_satellite, m)?)?;
+    m.add_function(wrap_pyfunction!(read_integrated, m)?)?;
+    m.add_function(wrap_pyfunction!(to_elements, m)?)?;
+    m.add_function(wrap_pyfunction!(from_elements, m)?)?;
+    m.add_function(wrap_pyfunction!(tle_mean_elements, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //the ISS at another epoch, with the checksum redone
+    fn iss_at(epoch: &str) -> (String, String) {
+        let line1 = format!("1 25544U 98067A   {epoch} -.00002182  00000-0 -11606-4 0  292");
+        let checksum: u32 = line1.chars().map(|c| if c == '-' { 1 } else { c.to_digit(10).unwrap_or(0) }).sum();
+        (format!("{line1}{}", checksum % 10), "2 25544  51.6416 247.4627 0006703 130.5360 325.0288 15.72125391563537".to_string())
+    }
+
+    #[test]
+    fn history_parses_and_sorts_by_epoch() {
+        let (lines1, lines2): (Vec<String>, Vec<String>) = ["08266.5", "08264.5", "08265.5"].iter().map(|epoch| iss_at(&format!("{epoch}0000000"))).unzip();
+        let tles = history(&lines1, &lines2).unwrap();
+        let days: Vec<f64> = tles.iter().map(|tle| (tle.epoch - tles[0].epoch).as_days()).collect();
+        assert!(days.iter().zip([0.0, 1.0, 2.0]).all(|(day, expected)| (day - expected).abs() < 1e-6), "{days:?}");
+
+        assert!(parse_tles(&lines1, &lines2[..2]).is_err());
+    }
+
+    #[test]
+    fn leo_rows_filter_like_the_readers() {
+        let columns = element_columns();
+        let (eccentricity, mean_motion) = (columns.iter().position(|c| *c == "eccentricity").unwrap(), columns.iter().position(|c| *c == "mean_motion").unwrap());
+        let mut elements = Array2::<f64>::zeros((3, columns.len()));
+        for (row, (e, n)) in [(0.0007, 15.72), (0.0002, 1.0027), (0.7, 12.0)].iter().enumerate() {
+            elements[(row, eccentricity)] = *e;
+            elements[(row, mean_motion)] = *n;
+        }
+        assert_eq!(leo_rows(elements.view()).to_vec(), vec![true, false, false]);
+    }
+
+    #[test]
+    fn sgp4_rows_are_nan_where_sgp4_fails() {
+        let (line1, line2) = iss_at("08264.51782528");
+        let mut decaying = TLE::load_2line(&line1, &line2).unwrap();
+        decaying.bstar = 0.05;
+        let tles = vec![TLE::load_2line(&line1, &line2).unwrap(), decaying];
+        let times = [tles[0].epoch, tles[0].epoch + satkit::Duration::from_days(60.0)];
+        let rows = sgp4_rows(tles, &times);
+        assert_eq!(rows.len(), 2 * 2 * 6);
+        assert!(rows[..12].iter().all(|value| value.is_finite()));
+        assert!((Vector3::new(rows[0], rows[1], rows[2]).norm() - 6.72e6).abs() < 1.0e5);
+        assert!(rows[18..].iter().all(|value| value.is_nan()));
+
+        let state = SatState::from_pv(&times[0], &Vector3::new(rows[0], rows[1], rows[2]), &Vector3::new(rows[3], rows[4], rows[5]));
+        assert_eq!(state_rows(&[state]), [&[times[0].as_unixtime()], &rows[..6]].concat());
+    }
+}
@@ -1,147 +1,244 @@
-use std::{fs::read_to_string, collections::HashMap, thread};
+use std::{fs::read_to_string, collections::HashMap, path::PathBuf};
+use rayon::{prelude::*, ThreadPoolBuilder};
 use crate::satellite::{OrbitalInstance, SatelliteRecord};
-use anyhow::{bail, Ok, Result};
+use anyhow::{anyhow, bail, Ok, Result};
 use satkit::{orbitprop::SatState, types::Vector3};
+use crate::frames::{parse_header, to_gcrf, Frame, HEADER_PREFIX};
 use crate::merge::merge_satellite_hashmaps;
 use std::fs;
 use std::io;
 use std::path::Path;
 use satkit::tle::TLE;
-use sgp4::chrono::{TimeZone, Utc, Datelike, Timelike};
-
-pub(crate) fn read_txt_files(year_start:u8, year_end:u8) -> HashMap<String, SatelliteRecord> {
-    let mut handles = vec![];
-
-    for i in year_start..year_end {
-        let handle = thread::Builder::new()
-        .name(String::from(format!("Data Reader for i = {i}")))
-        .spawn(move || -> Result<HashMap<String, SatelliteRecord>> { //creates a thread for each .txt file
-            let filepath: String = format!("./data/tle20{:02}.txt", i);
-            // let _ = clean_file(&filepath);
-            let time: std::time::Instant = std::time::Instant::now();
-            println!("[{i}] Now reading");
-            let satellites: HashMap<String, SatelliteRecord> = read_txt(&filepath).expect("Failed to read txt file");
-            println!("[{i}] Reading is done");
-            println!("[{i}] Read in {} seconds", time.elapsed().as_secs_f64());
-            println!("[{i}] Size of satellites = {}", satellites.len());
-            Ok(satellites)
-        }).expect("Failed to spawn thread");
-        handles.push(handle);
+
+pub(crate) const LEO_MAX_ECCENTRICITY: f64 = 0.25;
+pub(crate) const LEO_MIN_MEAN_MOTION: f64 = 11.25; //rev/day
+
+const CHUNK_LINES: usize = 20_000; //lines parsed per task (even, so TLE pairs stay together), a single huge year file is spread over the pool
+
+//reads every file the inputs name (paths, directories of .txt files or globs like ./data/tle20*.txt) on a rayon pool,
+//at most `threads` at a time when given, merging each file's satellites in as it finishes
+pub(crate) fn read_many(inputs: &[String], threads: Option<usize>) -> Result<HashMap<String, SatelliteRecord>> {
+    let files = expand_inputs(inputs)?;
+    if files.is_empty() {
+        bail!("No TLE files found for {}", inputs.join(", "));
+    }
+    let satellites = on_pool(threads, || {
+        let mut satellites = files.par_iter()
+            .map(|file| -> Result<HashMap<String, SatelliteRecord>> {
+                let time = std::time::Instant::now();
+                let satellites = read_txt(&file.to_string_lossy())?;
+                println!("Read {} ({} satellites) in {} seconds", file.display(), satellites.len(), time.elapsed().as_secs_f64());
+                Ok(satellites)
+            })
+            .try_reduce(HashMap::new, |mut kept, lost| {
+                merge_satellite_hashmaps(&mut kept, lost)?;
+                Ok(kept)
+            })?;
+        satellites.par_iter_mut().for_each(|(_, record)| record.sort_by_epoch()); //files can come in any order
+        Ok(satellites)
+    })?;
+    println!("Read {} files, {} satellites", files.len(), satellites.len());
+    Ok(satellites)
+}
+
+//runs the work on a pool of `threads` when given, otherwise on rayon's global one
+fn on_pool<T: Send>(threads: Option<usize>, work: impl FnOnce() -> Result<T> + Send) -> Result<T> {
+    match threads {
+        Some(threads) => ThreadPoolBuilder::new().num_threads(threads).build()?.install(work),
+        None => work(),
     }
+}
+
+//files in the order given, directories and globs expanded in name order, each file once
+pub(crate) fn expand_inputs(inputs: &[String]) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        let mut expanded: Vec<PathBuf> = if path.is_dir() {
+            list_dir(path, |name| name.ends_with(".txt"))?
+        } else if input.contains(['*', '?']) {
+            //only the file name can have wildcards, e.g. ./data/tle20*.txt
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            let pattern = path.file_name().and_then(|name| name.to_str()).ok_or_else(|| anyhow!("Invalid pattern {input}"))?;
+            if dir.to_string_lossy().contains(['*', '?']) {
+                bail!("Only the file name can have wildcards: {input}");
+            }
+            list_dir(dir, |name| wildcard_match(pattern, name))?
+        } else if path.is_file() {
+            vec![path.to_path_buf()]
+        } else {
+            bail!("{input} is not a file, directory or pattern");
+        };
+        expanded.retain(|file| !files.contains(file));
+        files.extend(expanded);
+    }
+    Ok(files)
+}
+
+fn list_dir(dir: &Path, keep: impl Fn(&str) -> bool) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?
+        .into_iter()
+        .filter(|path| path.is_file() && path.file_name().and_then(|name| name.to_str()).is_some_and(&keep))
+        .collect();
+    files.sort();
+    Ok(files)
+}
 
-    //combine all satellite hashmaps from each year into one
-    let mut satellites: HashMap<String, SatelliteRecord> = HashMap::new();
-    for handle in handles {
-        let thread_maps = handle.join().unwrap().unwrap();
-        let _ = merge_satellite_hashmaps(&mut satellites, thread_maps); //merges in place doesn't return anything
-        println!("Merged with a thread, new size: {}", satellites.len())
+//* matches any run of characters and ? any single one
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None; //position after the last * and the name position it is matching from
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p + 1, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            p = star_p;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
     }
-    
-    return satellites;
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+//./data/tle<year>.txt for every year from first to last inclusive, two digit years are 20xx
+pub(crate) fn year_files(first: u16, last: u16) -> Vec<String> {
+    let full_year = |year: u16| if year < 100 { 2000 + year } else { year };
+    (full_year(first)..=full_year(last)).map(|year| format!("./data/tle{}.txt", year)).collect()
 }
 
-fn read_txt(filepath: &str) -> Result<HashMap<String, SatelliteRecord>> {
+//LEO satellites of one two line file, parsed in chunks on the rayon pool and merged back in file order
+pub(crate) fn read_txt(filepath: &str) -> Result<HashMap<String, SatelliteRecord>> {
     let file_contents = read_to_string(filepath)?;
     let lines: Vec<&str> = file_contents.lines().collect();
-    let mut satellites: HashMap<String, SatelliteRecord> = HashMap::new();
+    if lines.len() % 2 == 1 {
+        bail!("Last line (no pair): {}", lines[lines.len() - 1]);
+    }
 
-    for chunk in lines.chunks(2).into_iter() {
-        if chunk.len() < 2 {
-            bail!("Last line (no pair): {}", chunk[0]);
-        }
+    lines.par_chunks(CHUNK_LINES)
+        .map(|chunk| -> Result<HashMap<String, SatelliteRecord>> {
+            let mut satellites: HashMap<String, SatelliteRecord> = HashMap::new();
+            for pair in chunk.chunks(2) {
+                let line1 = pair[0].trim_end_matches('\\');
+                let line2 = pair[1].trim_end_matches('\\');
 
-        let line1 = chunk[0].trim_end_matches('\\');
-        let line2 = chunk[1].trim_end_matches('\\');
+                let tle = TLE::load_2line(line1, line2).map_err(|e| anyhow!("Invalid TLE in {filepath}: {line1}: {e}"))?;
 
-        let tle = TLE::load_2line(line1, line2).unwrap();
+                if is_leo(&tle) {
+                    let id = tle.sat_num.to_string();
 
-        if tle.eccen < 0.25 && tle.mean_motion > 11.25 {
-            let id = tle.sat_num.to_string();
+                    let instance = OrbitalInstance::from_tle(&tle);
 
-            let unix_time: f64 = tle.epoch.as_unixtime();
-            let whole_seconds = unix_time.trunc() as i64;
-            let fractional_part = unix_time - unix_time.trunc();
-            let nanos = (fractional_part * 1.0e9) as u32;
-
-            let dt = Utc.timestamp_opt(whole_seconds, nanos).unwrap();
-        
-            let year = dt.year();
-            let day_of_year = dt.ordinal();
-            let fraction_of_day = (dt.hour() as f64
-            + (dt.minute() as f64 / 60.0)
-            + (dt.second() as f64 / 3600.0)
-            + (dt.nanosecond() as f64 / 3.6e12)) / 24.0;  
-            let day_of_year_fractional = (day_of_year as f64) + fraction_of_day;
-
-            let instance = OrbitalInstance {
-                epoch_year:           year,
-                epoch_day:            day_of_year_fractional,
-                first_time_derivative: tle.mean_motion_dot,
-                second_time_derivative: tle.mean_motion_dot_dot,
-                drag:                 tle.bstar,
-                inclination:          tle.inclination,
-                raan:                 tle.raan,
-                eccentricity:         tle.eccen,
-                perigee:              tle.raan,
-                mean_anomaly:         tle.mean_anomaly,
-                mean_motion:          tle.mean_motion,
-            };
-
-            satellites
-                .entry(id)
-                .and_modify(|sat_rec| sat_rec.orbital_records.push(instance.clone()))
-                .or_insert_with(|| SatelliteRecord {
-                    catalog_number: tle.sat_num,
-                    international_designator: tle.intl_desig.clone(),
-                    orbital_records: vec![instance],
-                });
-        }
-    }
+                    satellites
+                        .entry(id)
+                        .and_modify(|sat_rec| sat_rec.orbital_records.push(instance.clone()))
+                        .or_insert_with(|| SatelliteRecord {
+                            catalog_number: tle.sat_num,
+                            international_designator: tle.intl_desig.clone(),
+                            orbital_records: vec![instance],
+                            satcat: None,
+                        });
+                }
+            }
+            Ok(satellites)
+        })
+        .try_reduce(HashMap::new, |mut kept, lost| {
+            merge_satellite_hashmaps(&mut kept, lost)?;
+            Ok(kept)
+        })
+}
 
+//LEO TLEs of every file the inputs name, read on the pool as for read_many, put in epoch order when they come from more than one file
+pub(crate) fn read_txt_for_integration(inputs: &[String], threads: Option<usize>) -> Result<HashMap<String, Vec<TLE>>> {
+    println!("Creating TLE structs out of lines");
+    let time = std::time::Instant::now();
+    let files = expand_inputs(inputs)?;
+    if files.is_empty() {
+        bail!("No TLE files found for {}", inputs.join(", "));
+    }
+    let satellites = on_pool(threads, || {
+        let mut satellites = files.par_iter()
+            .map(|file| read_tles(&file.to_string_lossy(), true))
+            .try_reduce(HashMap::new, |mut kept, lost| {
+                for (id, tles) in lost {
+                    kept.entry(id).or_default().extend(tles);
+                }
+                Ok(kept)
+            })?;
+        if files.len() > 1 {
+            satellites.par_iter_mut().for_each(|(_, tles)| tles.sort_by(|a, b| a.epoch.partial_cmp(&b.epoch).unwrap()));
+        }
+        Ok(satellites)
+    })?;
+    println!("Finished! \n Size of HashMap: {} \n Time Elapsed (s): {}", satellites.len(), time.elapsed().as_secs());
     Ok(satellites)
 }
 
-pub(crate) fn read_txt_for_integration(filepath: &str) -> Result<HashMap<String, Vec<TLE>>> {
+//TLEs grouped by catalog number in file order, optionally only the low earth orbit ones
+pub(crate) fn read_tles(filepath: &str, leo_only: bool) -> Result<HashMap<String, Vec<TLE>>> {
     let file_contents = read_to_string(filepath)?;
     let lines: Vec<&str> = file_contents.lines().collect();
 
-    println!("Creating TLE structs out of lines");
-    let time = std::time::Instant::now();
     let mut satellites: HashMap<String, Vec<TLE>> = HashMap::new();
-    for satellite_tle in lines.chunks(2).into_iter() {
-        let tle: TLE = TLE::load_2line(satellite_tle[0], satellite_tle[1]).unwrap();
+    for satellite_tle in lines.chunks(2) {
+        if satellite_tle.len() < 2 {
+            bail!("Last line (no pair): {}", satellite_tle[0]);
+        }
+        let tle: TLE = TLE::load_2line(satellite_tle[0], satellite_tle[1]).map_err(|e| anyhow!("Invalid TLE {}: {e}", satellite_tle[0]))?;
 
-        if tle.eccen < 0.25 && tle.mean_motion > 11.25 {
+        if !leo_only || is_leo(&tle) {
             let id = tle.sat_num.to_string();
-            if satellites.contains_key(&id) {
-                satellites.get_mut(&id).unwrap().push(tle);
-            } else {
-                let mut tle_vec: Vec<TLE> = Vec::new();
-                tle_vec.push(tle);
-                satellites.insert(id, tle_vec);
-            }
+            satellites.entry(id).or_default().push(tle);
         }
     }
-    println!("Finished! \n Size of HashMap: {} \n Time Elapsed (s): {}", satellites.len(), time.elapsed().as_secs());
     Ok(satellites)
 }
 
-#[allow(dead_code)]
-pub(crate) fn read_txt_integrated(filepath: &str) -> Result<Vec<SatState>> {
-    let file_contents = read_to_string(filepath)?;
-    let lines: Vec<&str> = file_contents.lines().collect();
+//the near circular, more than 11.25 rev/day orbits the model is trained on
+pub(crate) fn is_leo(tle: &TLE) -> bool {
+    tle.eccen < LEO_MAX_ECCENTRICITY && tle.mean_motion > LEO_MIN_MEAN_MOTION
+}
+
+//reads the states of an integration output file (plain or .zst), skipping the TLE lines in between
+//returns the frame from the header and the states converted back to GCRF, RTN offsets are returned as written
+pub(crate) fn read_txt_integrated(filepath: &str) -> Result<(Frame, Vec<SatState>)> {
+    let file_contents = if filepath.ends_with(".zst") {
+        String::from_utf8(zstd::decode_all(fs::File::open(filepath)?)?)?
+    } else {
+        read_to_string(filepath)?
+    };
 
+    let mut frame = Frame::Gcrf; //files written before the header was added are GCRF
     let mut states: Vec<SatState> = Vec::new();
 
-    for line in lines.into_iter() {
+    for line in file_contents.lines() {
+        if let Some(header_frame) = parse_header(line)? {
+            frame = header_frame;
+            continue;
+        }
+        if line.starts_with(HEADER_PREFIX) || !line.contains(',') { //segment lines and TLE lines
+            continue;
+        }
         let line_split: Vec<&str> = line.split(",").collect();
-        let epoch: satkit::Instant = satkit::Instant::new(line_split[0].parse::<i64>()?);
+        let epoch: satkit::Instant = satkit::Instant::from_unixtime(line_split[0].trim().parse::<f64>()?);
         let pos = Vector3::new(line_split[1].parse()?, line_split[2].parse()?, line_split[3].parse()?);
         let vel = Vector3::new(line_split[4].parse()?, line_split[5].parse()?, line_split[6].parse()?);
-        let state = SatState::from_pv(&epoch, &pos, &vel);
+        let state = if frame == Frame::Rtn {
+            SatState::from_pv(&epoch, &pos, &vel)
+        } else {
+            to_gcrf(&epoch, &pos, &vel, frame)?
+        };
         states.push(state);
     } 
-    Ok(states)
+    Ok((frame, states))
 }
 
 #[allow(dead_code)]
@@ -157,4 +254,62 @@ fn clean_file<P: AsRef<Path>>(path: P) -> io::Result<()> { //sometimes needed if
         .collect();
     let new_content = filtered_lines.join("\n");
     fs::write(path, new_content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_runs_and_single_characters() {
+        assert!(wildcard_match("tle20*.txt", "tle2024.txt"));
+        assert!(wildcard_match("tle20*.txt", "tle20.txt"));
+        assert!(wildcard_match("tle20??.txt", "tle2006.txt"));
+        assert!(!wildcard_match("tle20??.txt", "tle200.txt"));
+        assert!(wildcard_match("*a*b", "xxaxxab")); //the first a has to be given up for the second
+        assert!(!wildcard_match("*.txt", "tle2024.txt.zst"));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("?", ""));
+    }
+
+    #[test]
+    fn inputs_expand_in_order_without_repeats() {
+        let dir = std::env::temp_dir().join(format!("expand_inputs_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("more")).unwrap();
+        for name in ["tle2007.txt", "tle2006.txt", "notes.md", "more/tle2010.txt"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let path = |name: &str| dir.join(name);
+        let input = |name: &str| path(name).to_string_lossy().to_string();
+
+        let files = expand_inputs(&[input("tle2007.txt"), input("tle200?.txt"), input("more")]).unwrap();
+        assert_eq!(files, vec![path("tle2007.txt"), path("tle2006.txt"), path("more").join("tle2010.txt")]);
+        assert_eq!(expand_inputs(&[input("*.txt")]).unwrap(), vec![path("tle2006.txt"), path("tle2007.txt")]);
+        assert!(expand_inputs(&[input("tle2099.txt")]).is_err());
+        assert!(expand_inputs(&[input("*/tle2010.txt")]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    //the ISS at another epoch, with the checksum redone
+    fn iss_at(epoch: &str) -> String {
+        let line1 = format!("1 25544U 98067A   {epoch} -.00002182  00000-0 -11606-4 0  292");
+        let checksum: u32 = line1.chars().map(|c| if c == '-' { 1 } else { c.to_digit(10).unwrap_or(0) }).sum();
+        format!("{line1}{}\n2 25544  51.6416 247.4627 0006703 130.5360 325.0288 15.72125391563537\n", checksum % 10)
+    }
+
+    #[test]
+    fn integration_tles_from_several_files_come_back_in_epoch_order() {
+        let dir = std::env::temp_dir().join(format!("read_integration_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("tle2008b.txt"), iss_at("08266.51782528") + &iss_at("08265.51782528")).unwrap();
+        fs::write(dir.join("tle2008a.txt"), iss_at("08267.51782528")).unwrap();
+
+        let satellites = read_txt_for_integration(&[dir.to_string_lossy().to_string()], Some(2)).unwrap();
+        let days: Vec<f64> = satellites["25544"].iter().map(|tle| (tle.epoch - satellites["25544"][0].epoch).as_days()).collect();
+        assert_eq!(days.len(), 3);
+        assert!(days.iter().zip([0.0, 1.0, 2.0]).all(|(day, expected)| (day - expected).abs() < 1e-6));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,339 @@
+use std::{collections::HashMap, fmt, fs::read_to_string, str::FromStr};
+use satkit::{Duration, Instant, TLE};
+use serde_json::Value;
+use anyhow::{anyhow, bail, Result};
+use crate::satellite::SatelliteRecord;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum ObjectType {
+    Payload,
+    RocketBody,
+    Debris,
+    Unknown,
+}
+
+//space-track spells the types out ("ROCKET BODY"), celestrak abbreviates them ("R/B")
+impl FromStr for ObjectType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_uppercase().replace(['_', '-'], " ").as_str() {
+            "PAYLOAD" | "PAY" => Ok(ObjectType::Payload),
+            "ROCKET BODY" | "R/B" | "RB" => Ok(ObjectType::RocketBody),
+            "DEBRIS" | "DEB" => Ok(ObjectType::Debris),
+            "UNKNOWN" | "UNK" | "TBA" | "" => Ok(ObjectType::Unknown),
+            _ => bail!("Unknown object type {s}, expected payload, rocket_body, debris or unknown"),
+        }
+    }
+}
+
+impl fmt::Display for ObjectType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ObjectType::Payload => "payload",
+            ObjectType::RocketBody => "rocket_body",
+            ObjectType::Debris => "debris",
+            ObjectType::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+//radar cross section class, space-track's thresholds for the numeric celestrak RCS in m^2
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum RcsSize {
+    Small, //under 0.1 m^2
+    Medium, //0.1 to 1 m^2
+    Large, //over 1 m^2
+}
+
+impl RcsSize {
+    fn parse(value: &str) -> Option<RcsSize> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "SMALL" => Some(RcsSize::Small),
+            "MEDIUM" => Some(RcsSize::Medium),
+            "LARGE" => Some(RcsSize::Large),
+            other => match other.parse::<f64>() {
+                Ok(rcs) if rcs < 0.1 => Some(RcsSize::Small),
+                Ok(rcs) if rcs <= 1.0 => Some(RcsSize::Medium),
+                Ok(_) => Some(RcsSize::Large),
+                Err(_) => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for RcsSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            RcsSize::Small => "small",
+            RcsSize::Medium => "medium",
+            RcsSize::Large => "large",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct SatcatEntry {
+    pub(crate) name: String,
+    pub(crate) object_type: ObjectType,
+    pub(crate) owner: String, //country or organisation code, e.g. US, PRC, ESA
+    pub(crate) launch_date: Option<Instant>,
+    pub(crate) decay_date: Option<Instant>, //None while still in orbit
+    pub(crate) rcs_size: Option<RcsSize>,
+}
+
+//column names for each field, space-track's first then celestrak's
+const CATALOG_NUMBER: [&str; 1] = ["NORAD_CAT_ID"];
+const NAME: [&str; 2] = ["SATNAME", "OBJECT_NAME"];
+const OBJECT_TYPE: [&str; 1] = ["OBJECT_TYPE"];
+const OWNER: [&str; 2] = ["COUNTRY", "OWNER"];
+const LAUNCH: [&str; 2] = ["LAUNCH", "LAUNCH_DATE"];
+const DECAY: [&str; 2] = ["DECAY", "DECAY_DATE"];
+const RCS: [&str; 2] = ["RCS_SIZE", "RCS"];
+
+//SATCAT as downloaded from space-track or celestrak, as .json (a list of objects) or .csv with a header line
+pub(crate) fn load_satcat(path: &str) -> Result<HashMap<String, SatcatEntry>> {
+    let contents = read_to_string(path)?;
+    let rows: Vec<HashMap<String, String>> = if path.to_ascii_lowercase().ends_with(".json") {
+        let json: Value = serde_json::from_str(&contents)?;
+        json.as_array().ok_or_else(|| anyhow!("{path} should hold a list of SATCAT objects"))?
+            .iter()
+            .map(|object| object.as_object().map(|object| object.iter()
+                .map(|(key, value)| (key.to_ascii_uppercase(), match value {
+                    Value::String(s) => s.clone(),
+                    Value::Null => String::new(),
+                    other => other.to_string(),
+                }))
+                .collect()))
+            .collect::<Option<Vec<HashMap<String, String>>>>()
+            .ok_or_else(|| anyhow!("{path} should hold a list of SATCAT objects"))?
+    } else {
+        let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+        let header: Vec<String> = split_csv(lines.next().ok_or_else(|| anyhow!("{path} is empty"))?)
+            .into_iter().map(|column| column.to_ascii_uppercase()).collect();
+        lines.map(|line| header.iter().cloned().zip(split_csv(line)).collect()).collect()
+    };
+
+    let mut satcat: HashMap<String, SatcatEntry> = HashMap::new();
+    for row in rows {
+        let field = |names: &[&str]| -> &str {
+            names.iter().find_map(|name| row.get(*name)).map(|value| value.trim()).unwrap_or_default()
+        };
+        let id = match field(&CATALOG_NUMBER).parse::<u64>() {
+            Ok(id) => id.to_string(), //same form as the readers' keys, without leading zeros
+            Err(_) => continue,
+        };
+        satcat.insert(id, SatcatEntry {
+            name: field(&NAME).to_string(),
+            object_type: field(&OBJECT_TYPE).parse().unwrap_or(ObjectType::Unknown),
+            owner: field(&OWNER).to_string(),
+            launch_date: parse_date(field(&LAUNCH))?,
+            decay_date: parse_date(field(&DECAY))?,
+            rcs_size: RcsSize::parse(field(&RCS)),
+        });
+    }
+    println!("Loaded {} SATCAT entries", satcat.len());
+    Ok(satcat)
+}
+
+//fields split on commas outside double quotes, with "" as an escaped quote
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields: Vec<String> = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                fields.last_mut().unwrap().push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+//YYYY-MM-DD, empty for none
+fn parse_date(value: &str) -> Result<Option<Instant>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let parts: Vec<&str> = value.get(..10).unwrap_or(value).split('-').collect();
+    match parts.as_slice() {
+        [year, month, day] => Ok(Some(Instant::from_date(year.parse()?, month.parse()?, day.parse()?))),
+        _ => bail!("Invalid SATCAT date {value}, expected YYYY-MM-DD"),
+    }
+}
+
+//gives every record its SATCAT entry and drops element sets dated after the object decayed,
+//returns how many records were matched and how many element sets were dropped
+pub(crate) fn attach(satellites: &mut HashMap<String, SatelliteRecord>, satcat: &HashMap<String, SatcatEntry>) -> (usize, usize) {
+    let (mut matched, mut dropped) = (0, 0);
+    for (id, record) in satellites.iter_mut() {
+        let entry = match satcat.get(id) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        matched += 1;
+        if let Some(decay) = entry.decay_date {
+            let before = record.orbital_records.len();
+            record.orbital_records.retain(|instance| instance.epoch() <= decay + Duration::from_days(1.0)); //the decay date has no time of day
+            dropped += before - record.orbital_records.len();
+        }
+        record.satcat = Some(entry.clone());
+    }
+    satellites.retain(|_, record| !record.orbital_records.is_empty());
+    (matched, dropped)
+}
+
+//the same for TLE lists keyed by catalog number, also keeping only the wanted object types when given
+pub(crate) fn filter_tles(satellites: &mut HashMap<String, Vec<TLE>>, satcat: &HashMap<String, SatcatEntry>, types: Option<&[ObjectType]>) -> usize {
+    let mut dropped = 0;
+    satellites.retain(|id, tles| {
+        if types.is_some_and(|types| !has_type(satcat, id, types)) {
+            return false;
+        }
+        if let Some(decay) = satcat.get(id).and_then(|entry| entry.decay_date) {
+            let before = tles.len();
+            tles.retain(|tle| tle.epoch <= decay + Duration::from_days(1.0));
+            dropped += before - tles.len();
+        }
+        !tles.is_empty()
+    });
+    dropped
+}
+
+//whether an object's type is one of the wanted ones, objects missing from the SATCAT count as unknown
+pub(crate) fn has_type(satcat: &HashMap<String, SatcatEntry>, id: &str, types: &[ObjectType]) -> bool {
+    let object_type = satcat.get(id).map(|entry| entry.object_type).unwrap_or(ObjectType::Unknown);
+    types.contains(&object_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::satellite::OrbitalInstance;
+
+    fn load(name: &str, contents: &str) -> HashMap<String, SatcatEntry> {
+        let path = std::env::temp_dir().join(format!("satcat_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        let satcat = load_satcat(&path.to_string_lossy());
+        std::fs::remove_file(&path).unwrap();
+        satcat.unwrap()
+    }
+
+    fn entry(decay_date: Option<Instant>, object_type: ObjectType) -> SatcatEntry {
+        SatcatEntry { name: String::new(), object_type, owner: String::new(), launch_date: None, decay_date, rcs_size: None }
+    }
+
+    #[test]
+    fn reads_celestrak_csv_with_quoted_fields() {
+        let satcat = load("celestrak.csv", "OBJECT_NAME,OBJECT_ID,NORAD_CAT_ID,OBJECT_TYPE,OWNER,LAUNCH_DATE,DECAY_DATE,RCS\n\
+            \"SL-1 R/B, \"\"FIRST\"\"\",1957-001A,00001,R/B,CIS,1957-10-04,1957-12-01,20.4200\n\
+            VANGUARD 1,1958-002B,5,PAY,US,1958-03-17,,0.1220\n\
+            \n\
+            NO ID,,,DEB,US,,,\n");
+        assert_eq!(satcat.len(), 2);
+        let rocket = &satcat["1"];
+        assert_eq!(rocket.name, "SL-1 R/B, \"FIRST\"");
+        assert_eq!(rocket.object_type, ObjectType::RocketBody);
+        assert_eq!(rocket.owner, "CIS");
+        assert_eq!(rocket.decay_date, Some(Instant::from_date(1957, 12, 1)));
+        assert_eq!(rocket.rcs_size, Some(RcsSize::Large));
+        let vanguard = &satcat["5"];
+        assert_eq!(vanguard.launch_date, Some(Instant::from_date(1958, 3, 17)));
+        assert_eq!(vanguard.decay_date, None);
+        assert_eq!(vanguard.rcs_size, Some(RcsSize::Medium));
+    }
+
+    #[test]
+    fn reads_space_track_csv_columns() {
+        let satcat = load("spacetrack.csv", "INTLDES,NORAD_CAT_ID,OBJECT_TYPE,SATNAME,COUNTRY,LAUNCH,DECAY,RCS_SIZE\n\
+            1998-067A,25544,PAYLOAD,ISS (ZARYA),ISS,1998-11-20,,LARGE\n\
+            1999-025DEF,26000,DEBRIS,FENGYUN 1C DEB,PRC,1999-05-10,2024-01-05,SMALL\n");
+        assert_eq!(satcat["25544"].name, "ISS (ZARYA)");
+        assert_eq!(satcat["25544"].object_type, ObjectType::Payload);
+        assert_eq!(satcat["25544"].owner, "ISS");
+        assert_eq!(satcat["26000"].object_type, ObjectType::Debris);
+        assert_eq!(satcat["26000"].decay_date, Some(Instant::from_date(2024, 1, 5)));
+        assert_eq!(satcat["26000"].rcs_size, Some(RcsSize::Small));
+    }
+
+    #[test]
+    fn reads_json_with_numeric_ids_and_nulls() {
+        let satcat = load("satcat.json", r#"[
+            {"OBJECT_NAME": "ISS (ZARYA)", "NORAD_CAT_ID": 25544, "OBJECT_TYPE": "PAY", "OWNER": "ISS", "LAUNCH_DATE": "1998-11-20", "DECAY_DATE": null, "RCS": 399.05},
+            {"satname": "OLD R/B", "norad_cat_id": "00100", "object_type": "ROCKET BODY", "decay": "2001-02-03T00:00:00"}
+        ]"#);
+        assert_eq!(satcat["25544"].decay_date, None);
+        assert_eq!(satcat["25544"].rcs_size, Some(RcsSize::Large));
+        assert_eq!(satcat["100"].name, "OLD R/B");
+        assert_eq!(satcat["100"].object_type, ObjectType::RocketBody);
+        assert_eq!(satcat["100"].decay_date, Some(Instant::from_date(2001, 2, 3)));
+    }
+
+    #[test]
+    fn object_types_parse_both_spellings() {
+        for (text, object_type) in [("PAYLOAD", ObjectType::Payload), ("pay", ObjectType::Payload), ("ROCKET BODY", ObjectType::RocketBody),
+            ("R/B", ObjectType::RocketBody), ("rocket_body", ObjectType::RocketBody), ("DEB", ObjectType::Debris), ("TBA", ObjectType::Unknown), ("", ObjectType::Unknown)] {
+            assert_eq!(text.parse::<ObjectType>().unwrap(), object_type, "{text}");
+        }
+        assert!("satellite".parse::<ObjectType>().is_err());
+        assert_eq!(ObjectType::RocketBody.to_string().parse::<ObjectType>().unwrap(), ObjectType::RocketBody);
+    }
+
+    #[test]
+    fn element_sets_after_decay_are_dropped() {
+        let decay = Instant::from_date(2024, 1, 5);
+        let satcat = HashMap::from([
+            ("1".to_string(), entry(Some(decay), ObjectType::Debris)),
+            ("2".to_string(), entry(Some(decay - Duration::from_days(30.0)), ObjectType::Debris)),
+            ("3".to_string(), entry(None, ObjectType::Payload)),
+        ]);
+        let record = |days: &[f64]| SatelliteRecord {
+            catalog_number: 1,
+            international_designator: String::new(),
+            orbital_records: days.iter().map(|day| OrbitalInstance {
+                epoch_year: 2024, epoch_day: *day, first_time_derivative: 0.0, second_time_derivative: 0.0, drag: 0.0,
+                inclination: 51.6, raan: 0.0, eccentricity: 0.001, perigee: 0.0, mean_anomaly: 0.0, mean_motion: 15.5,
+                element_set_number: 0, revolution_number: 0, ephemeris_type: 0,
+            }).collect(),
+            satcat: None,
+        };
+        //day 5.9 is still on the decay date, day 6.5 is after it
+        let mut satellites = HashMap::from([
+            ("1".to_string(), record(&[4.0, 5.9, 6.5])),
+            ("2".to_string(), record(&[4.0])),
+            ("4".to_string(), record(&[4.0])),
+        ]);
+        assert_eq!(attach(&mut satellites, &satcat), (2, 2));
+        assert_eq!(satellites["1"].orbital_records.len(), 2);
+        assert!(satellites["1"].satcat.is_some());
+        assert!(!satellites.contains_key("2"));
+        assert!(satellites["4"].satcat.is_none());
+
+        let lines = [
+            "1 25544U 98067A   24001.50000000  .00016717  00000-0  30270-3 0  9994",
+            "2 25544  51.6416 247.4627 0006703 130.5360 325.0288 15.50377579432523",
+        ];
+        let tle = |days: f64| {
+            let mut tle = TLE::load_2line(lines[0], lines[1]).unwrap();
+            tle.epoch = decay + Duration::from_days(days);
+            tle
+        };
+        let mut tles = HashMap::from([
+            ("1".to_string(), vec![tle(-1.0), tle(0.5), tle(1.5)]),
+            ("3".to_string(), vec![tle(1.5)]),
+            ("4".to_string(), vec![tle(1.5)]),
+        ]);
+        assert_eq!(filter_tles(&mut tles.clone(), &satcat, None), 1);
+        assert_eq!(filter_tles(&mut tles, &satcat, Some(&[ObjectType::Debris, ObjectType::Unknown])), 1);
+        assert_eq!(tles["1"].len(), 2);
+        assert!(!tles.contains_key("3")); //a payload
+        assert!(tles.contains_key("4")); //not in the SATCAT, so unknown
+    }
+}
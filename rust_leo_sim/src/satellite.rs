@@ -1,15 +1,19 @@
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyList};
+use pyo3::types::PyDict;
+use satkit::{consts::MU_EARTH, Duration, Instant, TLE};
+use sgp4::chrono::{TimeZone, Utc, Datelike, Timelike};
+use crate::satcat::SatcatEntry;
 
 #[derive(Clone)]
 pub(crate) struct SatelliteRecord {
     pub(crate) catalog_number: i32,
     pub(crate) international_designator:String,
-    pub(crate) orbital_records: Vec<OrbitalInstance>
+    pub(crate) orbital_records: Vec<OrbitalInstance>,
+    pub(crate) satcat: Option<SatcatEntry>, //name, type, launch and decay when a SATCAT was loaded
 }
 
 impl SatelliteRecord {
-    fn to_py_dict(&self, py:Python, instance: &OrbitalInstance) -> PyObject {
+    pub(crate) fn to_py_dict(&self, py:Python, instance: &OrbitalInstance) -> PyObject {
         let dict = PyDict::new(py);
         dict.set_item("satellite_catalog_number", self.catalog_number).unwrap();
         dict.set_item("international_designator", self.international_designator.clone()).unwrap();
@@ -33,14 +37,20 @@ impl SatelliteRecord {
         dict.into()
     }
     
-    pub(crate) fn to_python(&self, py:Python) -> PyObject {
-        let dicts: Vec<PyObject> = self
-        .orbital_records
-        .iter()
-        .map(|instance: &OrbitalInstance| self.to_py_dict(py, instance))
-        .collect();
-        let pylist = PyList::new(py, dicts).unwrap().into();
-        return pylist;
+    //the latest element set at or before the epoch, falling back to the earliest one when they are all after it
+    pub(crate) fn tle_at(&self, epoch: &Instant) -> Option<TLE> {
+        let before = self.orbital_records.iter()
+            .filter(|instance| instance.epoch() <= *epoch)
+            .max_by(|a, b| a.epoch().partial_cmp(&b.epoch()).unwrap());
+        let instance = match before {
+            Some(instance) => instance,
+            None => self.orbital_records.iter().min_by(|a, b| a.epoch().partial_cmp(&b.epoch()).unwrap())?,
+        };
+        Some(instance.to_tle(self.catalog_number, &self.international_designator))
+    }
+
+    pub(crate) fn sort_by_epoch(&mut self) {
+        self.orbital_records.sort_by(|a, b| (a.epoch_year, a.epoch_day).partial_cmp(&(b.epoch_year, b.epoch_day)).unwrap());
     }
 }
 
@@ -57,4 +67,74 @@ pub(crate) struct OrbitalInstance {
     pub(crate) perigee:f64,
     pub(crate) mean_anomaly:f64,
     pub(crate) mean_motion:f64,
+    pub(crate) element_set_number:i32, //the bookkeeping fields of the TLE, kept so the same lines can be written back out
+    pub(crate) revolution_number:i32,
+    pub(crate) ephemeris_type:u8,
+}
+
+impl OrbitalInstance {
+    pub(crate) fn from_tle(tle: &TLE) -> OrbitalInstance {
+        let unix_time: f64 = tle.epoch.as_unixtime();
+        let whole_seconds = unix_time.trunc() as i64;
+        let fractional_part = unix_time - unix_time.trunc();
+        let nanos = (fractional_part * 1.0e9) as u32;
+
+        let dt = Utc.timestamp_opt(whole_seconds, nanos).unwrap();
+    
+        let year = dt.year();
+        let day_of_year = dt.ordinal();
+        let fraction_of_day = (dt.hour() as f64
+        + (dt.minute() as f64 / 60.0)
+        + (dt.second() as f64 / 3600.0)
+        + (dt.nanosecond() as f64 / 3.6e12)) / 24.0;  
+        let day_of_year_fractional = (day_of_year as f64) + fraction_of_day;
+
+        OrbitalInstance {
+            epoch_year:           year,
+            epoch_day:            day_of_year_fractional,
+            first_time_derivative: tle.mean_motion_dot,
+            second_time_derivative: tle.mean_motion_dot_dot,
+            drag:                 tle.bstar,
+            inclination:          tle.inclination,
+            raan:                 tle.raan,
+            eccentricity:         tle.eccen,
+            perigee:              tle.arg_of_perigee,
+            mean_anomaly:         tle.mean_anomaly,
+            mean_motion:          tle.mean_motion,
+            element_set_number:   tle.element_num,
+            revolution_number:    tle.rev_num,
+            ephemeris_type:       tle.ephem_type,
+        }
+    }
+
+    pub(crate) fn to_tle(&self, catalog_number: i32, international_designator: &str) -> TLE {
+        let mut tle = TLE::new();
+        tle.sat_num = catalog_number;
+        tle.intl_desig = international_designator.to_string();
+        tle.epoch = self.epoch();
+        tle.mean_motion_dot = self.first_time_derivative;
+        tle.mean_motion_dot_dot = self.second_time_derivative;
+        tle.bstar = self.drag;
+        tle.inclination = self.inclination;
+        tle.raan = self.raan;
+        tle.eccen = self.eccentricity;
+        tle.arg_of_perigee = self.perigee;
+        tle.mean_anomaly = self.mean_anomaly;
+        tle.mean_motion = self.mean_motion;
+        tle.element_num = self.element_set_number;
+        tle.rev_num = self.revolution_number;
+        tle.ephem_type = self.ephemeris_type;
+        tle
+    }
+
+    //epoch_day is 1-based, so day 1.0 is midnight on January 1st
+    pub(crate) fn epoch(&self) -> Instant {
+        Instant::from_date(self.epoch_year, 1, 1) + Duration::from_days(self.epoch_day - 1.0)
+    }
+
+    //semi-major axis in meters from the mean motion in revolutions per day
+    pub(crate) fn semi_major_axis(&self) -> f64 {
+        let n = self.mean_motion * 2.0 * std::f64::consts::PI / 86400.0;
+        (MU_EARTH / n.powi(2)).cbrt()
+    }
 }
\ No newline at end of file
@@ -0,0 +1,233 @@
+use std::{collections::HashMap, fs::{self, read_to_string}, io, path::{Path, PathBuf}};
+use satkit::{orbitprop::SatPropertiesStatic, Duration, Instant};
+use anyhow::{anyhow, bail, Result};
+
+const BSTAR_TO_CD_A_OVER_M: f64 = 2.0 / 0.15696615; //B* (1/earth radii) to Cd*A/m (m^2/kg), the SGP4 reference density is 0.157 kg/m^2/ER
+const DENSITY_LAG_DAYS: f64 = 1.0; //satkit's NRLMSISE-00 takes the indices of the day before the epoch
+const SATKIT_FILE: &str = "SW-All.csv"; //the name satkit loads its space weather from, in its data directory
+
+//one day of a CelesTrak SW-All.csv, F10.7 in solar flux units
+#[derive(Clone, Debug)]
+struct Day {
+    date: Instant, //midnight UTC
+    f107_adj: f64, //adjusted to 1 AU, what the density model uses
+    f107_adj_c81: f64, //81 day centered average of the adjusted flux
+    ap: [f64; 8], //three hourly, from 00-03 UT
+    kp: [f64; 8], //three hourly, the file holds tenths
+}
+
+//the indices at one epoch, F10.7 interpolated between days, Ap and Kp of the three hour interval the epoch is in
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Indices {
+    pub(crate) f107_adj: f64,
+    pub(crate) f107_adj_c81: f64,
+    pub(crate) ap: f64,
+    pub(crate) kp: f64,
+}
+
+//solar flux and geomagnetic indices loaded from a local SW-All.csv (https://celestrak.org/SpaceData/SW-All.csv)
+pub(crate) struct SpaceWeather {
+    path: PathBuf,
+    days: Vec<Day>, //consecutive, in date order
+    private_dir: Option<PrivateDataDir>, //set by install, removed with the space weather at the end of the run
+}
+
+//a data directory made for one run, deleted when dropped (only the links in it, not the shared files they point to)
+struct PrivateDataDir(PathBuf);
+
+impl Drop for PrivateDataDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+impl SpaceWeather {
+    //rows without an adjusted flux or a daily Ap (the monthly predictions at the end of the file) are not loaded,
+    //so the range only covers days every index is known for
+    pub(crate) fn load(path: &str) -> Result<SpaceWeather> {
+        let contents = read_to_string(path).map_err(|e| anyhow!("Failed to read space weather from {path}: {e}"))?;
+        let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+        let header: HashMap<String, usize> = lines.next().ok_or_else(|| anyhow!("{path} is empty"))?
+            .split(',').enumerate().map(|(i, column)| (column.trim().to_ascii_uppercase(), i)).collect();
+        let column = |name: &str| header.get(name).copied().ok_or_else(|| anyhow!("{path} has no {name} column, expected a CelesTrak SW-All.csv"));
+        let (date, f107_adj, f107_adj_c81, ap_avg) = (column("DATE")?, column("F10.7_ADJ")?, column("F10.7_ADJ_CENTER81")?, column("AP_AVG")?);
+        let (ap, kp): (Vec<usize>, Vec<usize>) = ((1..=8).map(|i| column(&format!("AP{i}"))).collect::<Result<_>>()?,
+            (1..=8).map(|i| column(&format!("KP{i}"))).collect::<Result<_>>()?);
+
+        let mut days: Vec<Day> = Vec::new();
+        for line in lines {
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            let number = |i: usize| fields.get(i).and_then(|field| field.parse::<f64>().ok());
+            let (f107_adj, f107_adj_c81, ap_avg) = match (number(f107_adj), number(f107_adj_c81), number(ap_avg)) {
+                (Some(f107_adj), Some(f107_adj_c81), Some(ap_avg)) => (f107_adj, f107_adj_c81, ap_avg),
+                _ => break,
+            };
+            let day = Day {
+                date: parse_date(fields.get(date).copied().unwrap_or_default()).map_err(|e| anyhow!("{path}: {e}"))?,
+                f107_adj,
+                f107_adj_c81,
+                ap: std::array::from_fn(|i| number(ap[i]).unwrap_or(ap_avg)), //predicted days only have the daily average
+                kp: std::array::from_fn(|i| number(kp[i]).map(|kp| kp / 10.0).unwrap_or(f64::NAN)),
+            };
+            if let Some(previous) = days.last() {
+                if (day.date - previous.date).as_days().round() != 1.0 {
+                    bail!("{path} skips from {} to {}", previous.date, day.date);
+                }
+            }
+            days.push(day);
+        }
+        if days.is_empty() {
+            bail!("{path} holds no days with F10.7 and Ap");
+        }
+        let space_weather = SpaceWeather { path: PathBuf::from(path), days, private_dir: None };
+        let (first, last) = space_weather.coverage();
+        println!("Space weather from {}: {} to {}", path, first, last);
+        Ok(space_weather)
+    }
+
+    //from midnight of the first day to midnight after the last one
+    pub(crate) fn coverage(&self) -> (Instant, Instant) {
+        (self.days[0].date, self.days[self.days.len() - 1].date + Duration::from_days(1.0))
+    }
+
+    pub(crate) fn at(&self, epoch: &Instant) -> Result<Indices> {
+        let (first, last) = self.coverage();
+        if *epoch < first || *epoch >= last {
+            bail!("{} is outside the space weather loaded from {} ({} to {})", epoch, self.path.display(), first, last);
+        }
+        let days = (*epoch - first).as_days();
+        let index = days.floor() as usize;
+        let day = &self.days[index];
+        let next = self.days.get(index + 1).unwrap_or(day); //flat through the last day
+        let fraction = days - index as f64;
+        let interval = ((fraction * 8.0).floor() as usize).min(7);
+        Ok(Indices {
+            f107_adj: day.f107_adj + (next.f107_adj - day.f107_adj) * fraction,
+            f107_adj_c81: day.f107_adj_c81 + (next.f107_adj_c81 - day.f107_adj_c81) * fraction,
+            ap: day.ap[interval],
+            kp: day.kp[interval],
+        })
+    }
+
+    //fails before a propagation whose density lookups would fall outside the file,
+    //where satkit would otherwise quietly keep using the last day it has
+    pub(crate) fn check_range(&self, start: &Instant, stop: &Instant) -> Result<()> {
+        let (first, last) = self.coverage();
+        let (start, stop) = if start <= stop { (*start, *stop) } else { (*stop, *start) };
+        let needed = start - Duration::from_days(DENSITY_LAG_DAYS);
+        if needed < first || stop > last {
+            bail!("Propagating from {} to {} needs space weather from {} to {}, {} only covers {} to {}",
+                start, stop, needed, stop, self.path.display(), first, last);
+        }
+        Ok(())
+    }
+
+    //makes satkit's density model read this file without overwriting the SW-All.csv other runs share: satkit is pointed at
+    //a data directory of this run's own, with this file and links to everything else in the shared one, which goes away
+    //when the space weather is dropped. Has to run before the first propagation since satkit loads the file once
+    pub(crate) fn install(&mut self) -> Result<()> {
+        let shared = satkit::utils::datadir().map_err(|e| anyhow!("No satkit data directory to take the other files from: {e}"))?;
+        let target = shared.join(SATKIT_FILE);
+        if target.exists() && fs::canonicalize(&target)? == fs::canonicalize(&self.path)? {
+            return Ok(());
+        }
+        let private = std::env::temp_dir().join(format!("rust_leo_sim_data_{}", std::process::id()));
+        self.private_dir = Some(PrivateDataDir(private.clone())); //cleaned up even if linking fails part way
+        self.private_data_dir(&shared, &private)?;
+        satkit::utils::set_datadir(&private).map_err(|e| anyhow!("Can't use {} as the satkit data directory: {e}", private.display()))?;
+        println!("satkit reads {} from {}, {} is left as it is", self.path.display(), private.display(), shared.display());
+        Ok(())
+    }
+
+    //the shared directory's files linked into the private one, with a copy of this file as satkit's space weather
+    fn private_data_dir(&self, shared: &Path, private: &Path) -> Result<()> {
+        fs::create_dir_all(private)?;
+        for entry in fs::read_dir(shared)? {
+            let path = entry?.path();
+            let name = match path.file_name() {
+                Some(name) if path.is_file() && name != SATKIT_FILE => name,
+                _ => continue,
+            };
+            let link = private.join(name);
+            if fs::symlink_metadata(&link).is_err() {
+                link_file(&path, &link).map_err(|e| anyhow!("Failed to link {} into {}: {e}", path.display(), private.display()))?;
+            }
+        }
+        let target = private.join(SATKIT_FILE);
+        fs::copy(&self.path, &target).map_err(|e| anyhow!("Failed to copy {} to {}: {e}", self.path.display(), target.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn link_file(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(not(unix))]
+fn link_file(original: &Path, link: &Path) -> io::Result<()> {
+    fs::copy(original, link).map(|_| ())
+}
+
+//YYYY-MM-DD
+pub(crate) fn parse_date(value: &str) -> Result<Instant> {
+    let parts: Vec<&str> = value.split('-').collect();
+    match parts.as_slice() {
+        [year, month, day] => Ok(Instant::from_date(year.parse()?, month.parse()?, day.parse()?)),
+        _ => bail!("Invalid date {value}, expected YYYY-MM-DD"),
+    }
+}
+
+//drag properties from a TLE's B*, which folds the drag coefficient and area to mass ratio into one term
+pub(crate) fn drag_properties(bstar: f64) -> SatPropertiesStatic {
+    SatPropertiesStatic::new(bstar.max(0.0) * BSTAR_TO_CD_A_OVER_M, 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_inside_the_file_and_fails_outside_it() {
+        let path = std::env::temp_dir().join(format!("sw_all_{}.csv", std::process::id()));
+        fs::write(&path, "\
+DATE,BSRN,ND,KP1,KP2,KP3,KP4,KP5,KP6,KP7,KP8,KP_SUM,AP1,AP2,AP3,AP4,AP5,AP6,AP7,AP8,AP_AVG,CP,C9,ISN,F10.7_OBS,F10.7_ADJ,F10.7_DATA_TYPE,F10.7_OBS_CENTER81,F10.7_OBS_LAST81,F10.7_ADJ_CENTER81,F10.7_ADJ_LAST81
+2024-01-01,2596,1,10,20,30,40,30,20,10,7,167,4,7,15,27,15,7,4,3,10,0.5,2,100,150.0,145.0,OBS,160.0,158.0,155.0,153.0
+2024-01-02,2596,2,7,7,7,7,7,7,7,7,56,3,3,3,3,3,3,3,3,3,0.1,0,90,160.0,155.0,OBS,161.0,158.5,156.0,153.5
+2024-01-03,2596,3,,,,,,,,,,,,,,,,,,12,,,,170.0,165.0,PRD,,,157.0,
+2024-02-01,,,,,,,,,,,,,,,,,,,,,,,,175.0,170.0,PRM,,,158.0,
+").unwrap();
+        let space_weather = SpaceWeather::load(&path.to_string_lossy()).unwrap();
+        let day = |d: f64| Instant::from_date(2024, 1, 1) + Duration::from_days(d);
+        assert!(space_weather.coverage() == (day(0.0), day(3.0))); //the monthly prediction has no Ap
+
+        let indices = space_weather.at(&day(0.4)).unwrap();
+        assert!((indices.f107_adj - 149.0).abs() < 1e-9);
+        assert!((indices.f107_adj_c81 - 155.4).abs() < 1e-9);
+        assert_eq!((indices.ap, indices.kp), (27.0, 4.0)); //09-12 UT
+        assert_eq!(space_weather.at(&day(2.5)).unwrap().ap, 12.0); //predicted days only have the average
+        assert!(space_weather.at(&day(3.0)).is_err());
+
+        assert!(space_weather.check_range(&day(1.0), &day(3.0)).is_ok());
+        assert!(space_weather.check_range(&day(0.5), &day(2.0)).is_err()); //needs the day before the start
+        assert!(space_weather.check_range(&day(1.5), &day(3.5)).is_err());
+
+        //installing leaves the shared directory's SW-All.csv alone and links the rest
+        let shared = std::env::temp_dir().join(format!("sw_shared_{}", std::process::id()));
+        let private = std::env::temp_dir().join(format!("sw_private_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&private);
+        fs::create_dir_all(&shared).unwrap();
+        fs::write(shared.join(SATKIT_FILE), "shared").unwrap();
+        fs::write(shared.join("EOP-All.csv"), "eop").unwrap();
+        space_weather.private_data_dir(&shared, &private).unwrap();
+        assert_eq!(fs::read_to_string(shared.join(SATKIT_FILE)).unwrap(), "shared");
+        assert_eq!(fs::read_to_string(private.join(SATKIT_FILE)).unwrap(), fs::read_to_string(&path).unwrap());
+        assert_eq!(fs::read_to_string(private.join("EOP-All.csv")).unwrap(), "eop");
+        space_weather.private_data_dir(&shared, &private).unwrap(); //again, over the links already there
+        drop(PrivateDataDir(private.clone()));
+        assert!(!private.exists());
+        assert_eq!(fs::read_to_string(shared.join("EOP-All.csv")).unwrap(), "eop");
+        fs::remove_dir_all(&shared).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,434 @@
+use std::{collections::{BTreeMap, VecDeque}, fmt, fs::{self, read_to_string, OpenOptions}, io::{BufWriter, Write}, path::{Path, PathBuf}, str::FromStr, thread, time};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use anyhow::{anyhow, bail, Result};
+
+pub(crate) const SPACE_TRACK_URL: &str = "https://www.space-track.org";
+const DAY: i64 = 86400;
+
+//what each fetched element set is written as, one file per epoch year in the output directory
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum OutputFormat {
+    Tle, //tle<year>.txt, the two line format the readers take
+    Omm, //omm<year>.jsonl, every GP field of a record as one JSON object per line
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "tle" => Ok(OutputFormat::Tle),
+            "omm" => Ok(OutputFormat::Omm),
+            _ => bail!("Unknown format {s}, expected tle or omm"),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputFormat::Tle => write!(f, "tle"),
+            OutputFormat::Omm => write!(f, "omm"),
+        }
+    }
+}
+
+pub(crate) struct FetchOptions {
+    pub(crate) base_url: String,
+    pub(crate) identity: String,
+    pub(crate) password: String,
+    pub(crate) start: i64, //unix seconds, oldest epoch fetched
+    pub(crate) end: i64, //unix seconds, windows walk backwards from here
+    pub(crate) window_days: i64,
+    pub(crate) max_eccentricity: Option<f64>,
+    pub(crate) min_mean_motion: Option<f64>, //rev/day
+    pub(crate) format: OutputFormat,
+    pub(crate) output_dir: String,
+    pub(crate) rate_limits: Vec<(usize, f64)>, //(requests, seconds), every limit is kept
+    pub(crate) retries: usize,
+    pub(crate) retry_delay: f64, //s
+}
+
+impl FetchOptions {
+    //cursor file kept next to the output so an interrupted fetch picks up at the window it stopped at
+    fn state_path(&self) -> PathBuf {
+        Path::new(&self.output_dir).join("fetch_state.json")
+    }
+
+    //gp_history query for [from, to) in whole milliseconds, oldest first
+    fn query_path(&self, from: i64, to: i64) -> String {
+        let format_time = |seconds: i64| -> String {
+            DateTime::<Utc>::from_timestamp_millis(seconds * 1000).unwrap().format("%Y-%m-%dT%H:%M:%S%.3f").to_string()
+        };
+        let last = DateTime::<Utc>::from_timestamp_millis(to * 1000 - 1).unwrap().format("%Y-%m-%dT%H:%M:%S%.3f").to_string();
+        let mut path = format!("/basicspacedata/query/class/gp_history/EPOCH/{}--{}", format_time(from), last);
+        if let Some(eccentricity) = self.max_eccentricity {
+            path.push_str(&format!("/ECCENTRICITY/%3C{}", eccentricity));
+        }
+        if let Some(mean_motion) = self.min_mean_motion {
+            path.push_str(&format!("/MEAN_MOTION/%3E{}", mean_motion));
+        }
+        path.push_str("/orderby/EPOCH%20asc/format/json");
+        path
+    }
+}
+
+//sliding window limiter, space-track allows 30 requests a minute and 300 an hour
+struct RateLimiter {
+    limits: Vec<(usize, time::Duration)>,
+    sent: VecDeque<time::Instant>,
+}
+
+impl RateLimiter {
+    fn new(limits: &[(usize, f64)]) -> RateLimiter {
+        RateLimiter {
+            limits: limits.iter().map(|(count, seconds)| (*count, time::Duration::from_secs_f64(*seconds))).collect(),
+            sent: VecDeque::new(),
+        }
+    }
+
+    //sleeps until another request fits in every window, then records it
+    fn wait(&mut self) {
+        let longest = self.limits.iter().map(|(_, period)| *period).max().unwrap_or_default();
+        loop {
+            let now = time::Instant::now();
+            while self.sent.front().is_some_and(|sent| now.duration_since(*sent) >= longest) {
+                self.sent.pop_front();
+            }
+            let wait = self.limits.iter()
+                .filter_map(|(count, period)| {
+                    let in_window: Vec<&time::Instant> = self.sent.iter().filter(|sent| now.duration_since(**sent) < *period).collect();
+                    (*count > 0 && in_window.len() >= *count).then(|| *period - now.duration_since(*in_window[in_window.len() - count]))
+                })
+                .max();
+            match wait {
+                Some(wait) => thread::sleep(wait),
+                None => break,
+            }
+        }
+        self.sent.push_back(time::Instant::now());
+    }
+}
+
+//logged in agent, space-track keeps the session in a cookie
+struct Session<'a> {
+    agent: ureq::Agent,
+    options: &'a FetchOptions,
+    limiter: RateLimiter,
+}
+
+impl Session<'_> {
+    fn login(&mut self) -> Result<()> {
+        self.limiter.wait();
+        let response = self.agent.post(&format!("{}/ajaxauth/login", self.options.base_url))
+            .send_form(&[("identity", self.options.identity.as_str()), ("password", self.options.password.as_str())])
+            .map_err(|e| anyhow!("Login failed: {e}"))?;
+        let body = response.into_string()?;
+        if body.contains("\"Failed\"") { //wrong credentials still come back as 200
+            bail!("Login failed: {}", body.trim());
+        }
+        println!("Logged in to {}", self.options.base_url);
+        Ok(())
+    }
+
+    //retries transport errors and server errors, logging in again when the session expired
+    fn query(&mut self, path: &str) -> Result<Vec<Value>> {
+        let url = format!("{}{}", self.options.base_url, path);
+        let mut last_error = anyhow!("No attempts made for {url}");
+        for attempt in 1..=self.options.retries.max(1) {
+            self.limiter.wait();
+            match self.agent.get(&url).call() {
+                Ok(response) => {
+                    let records: Value = serde_json::from_str(&response.into_string()?)?;
+                    return match records {
+                        Value::Array(records) => Ok(records),
+                        other => bail!("Expected a list of records from {url}, got {other}"),
+                    };
+                }
+                Err(ureq::Error::Status(401 | 403, _)) => {
+                    println!("Session expired, logging in again");
+                    last_error = anyhow!("Unauthorized for {url}");
+                    self.login()?;
+                    continue;
+                }
+                Err(ureq::Error::Status(code, _)) if code < 500 && code != 429 => bail!("Request to {url} failed with status {code}"),
+                Err(e) => last_error = anyhow!("Attempt {attempt} for {url} failed: {e}"),
+            }
+            println!("{last_error}, retrying");
+            thread::sleep(time::Duration::from_secs_f64(self.options.retry_delay));
+        }
+        Err(last_error)
+    }
+}
+
+pub(crate) struct FetchSummary {
+    pub(crate) windows: usize,
+    pub(crate) records: usize,
+}
+
+//walks [start, end) backwards in windows, appending each window's records before moving the cursor past it,
+//then puts every year file back in epoch order since the windows arrive newest first
+pub(crate) fn fetch(options: &FetchOptions) -> Result<FetchSummary> {
+    if options.end <= options.start || options.window_days <= 0 {
+        bail!("Fetch window must end after it starts and use a positive window length");
+    }
+    fs::create_dir_all(&options.output_dir)?;
+    let mut cursor = read_cursor(options)?;
+    if cursor < options.end {
+        println!("Resuming at {}", DateTime::<Utc>::from_timestamp(cursor, 0).unwrap());
+    }
+
+    let agent = ureq::AgentBuilder::new().timeout(time::Duration::from_secs(300)).build();
+    let mut session = Session { agent, options, limiter: RateLimiter::new(&options.rate_limits) };
+    session.login()?;
+
+    let mut summary = FetchSummary { windows: 0, records: 0 };
+    while cursor > options.start {
+        let from = (cursor - options.window_days * DAY).max(options.start);
+        let records = session.query(&options.query_path(from, cursor))?;
+        write_records(&records, options)?;
+        cursor = from;
+        write_cursor(options, cursor)?;
+
+        summary.windows += 1;
+        summary.records += records.len();
+        println!("{} records up to {} ({} windows, {} records)",
+            records.len(), DateTime::<Utc>::from_timestamp(cursor, 0).unwrap(), summary.windows, summary.records);
+    }
+    sort_year_files(options)?;
+    Ok(summary)
+}
+
+//where the last run stopped, or the end when starting fresh or when the saved state was for a different range
+fn read_cursor(options: &FetchOptions) -> Result<i64> {
+    let path = options.state_path();
+    if !path.exists() {
+        return Ok(options.end);
+    }
+    let state: Value = serde_json::from_str(&read_to_string(&path)?)?;
+    let same_query = state["start"].as_i64() == Some(options.start) && state["end"].as_i64() == Some(options.end)
+        && state["format"].as_str() == Some(options.format.to_string().as_str());
+    match state["cursor"].as_i64() {
+        Some(cursor) if same_query => Ok(cursor),
+        _ => {
+            println!("{} is for another query, starting over", path.display());
+            Ok(options.end)
+        }
+    }
+}
+
+fn write_cursor(options: &FetchOptions, cursor: i64) -> Result<()> {
+    let state = json!({
+        "start": options.start,
+        "end": options.end,
+        "format": options.format.to_string(),
+        "cursor": cursor,
+    });
+    let temporary = options.state_path().with_extension("json.tmp");
+    fs::write(&temporary, serde_json::to_string_pretty(&state)?)?;
+    fs::rename(&temporary, options.state_path())?; //so a crash never leaves half a state file
+    Ok(())
+}
+
+//appends records to the file for their epoch year
+fn write_records(records: &[Value], options: &FetchOptions) -> Result<()> {
+    let mut by_year: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for record in records {
+        let year = record["EPOCH"].as_str().and_then(|epoch| epoch.get(..4)).ok_or_else(|| anyhow!("Record without an EPOCH: {record}"))?;
+        let line = match options.format {
+            OutputFormat::Tle => {
+                let line1 = record["TLE_LINE1"].as_str().ok_or_else(|| anyhow!("Record without TLE_LINE1: {record}"))?;
+                let line2 = record["TLE_LINE2"].as_str().ok_or_else(|| anyhow!("Record without TLE_LINE2: {record}"))?;
+                format!("{}\n{}", line1.trim_end(), line2.trim_end())
+            }
+            OutputFormat::Omm => record.to_string(),
+        };
+        by_year.entry(year.to_string()).or_default().push(line);
+    }
+    for (year, lines) in by_year {
+        let name = match options.format {
+            OutputFormat::Tle => format!("tle{}.txt", year),
+            OutputFormat::Omm => format!("omm{}.jsonl", year),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(Path::new(&options.output_dir).join(name))?;
+        let mut writer = BufWriter::new(file);
+        for line in lines {
+            writeln!(writer, "{}", line)?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+//rewrites each tle<year>.txt or omm<year>.jsonl with its element sets sorted by epoch, keeping the order of equal epochs
+fn sort_year_files(options: &FetchOptions) -> Result<()> {
+    let (prefix, suffix) = match options.format {
+        OutputFormat::Tle => ("tle", ".txt"),
+        OutputFormat::Omm => ("omm", ".jsonl"),
+    };
+    for entry in fs::read_dir(&options.output_dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let is_year = name.strip_prefix(prefix).and_then(|rest| rest.strip_suffix(suffix)).is_some_and(|year| year.len() == 4 && year.bytes().all(|b| b.is_ascii_digit()));
+        if !is_year {
+            continue;
+        }
+        let contents = read_to_string(&path)?;
+        let lines: Vec<&str> = contents.lines().collect();
+        let mut entries: Vec<(String, String)> = match options.format {
+            //the epoch field (YYDDD.DDDDDDDD) sorts as text within a year
+            OutputFormat::Tle => lines.chunks(2)
+                .map(|pair| (pair[0].get(18..32).unwrap_or_default().to_string(), pair.join("\n")))
+                .collect(),
+            OutputFormat::Omm => lines.iter()
+                .map(|line| -> Result<(String, String)> {
+                    let record: Value = serde_json::from_str(line)?;
+                    Ok((record["EPOCH"].as_str().unwrap_or_default().to_string(), line.to_string()))
+                })
+                .collect::<Result<Vec<(String, String)>>>()?,
+        };
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let temporary = path.with_extension("sorting");
+        let mut writer = BufWriter::new(fs::File::create(&temporary)?);
+        for (_, entry) in entries {
+            writeln!(writer, "{}", entry)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temporary, &path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::{BufRead, BufReader, Read}, net::TcpListener, sync::{Arc, Mutex}};
+
+    const LINE1: &str = "1 25544U 98067A   24001.50000000  .00016717  00000-0  10270-3 0  9005";
+    const LINE2: &str = "2 25544  51.6400 208.9163 0006317  69.9862  25.2906 15.49815322 12345";
+
+    //answers login with a session cookie and queries with one record per window, recording every request line
+    fn mock_server(expire_first_query: bool) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        thread::spawn(move || {
+            let mut expire = expire_first_query;
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let (mut length, mut cookie) = (0, false);
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    let lower = header.to_ascii_lowercase();
+                    if let Some(value) = lower.strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    cookie |= lower.starts_with("cookie:") && lower.contains("chocolatechip=session");
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                seen.lock().unwrap().push(request_line.trim().to_string());
+
+                let (status, headers, body) = if request_line.starts_with("POST /ajaxauth/login") {
+                    ("200 OK", "Set-Cookie: chocolatechip=session; Path=/\r\n", "\"\"".to_string())
+                } else if !cookie || expire {
+                    expire = false;
+                    ("401 Unauthorized", "", "[]".to_string())
+                } else {
+                    //one element set at the start of the window, with that epoch in its first line too
+                    let epoch = request_line.split("/EPOCH/").nth(1).unwrap().get(..19).unwrap().to_string();
+                    let time = chrono::NaiveDateTime::parse_from_str(&epoch, "%Y-%m-%dT%H:%M:%S").unwrap();
+                    let line1 = format!("{}{}{}", &LINE1[..18], time.format("%y%j.00000000"), &LINE1[32..]);
+                    ("200 OK", "", json!([{ "EPOCH": epoch, "TLE_LINE1": line1, "TLE_LINE2": LINE2 }]).to_string())
+                };
+                write!(stream, "HTTP/1.1 {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, headers, body.len(), body).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn options(base_url: String, output_dir: &Path) -> FetchOptions {
+        FetchOptions {
+            base_url,
+            identity: "user".to_string(),
+            password: "secret".to_string(),
+            start: 1704067200 - 10 * DAY, //2023-12-22
+            end: 1704067200, //2024-01-01
+            window_days: 4,
+            max_eccentricity: Some(0.25),
+            min_mean_motion: Some(11.25),
+            format: OutputFormat::Tle,
+            output_dir: output_dir.to_string_lossy().to_string(),
+            rate_limits: vec![(30, 60.0)],
+            retries: 3,
+            retry_delay: 0.0,
+        }
+    }
+
+    fn temporary_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("spacetrack_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn walks_windows_backwards_and_writes_tle_files_by_year() {
+        let (url, requests) = mock_server(true);
+        let dir = temporary_dir("walk");
+        let summary = fetch(&options(url, &dir)).unwrap();
+        assert_eq!((summary.windows, summary.records), (3, 3));
+
+        let requests = requests.lock().unwrap();
+        let queries: Vec<&String> = requests.iter().filter(|r| r.starts_with("GET")).collect();
+        assert_eq!(requests.iter().filter(|r| r.starts_with("POST")).count(), 2); //logged in again after the 401
+        assert!(queries[0].contains("/class/gp_history/EPOCH/2023-12-28T00:00:00.000--2023-12-31T23:59:59.999/ECCENTRICITY/%3C0.25/MEAN_MOTION/%3E11.25/"));
+        assert!(queries.last().unwrap().contains("/EPOCH/2023-12-22T00:00:00.000--2023-12-23T23:59:59.999/")); //last window cut at the start
+
+        //written newest window first, but sorted back into epoch order once the walk is done
+        let written = fs::read_to_string(dir.join("tle2023.txt")).unwrap();
+        assert_eq!(written.lines().count(), 6);
+        let epochs: Vec<&str> = written.lines().step_by(2).map(|line| &line[18..32]).collect();
+        assert_eq!(epochs, vec!["23356.00000000", "23358.00000000", "23362.00000000"]);
+        assert!(written.lines().skip(1).step_by(2).all(|line| line == LINE2));
+        let state: Value = serde_json::from_str(&fs::read_to_string(dir.join("fetch_state.json")).unwrap()).unwrap();
+        assert_eq!(state["cursor"].as_i64(), Some(1704067200 - 10 * DAY));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resumes_from_the_saved_cursor() {
+        let (url, requests) = mock_server(false);
+        let dir = temporary_dir("resume");
+        let options = options(url, &dir);
+        fs::create_dir_all(&dir).unwrap();
+        write_cursor(&options, options.end - 8 * DAY).unwrap();
+
+        let summary = fetch(&options).unwrap();
+        assert_eq!(summary.windows, 1);
+        let requests = requests.lock().unwrap();
+        assert!(requests.iter().any(|r| r.contains("/EPOCH/2023-12-22T00:00:00.000--2023-12-23T23:59:59.999/")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rate_limiter_spaces_requests_over_the_limit() {
+        let mut limiter = RateLimiter::new(&[(2, 0.2)]);
+        let started = time::Instant::now();
+        for _ in 0..3 {
+            limiter.wait();
+        }
+        assert!(started.elapsed() >= time::Duration::from_millis(190));
+    }
+}
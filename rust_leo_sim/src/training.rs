@@ -0,0 +1,283 @@
+use std::{fs::{self, File}, io::{BufWriter, Write}, path::Path};
+use satkit::{orbitprop::SatState, sgp4::{sgp4, SGP4Error}, types::Vector3, Instant, TLE};
+use anyhow::{bail, Result};
+use serde_json::json;
+use crate::frames::{gcrf_to_teme, teme_to_gcrf, Frame};
+
+//same constants customMLDSGP4.py divides its output by, so the states line up with what the model predicts
+const NORMALIZATION_R: f64 = 6958.137; //km
+const NORMALIZATION_V: f64 = 7.947155867983262; //km/s
+
+//TLE fields in the order and units to_py_dict hands them to python
+pub(crate) const TLE_FEATURES: [&str; 9] = [
+    "mean_motion_first_derivative",
+    "mean_motion_second_derivative",
+    "b_star",
+    "inclination",
+    "raan",
+    "eccentricity",
+    "argument_of_perigee",
+    "mean_anomaly",
+    "mean_motion",
+];
+//the same elements scaled the way ML-dSGP4 feeds them to its input layer (x0 in its forward pass): SGP4's internal
+//radians and rad/min, so the raw features above can still rebuild the TLE while these go straight into a network
+const MODEL_INPUTS: [&str; 6] = ["ecco", "argpo", "inclo", "mo", "no_kozai", "nodeo"];
+const STATE_COLUMNS: [&str; 6] = ["x", "y", "z", "vx", "vy", "vz"];
+pub(crate) const COLUMNS: usize = TLE_FEATURES.len() + MODEL_INPUTS.len() + 1 + 2 * STATE_COLUMNS.len(); //features, model inputs, tsince, truth, sgp4
+const TSINCE_COLUMN: usize = TLE_FEATURES.len() + MODEL_INPUTS.len();
+
+pub(crate) struct SampleOptions {
+    pub(crate) frame: Frame, //TEME (what ML-dSGP4 is trained on) or GCRF
+    pub(crate) output_dir: String,
+}
+
+//per column count, mean and spread, merged across satellites with Chan's parallel update
+#[derive(Default, Clone)]
+pub(crate) struct SampleStats {
+    pub(crate) count: u64,
+    mean: Vec<f64>,
+    m2: Vec<f64>,
+    min: Vec<f64>,
+    max: Vec<f64>,
+}
+
+impl SampleStats {
+    //Welford's online update
+    fn add(&mut self, row: &[f64; COLUMNS]) {
+        if self.count == 0 {
+            *self = SampleStats {
+                count: 0,
+                mean: vec![0.0; COLUMNS],
+                m2: vec![0.0; COLUMNS],
+                min: vec![f64::INFINITY; COLUMNS],
+                max: vec![f64::NEG_INFINITY; COLUMNS],
+            };
+        }
+        self.count += 1;
+        let n = self.count as f64;
+        for (i, value) in row.iter().enumerate() {
+            let delta = value - self.mean[i];
+            self.mean[i] += delta / n;
+            self.m2[i] += delta * (value - self.mean[i]);
+            self.min[i] = self.min[i].min(*value);
+            self.max[i] = self.max[i].max(*value);
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: &SampleStats) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            *self = other.clone();
+            return;
+        }
+        let (n_a, n_b) = (self.count as f64, other.count as f64);
+        let n = n_a + n_b;
+        for i in 0..COLUMNS {
+            let delta = other.mean[i] - self.mean[i];
+            self.mean[i] += delta * n_b / n;
+            self.m2[i] += other.m2[i] + delta * delta * n_a * n_b / n;
+            self.min[i] = self.min[i].min(other.min[i]);
+            self.max[i] = self.max[i].max(other.max[i]);
+        }
+        self.count += other.count;
+    }
+
+    //population standard deviation, 1 for constant columns so dividing by it is always safe
+    fn std(&self) -> Vec<f64> {
+        self.m2.iter()
+            .map(|m2| {
+                let std = (m2 / self.count as f64).sqrt();
+                if std > 0.0 { std } else { 1.0 }
+            })
+            .collect()
+    }
+}
+
+fn column_names() -> Vec<String> {
+    let mut names: Vec<String> = TLE_FEATURES.iter().map(|name| name.to_string()).collect();
+    names.extend(MODEL_INPUTS.iter().map(|name| format!("model_{}", name)));
+    names.push("tsince".to_string());
+    names.extend(STATE_COLUMNS.iter().map(|name| format!("truth_{}", name)));
+    names.extend(STATE_COLUMNS.iter().map(|name| format!("sgp4_{}", name)));
+    names
+}
+
+pub(crate) fn tle_features(tle: &TLE) -> [f64; 9] {
+    [
+        tle.mean_motion_dot,
+        tle.mean_motion_dot_dot,
+        tle.bstar,
+        tle.inclination,
+        tle.raan,
+        tle.eccen,
+        tle.arg_of_perigee,
+        tle.mean_anomaly,
+        tle.mean_motion,
+    ]
+}
+
+fn model_inputs(tle: &TLE) -> [f64; 6] {
+    [
+        tle.eccen,
+        tle.arg_of_perigee.to_radians(),
+        tle.inclination.to_radians(),
+        tle.mean_anomaly.to_radians(),
+        tle.mean_motion * 2.0 * std::f64::consts::PI / 1440.0, //rev/day to rad/min
+        tle.raan.to_radians(),
+    ]
+}
+
+//meters to the normalised km units the model works in
+fn normalise(pos: &Vector3, vel: &Vector3) -> [f64; 6] {
+    let (r, v) = (1.0e-3 / NORMALIZATION_R, 1.0e-3 / NORMALIZATION_V);
+    [pos[0] * r, pos[1] * r, pos[2] * r, vel[0] * v, vel[1] * v, vel[2] * v]
+}
+
+//one row per integrated step, steps where SGP4 fails (e.g. after decay) have no baseline and are left out
+fn sample_rows(tle: &mut TLE, steps: &[SatState], frame: Frame) -> Result<Vec<[f64; COLUMNS]>> {
+    if frame != Frame::Teme && frame != Frame::Gcrf {
+        bail!("Training samples can only be written in TEME or GCRF, not {frame}");
+    }
+    let features = tle_features(tle);
+    let inputs = model_inputs(tle);
+    let times: Vec<Instant> = steps.iter().map(|step| step.time).collect();
+    let (r_teme, v_teme, errs) = sgp4(tle, &times);
+
+    let mut rows: Vec<[f64; COLUMNS]> = Vec::with_capacity(steps.len());
+    for (i, step) in steps.iter().enumerate() {
+        if !matches!(errs.get(i), Some(SGP4Error::SGP4Success)) {
+            continue;
+        }
+        let baseline_pos = Vector3::new(r_teme[(0, i)], r_teme[(1, i)], r_teme[(2, i)]);
+        let baseline_vel = Vector3::new(v_teme[(0, i)], v_teme[(1, i)], v_teme[(2, i)]);
+        let ((truth_pos, truth_vel), (baseline_pos, baseline_vel)) = match frame {
+            Frame::Teme => (gcrf_to_teme(step), (baseline_pos, baseline_vel)),
+            _ => ((step.pos_gcrf(), step.vel_gcrf()), teme_to_gcrf(&step.time, &baseline_pos, &baseline_vel)),
+        };
+
+        let mut row = [0.0; COLUMNS];
+        row[..TLE_FEATURES.len()].copy_from_slice(&features);
+        row[TLE_FEATURES.len()..TSINCE_COLUMN].copy_from_slice(&inputs);
+        row[TSINCE_COLUMN] = (step.time - tle.epoch).as_seconds() / 60.0; //minutes, as dSGP4 takes tsince
+        row[TSINCE_COLUMN + 1..TSINCE_COLUMN + 7].copy_from_slice(&normalise(&truth_pos, &truth_vel));
+        row[TSINCE_COLUMN + 7..].copy_from_slice(&normalise(&baseline_pos, &baseline_vel));
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+//samples_<id>.bin, rows of COLUMNS little-endian f32 with no header, described by samples.json
+pub(crate) struct SampleWriter {
+    writer: BufWriter<File>,
+    frame: Frame,
+    pub(crate) stats: SampleStats,
+}
+
+impl SampleWriter {
+    pub(crate) fn create(options: &SampleOptions, id: &str) -> Result<SampleWriter> {
+        fs::create_dir_all(&options.output_dir)?;
+        let file = File::create(Path::new(&options.output_dir).join(format!("samples_{}.bin", id)))?;
+        Ok(SampleWriter { writer: BufWriter::new(file), frame: options.frame, stats: SampleStats::default() })
+    }
+
+    pub(crate) fn write(&mut self, tle: &TLE, steps: &[SatState]) -> Result<()> {
+        for row in sample_rows(&mut tle.clone(), steps, self.frame)? {
+            self.stats.add(&row);
+            for value in row {
+                self.writer.write_all(&(value as f32).to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<SampleStats> {
+        self.writer.flush()?;
+        Ok(self.stats)
+    }
+}
+
+//layout of the .bin files and mean/std per column over every sample written, for standardising at load time
+pub(crate) fn write_metadata(options: &SampleOptions, stats: &SampleStats) -> Result<()> {
+    let json = json!({
+        "dtype": "float32",
+        "byte_order": "little",
+        "frame": options.frame.to_string(),
+        "columns": column_names(),
+        "units": {
+            "tle": "as in the TLE (deg, rev/day, 1/earth radii), unscaled",
+            "model": "model_* columns are the ML-dSGP4 input layer's elements: eccentricity, angles in rad, mean motion in rad/min",
+            "tsince": "minutes since the TLE epoch",
+            "states": "km / normalization_R and km/s / normalization_V",
+        },
+        "normalization_R": NORMALIZATION_R,
+        "normalization_V": NORMALIZATION_V,
+        "samples": stats.count,
+        "mean": stats.mean,
+        "std": if stats.count > 0 { stats.std() } else { Vec::new() },
+        "min": stats.min,
+        "max": stats.max,
+    });
+    let file = File::create(Path::new(&options.output_dir).join("samples.json"))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, &json)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    println!("Wrote {} training samples", stats.count);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(value: f64) -> [f64; COLUMNS] {
+        let mut row = [value; COLUMNS];
+        row[0] = value * value;
+        row
+    }
+
+    #[test]
+    fn merged_stats_match_a_single_pass() {
+        let values: Vec<f64> = (0..20).map(|i| (i as f64 * 0.37).sin() * 10.0 + i as f64).collect();
+        let mut whole = SampleStats::default();
+        values.iter().for_each(|v| whole.add(&row(*v)));
+
+        let (mut a, mut b) = (SampleStats::default(), SampleStats::default());
+        values[..7].iter().for_each(|v| a.add(&row(*v)));
+        values[7..].iter().for_each(|v| b.add(&row(*v)));
+        a.merge(&b);
+        a.merge(&SampleStats::default());
+
+        assert_eq!(a.count, whole.count);
+        for i in 0..COLUMNS {
+            assert!((a.mean[i] - whole.mean[i]).abs() < 1.0e-9);
+            assert!((a.std()[i] - whole.std()[i]).abs() < 1.0e-9);
+            assert_eq!(a.min[i], whole.min[i]);
+            assert_eq!(a.max[i], whole.max[i]);
+        }
+    }
+
+    #[test]
+    fn model_inputs_are_in_sgp4_units() {
+        let lines = [
+            "1 25544U 98067A   24001.50000000  .00016717  00000-0  30270-3 0  9994",
+            "2 25544  51.6416 247.4627 0006703 130.5360 325.0288 15.50377579432523",
+        ];
+        let tle = TLE::load_2line(lines[0], lines[1]).unwrap();
+        let inputs = model_inputs(&tle);
+        assert!((inputs[0] - 0.0006703).abs() < 1.0e-12);
+        assert!((inputs[2] - 51.6416_f64.to_radians()).abs() < 1.0e-12);
+        assert!((inputs[4] - 15.50377579 * 2.0 * std::f64::consts::PI / 1440.0).abs() < 1.0e-9); //about 0.0676 rad/min
+        assert!((inputs[5] - 247.4627_f64.to_radians()).abs() < 1.0e-12);
+        assert_eq!(column_names()[TSINCE_COLUMN], "tsince");
+        assert_eq!(column_names()[TLE_FEATURES.len()], "model_ecco");
+    }
+
+    #[test]
+    fn column_names_match_the_row_width() {
+        assert_eq!(column_names().len(), COLUMNS);
+    }
+}
@@ -1,3 +1,4 @@
+# kept for the CSV pipeline, `rust_leo_sim fetch --output <dir> --start <date> --end <date>` fetches the same LEO history as TLE or OMM files and resumes where it stopped
 import requests
 import logging
 import os